@@ -1,7 +1,14 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use super::broadcast::BlockBroadcast;
+use super::data_sync::{BodySync, ReceiptSync, MAX_ITEMS_PER_REQUEST};
+use super::header_sync::{HeaderRange, HeaderSync, SkeletonRequest, MAX_HEADERS_PER_REQUEST, SKELETON_STEP};
+use super::verification::{verify_header_chain, verify_header_fields};
 use super::rlp_en_de::{
     decode_block_headers,
     decode_new_block,
@@ -9,66 +16,267 @@ use super::rlp_en_de::{
     encode_block_headers,
     encode_block_bodies,
     encode_get_block_bodies,
-    encode_get_block_headers
+    encode_get_block_headers,
+    encode_get_receipts,
+    encode_receipts
 };
 use crate::{
     client_adapter::Blockchain,
-    common_types::{BlockId, BlockBody, GetBlockHeaders},
+    common_types::{BlockId, BlockBody, BlockHeader, BlockNumber, GetBlockHeaders, NewBlock, Receipt},
     devp2p_adapter::PeerPenal,
     scheduler::PeerOrganizer,
-    scheduler::peer_organizer::{ErrorAct, InitialRequest, PeerId, Task},
+    scheduler::msgrate::MsgRate,
+    scheduler::peer_organizer::{ErrorAct, InitialRequest, PeerId, Task, TaskId},
     scheduler::protocol::{ProtocolId, MessageId, EthMessageId}
 };
-use primitive_types::H256;
-use crate::block_manager::rlp_en_de::{decode_block_bodies, decode_get_block_headers, decode_get_block_bodies};
+use primitive_types::{H256, U256};
+use crate::block_manager::rlp_en_de::{
+    decode_block_bodies, decode_get_block_headers, decode_get_block_bodies, decode_get_receipts, decode_receipts
+};
+
+/// Max number of announced hashes that are queued for fetching from one peer.
+pub const MAX_ANNOUNCED_PER_PEER: usize = 256;
+/// Max number of headers that we serve in one response, same as geth.
+pub const MAX_HEADERS_SERVE: u64 = 1024;
+/// Max number of block bodies that we serve in one response.
+pub const MAX_BODIES_SERVE: usize = 128;
+/// Max number of blocks whose receipts we serve in one response.
+pub const MAX_RECEIPTS_SERVE: usize = 128;
+/// Response that we serve is cut after it reaches this size, last item can go over it.
+pub const SOFT_RESPONSE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Leading `items` whose encoded size fits into `SOFT_RESPONSE_LIMIT`.
+fn within_soft_limit<T>(items: Vec<T>, size: impl Fn(&T) -> usize) -> Vec<T> {
+    let mut total = 0;
+    items
+        .into_iter()
+        .take_while(|item| {
+            let fits = total < SOFT_RESPONSE_LIMIT;
+            total += size(item);
+            fits
+        })
+        .collect()
+}
+
+/// Head of the chain as advertised by peer. Number is not part of status message,
+/// and we learn it by requesting header with `hash` from peer.
+#[derive(Debug, Clone)]
+pub struct PeerHead {
+    pub hash: H256,
+    pub number: Option<BlockNumber>,
+    pub total_difficulty: Option<U256>,
+}
 
 pub struct BlockManager {
     chain: Arc<Mutex<dyn Blockchain + Send + Sync>>,
+    peers: HashMap<PeerId, PeerHead>,
+    // requests for peers head header, needed to find out how far we need to sync.
+    // Announced headers are requested same way, from the peer that announced them.
+    head_requests: HashMap<TaskId, (PeerId, H256)>,
+    // hashes from NewBlockHashes that are not fetched yet
+    announced: HashMap<PeerId, VecDeque<H256>>,
+    broadcast: BlockBroadcast,
+    header_sync: HeaderSync,
+    body_sync: BodySync,
+    receipt_sync: ReceiptSync,
+    penalties: Vec<Task>,
 }
 
 //ALL APIs
 impl BlockManager {
     pub fn new(chain: Arc<Mutex<dyn Blockchain + Send + Sync>>) -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(BlockManager { chain }))
+        let start = match chain.lock().unwrap().best_block_header() {
+            Some(best) => best + 1,
+            None => 0,
+        };
+        Arc::new(Mutex::new(BlockManager {
+            chain,
+            peers: HashMap::new(),
+            head_requests: HashMap::new(),
+            announced: HashMap::new(),
+            broadcast: BlockBroadcast::new(),
+            header_sync: HeaderSync::new(start),
+            body_sync: BodySync::new(),
+            receipt_sync: ReceiptSync::new(),
+            penalties: Vec::new(),
+        }))
     }
 
-    fn request_block_headers(&self) -> InitialRequest {
-        let request = GetBlockHeaders::new(BlockId::Number(10_000_000), 100, 0, false);
-        let data = encode_get_block_headers(&request);
-        InitialRequest::new(EthMessageId::GetBlockHeaders, data)
+    fn request_head_header(&mut self, peer: &PeerId, hash: H256) -> InitialRequest {
+        let request = GetBlockHeaders::new(BlockId::Hash(hash), 1, 0, false);
+        let request = InitialRequest::new(MessageId::Eth(EthMessageId::GetBlockHeaders), encode_get_block_headers(&request));
+        self.head_requests.insert(request.task_id, (*peer, hash));
+        request
     }
 
-    fn request_block_bodies(&self) -> InitialRequest {
-        let hash: Vec<u8> = vec![
-            254, 133, 237, 238, 76, 75, 76, 219, 252, 14, 247, 181, 240, 164, 1, 45,
-            207, 31, 229, 94, 39, 154, 120, 247, 42, 246, 24, 88, 2, 167, 254, 215
-        ];
-        let hashes = vec![H256::from_slice(&hash)];
-        let data = encode_get_block_bodies(&hashes);
-        InitialRequest::new(EthMessageId::GetBlockBodies, data)
+    fn request_skeleton(&mut self, peer: &PeerId, skeleton: SkeletonRequest) -> InitialRequest {
+        debug!("Requesting skeleton of {} headers from #{} from peer {}", skeleton.count, skeleton.start, peer);
+        let request = GetBlockHeaders::new(
+            BlockId::Number(skeleton.start),
+            skeleton.count,
+            SKELETON_STEP - 1,
+            false,
+        );
+        let request = InitialRequest::new(MessageId::Eth(EthMessageId::GetBlockHeaders), encode_get_block_headers(&request))
+            .with_items(skeleton.count);
+        self.header_sync.skeleton_sent(request.task_id, *peer, skeleton);
+        request
+    }
+
+    fn request_block_headers(&mut self, peer: &PeerId, range: HeaderRange) -> InitialRequest {
+        debug!("Requesting headers #{}..#{} from peer {}", range.start, range.end(), peer);
+        let request = GetBlockHeaders::new(BlockId::Number(range.start), range.count, 0, false);
+        let request = InitialRequest::new(MessageId::Eth(EthMessageId::GetBlockHeaders), encode_get_block_headers(&request))
+            .with_items(range.count);
+        self.header_sync.request_sent(request.task_id, *peer, range);
+        request
+    }
+
+    fn request_block_bodies(&mut self, peer: &PeerId, hashes: Vec<H256>) -> InitialRequest {
+        debug!("Requesting {} block bodies from peer {}", hashes.len(), peer);
+        let request = InitialRequest::new(MessageId::Eth(EthMessageId::GetBlockBodies), encode_get_block_bodies(&hashes))
+            .with_items(hashes.len() as u64);
+        self.body_sync.request_sent(request.task_id, *peer, hashes);
+        request
+    }
+
+    fn request_receipts(&mut self, peer: &PeerId, hashes: Vec<H256>) -> InitialRequest {
+        debug!("Requesting receipts of {} blocks from peer {}", hashes.len(), peer);
+        let request = InitialRequest::new(MessageId::Eth(EthMessageId::GetReceipts), encode_get_receipts(&hashes))
+            .with_items(hashes.len() as u64);
+        self.receipt_sync.request_sent(request.task_id, *peer, hashes);
+        request
     }
 
     pub fn is_syncing(&self) -> bool {
-        // TODO implement sync instead of this test request
-        self.chain.lock().unwrap().best_block_header().is_none()
+        !self.header_sync.is_complete()
+            || !self.body_sync.is_complete()
+            || !self.receipt_sync.is_complete()
+    }
+
+    pub fn best_block(&self) -> Option<BlockNumber> {
+        self.chain.lock().unwrap().best_block_header().copied()
+    }
+
+    /// Chain is restored from snapshot at `number`, headers are synced from there on.
+    pub fn warped_to(&mut self, number: BlockNumber) {
+        self.header_sync = HeaderSync::new(number + 1);
+        for head in self.peers.values() {
+            if let Some(head_number) = head.number {
+                self.header_sync.set_target(head_number);
+            }
+        }
     }
 
-    pub fn next_sync_task(&self) -> Option<InitialRequest> {
-        // TODO implement sync instead of this test request
-        if self.is_syncing() {
-            Some(self.request_block_headers())
-        } else {
-            None
+    /// Highest head we know of, it is our sync target.
+    pub fn sync_target(&self) -> Option<BlockNumber> {
+        self.header_sync.target()
+    }
+
+    pub fn new_peer(&mut self, peer: &PeerId, latest_hash: H256, total_difficulty: Option<U256>) {
+        self.peers.insert(
+            *peer,
+            PeerHead { hash: latest_hash, number: None, total_difficulty },
+        );
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        // head of disconnected peer can't be synced to anymore
+        self.header_sync.retarget(self.peers.values().filter_map(|head| head.number).max());
+        self.announced.remove(peer);
+        self.broadcast.peer_disconnected(peer);
+    }
+
+    /// Request `task_id` that failed on other peer is going to be sent to `peer`. Returns false if
+    /// request is not ours or peer can't serve it. Head requests are not moved, as other peer
+    /// does not need to have the same head.
+    pub fn reassign_task(&mut self, task_id: &TaskId, peer: &PeerId) -> bool {
+        let peer_head = match self.peers.get(peer).and_then(|head| head.number) {
+            Some(number) => number,
+            None => return false,
+        };
+        self.header_sync.reassign(task_id, *peer, peer_head)
+            || self.body_sync.reassign(task_id, *peer, peer_head)
+            || self.receipt_sync.reassign(task_id, *peer, peer_head)
+    }
+
+    /// Request that should be sent to free `peer` to progress active sync. Requests are sized
+    /// by `rate`, so that slow peers get fewer headers or blocks than fast ones.
+    pub fn next_sync_task(&mut self, peer: &PeerId, rate: &MsgRate) -> Option<InitialRequest> {
+        let head = self.peers.get(peer)?.clone();
+        match head.number {
+            Some(number) => {
+                if let Some(hash) = self.next_announced(peer) {
+                    return Some(self.request_head_header(peer, hash));
+                }
+                if let Some(skeleton) = self.header_sync.next_skeleton(number) {
+                    return Some(self.request_skeleton(peer, skeleton));
+                }
+                let max = rate.capacity(&MessageId::Eth(EthMessageId::GetBlockHeaders), MAX_HEADERS_PER_REQUEST);
+                if let Some(range) = self.header_sync.next_range(number, max) {
+                    return Some(self.request_block_headers(peer, range));
+                }
+                let max = rate.capacity(&MessageId::Eth(EthMessageId::GetBlockBodies), MAX_ITEMS_PER_REQUEST as u64);
+                if let Some(hashes) = self.body_sync.next_batch(number, max as usize) {
+                    return Some(self.request_block_bodies(peer, hashes));
+                }
+                let max = rate.capacity(&MessageId::Eth(EthMessageId::GetReceipts), MAX_ITEMS_PER_REQUEST as u64);
+                let hashes = self.receipt_sync.next_batch(number, max as usize)?;
+                Some(self.request_receipts(peer, hashes))
+            }
+            None => Some(self.request_head_header(peer, head.hash)),
         }
     }
 
-    pub fn api_new_block_hashes(&self, peer: &PeerId, data: &[u8]) -> Result<Task,ErrorAct> {
+    // Announced hash that we still don't have.
+    fn next_announced(&mut self, peer: &PeerId) -> Option<H256> {
+        let announced = self.announced.get_mut(peer)?;
+        let chain = self.chain.lock().unwrap();
+        while let Some(hash) = announced.pop_front() {
+            if chain.block_number(&hash).is_none() {
+                return Some(hash);
+            }
+        }
+        None
+    }
+
+    /// Messages that announce block created or imported by our client to peers.
+    pub fn broadcast_block(&mut self, block: &NewBlock) -> Vec<Task> {
+        let peers: Vec<PeerId> = self.peers.keys().copied().collect();
+        self.broadcast.broadcast(block, &peers)
+    }
+
+    /// Request sent with `task_id` was not answered in time.
+    pub fn task_failed(&mut self, task_id: &TaskId) {
+        self.head_requests.remove(task_id);
+        self.header_sync.failed(task_id);
+        self.body_sync.failed(task_id);
+        self.receipt_sync.failed(task_id);
+    }
+
+    pub fn api_new_block_hashes(&mut self, peer: &PeerId, data: &[u8]) -> Result<Task,ErrorAct> {
         match decode_new_block_hashes(data) {
             Ok(hashes) => {
-                info!("Blockhashes: {:?}", hashes);
-                Ok(Task::None)  // Task::InsertPeer()
+                debug!("Peer {} announced {} blocks", peer, hashes.len());
+                let chain = self.chain.lock().unwrap();
+                let announced = self.announced.entry(*peer).or_default();
+                for announcement in hashes {
+                    self.broadcast.mark_known(peer, announcement.hash);
+                    if chain.block_number(&announcement.hash).is_some() {
+                        continue;
+                    }
+                    if announced.contains(&announcement.hash) {
+                        continue;
+                    }
+                    if announced.len() >= MAX_ANNOUNCED_PER_PEER {
+                        break;
+                    }
+                    announced.push_back(announcement.hash);
+                }
+                Ok(Task::None)
             },
-            Err(err) => ErrorAct::new_kick_generic(
+            Err(err) => ErrorAct::new_invalid_generic(
                 format!("Invalid NewBlockHashes request: {}", err)
             )
         }
@@ -76,16 +284,19 @@ impl BlockManager {
 
     pub fn api_get_block_headers(&self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
         match decode_get_block_headers(data) {
-            Ok(request) => {
+            Ok(mut request) => {
+                request.max_headers = std::cmp::min(request.max_headers, MAX_HEADERS_SERVE);
+                let headers = self.chain.lock().unwrap().block_headers(request);
+                let headers = within_soft_limit(headers, |header| rlp::encode(header).len());
                 Ok(Task::Responde(
                     *peer,
                     ProtocolId::Eth,
                     MessageId::Eth(EthMessageId::BlockHeaders),
-                    encode_block_headers(&self.chain.lock().unwrap().block_headers(request)),
+                    encode_block_headers(&headers),
                 ))
             },
             Err(err) => {
-                ErrorAct::new_kick_generic::<Task>(
+                ErrorAct::new_invalid_generic::<Task>(
                     format!("Invalid GetBlockHeaders request: {}", err)
                 )
             }
@@ -95,12 +306,12 @@ impl BlockManager {
 
     fn retrieve_block_bodies(&self, hashes: &[H256]) -> Vec<BlockBody> {
         let mut bodies = vec![];
-        for ref hash in hashes {
+        for ref hash in hashes.iter().take(MAX_BODIES_SERVE) {
             if let Some(body) = self.chain.lock().unwrap().block_body(hash) {
                 bodies.push(body);
             }
         }
-        bodies
+        within_soft_limit(bodies, |body| encode_block_bodies(std::slice::from_ref(body)).len())
     }
 
     pub fn api_get_block_bodies(&self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
@@ -114,50 +325,463 @@ impl BlockManager {
                 ))
             },
             Err(err) => {
-                ErrorAct::new_kick_generic::<Task>(
+                ErrorAct::new_invalid_generic::<Task>(
                     format!("Invalid GetBlockBodies request: {}", err)
                 )
             }
         }
     }
 
-    pub fn process_block_headers(&self, data: &[u8]) {
-        let decoded = decode_block_headers(&data);
-        match decoded {
-            Ok(headers) => {
-                info!("Decoded block headers: {:?}", headers);
-                for ref header in headers {
-                    self.chain.lock().unwrap().import_block_header(header);
+    fn process_head_header(&mut self, peer: &PeerId, hash: &H256, mut headers: Vec<BlockHeader>) -> Result<Task, ErrorAct> {
+        let header = match (headers.pop(), headers.is_empty()) {
+            (Some(header), true) => header,
+            (None, _) => return ErrorAct::new_useless("Peer did not return requested header".into()),
+            _ => return ErrorAct::new_invalid_generic("Peer returned more than requested header".into()),
+        };
+        verify_header_fields(&header)?;
+        if header.hash() != *hash {
+            return ErrorAct::new_invalid_generic("Peer returned wrong header".into());
+        }
+        // only header that peer served on our request moves sync target
+        self.update_peer_head(peer, &header);
+        self.header_sync.set_target(header.number);
+        self.announced_header(peer, header);
+        Ok(Task::None)
+    }
+
+    /// Records header as peer's head if it is newer then the one we know of.
+    fn update_peer_head(&mut self, peer: &PeerId, header: &BlockHeader) {
+        let hash = header.hash();
+        if let Some(head) = self.peers.get_mut(peer) {
+            if head.hash == hash || matches!(head.number, Some(number) if header.number > number) {
+                info!("Peer {} head is at #{}", peer, header.number);
+                head.hash = hash;
+                head.number = Some(header.number);
+            }
+        }
+    }
+
+    /// While we are at the top of the chain, header that extends it is imported right away.
+    /// Returns if header is imported.
+    fn announced_header(&mut self, peer: &PeerId, header: BlockHeader) -> bool {
+        let hash = header.hash();
+        {
+            let chain = self.chain.lock().unwrap();
+            if chain.block_number(&hash).is_some() {
+                return false;
+            }
+            let parent = header.number.checked_sub(1).and_then(|number| chain.block_header(number));
+            if let Some(parent) = parent {
+                if parent.hash() != header.parent_hash {
+                    info!("Peer {} announced block #{} on different fork", peer, header.number);
+                    return false;
                 }
-            },
-            Err(err) => error!("Could not decode block header: {}", err),
+            }
+        }
+        if !self.header_sync.announced(*peer, header.clone()) {
+            return false;
         }
+        self.import_ready_headers();
+        let imported = self.chain.lock().unwrap().block_number(&hash).is_some();
+        if imported {
+            // header is linked to our chain, so its number can be trusted
+            self.update_peer_head(peer, &header);
+        }
+        imported
     }
 
-    pub fn process_block_bodies(&self, data: &[u8]) {
-        match decode_block_bodies(&data) {
-            Ok(bodies) => {
-                for ref body in bodies {
-                    self.chain.lock().unwrap().import_block_body(body);
+    pub fn process_block_headers(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let headers = match decode_block_headers(&data) {
+            Ok(headers) => headers,
+            Err(err) => {
+                self.task_failed(task_id);
+                return ErrorAct::new_invalid_generic(format!("Could not decode block headers: {}", err));
+            }
+        };
+        if let Some((_, hash)) = self.head_requests.remove(task_id) {
+            return self.process_head_header(peer, &hash, headers);
+        }
+        if self.header_sync.is_skeleton(task_id) {
+            debug!("Got skeleton of {} headers from peer {}", headers.len(), peer);
+            if let Err(act) = headers.iter().try_for_each(verify_header_fields) {
+                self.header_sync.failed(task_id);
+                return Err(act);
+            }
+            self.header_sync.skeleton_response(task_id, headers)?;
+            return Ok(Task::None);
+        }
+        if !self.header_sync.is_outstanding(task_id) {
+            return Ok(Task::None);
+        }
+        debug!("Got {} headers from peer {}", headers.len(), peer);
+        if let Err(act) = verify_header_chain(None, &headers) {
+            self.header_sync.failed(task_id);
+            return Err(act);
+        }
+        if let Some(skeleton_peer) = self.header_sync.response(task_id, headers)? {
+            warn!("Peer {} served skeleton that does not match headers from peer {}", skeleton_peer, peer);
+            self.penalties.push(Task::PenalPeer(
+                skeleton_peer,
+                PeerPenal::InvalidData,
+                "Skeleton does not match chain".into(),
+            ));
+        }
+        self.import_ready_headers();
+        Ok(Task::None)
+    }
+
+    /// Imports downloaded headers that connect to our chain. Batch that does not fit on top
+    /// of chain is requested again with batch before it, and peers of both are penalized.
+    fn import_ready_headers(&mut self) {
+        let mut chain = self.chain.lock().unwrap();
+        while let Some((peer, headers)) = self.header_sync.next_ready() {
+            let first = headers[0].number;
+            let parent = match first {
+                0 => None,
+                number => chain.block_header(number - 1),
+            };
+            if let Err(act) = verify_header_chain(parent.as_ref(), &headers) {
+                warn!("Peer {} served invalid headers: {}", peer, act.reason());
+                let parent_peer = self.header_sync.reject(HeaderRange::new(first, headers.len() as u64));
+                if let Some(parent_peer) = parent_peer.filter(|parent_peer| *parent_peer != peer) {
+                    warn!("Peer {} served headers that next batch does not fit on", parent_peer);
+                    self.penalties.push(Task::PenalPeer(parent_peer, act.penal(), act.reason()));
                 }
-            },
-            Err(err) => error!("Could not decode block bodies: {}", err),
+                self.penalties.push(Task::PenalPeer(peer, act.penal(), act.reason()));
+                break;
+            }
+            info!("Importing headers #{}..#{}", first, first + headers.len() as u64 - 1);
+            for header in headers.iter() {
+                // header of batch that is synced again replaces old one, its data is not needed
+                if let Some(old) = chain.block_header(header.number).map(|old| old.hash()) {
+                    if old != header.hash() {
+                        self.body_sync.header_removed(&old);
+                        self.receipt_sync.header_removed(&old);
+                    }
+                }
+                chain.import_block_header(header);
+                self.body_sync.header_imported(header);
+                self.receipt_sync.header_imported(header);
+            }
+        }
+        for (hash, body) in self.body_sync.take_empty() {
+            chain.import_block_body(&hash, &body);
+        }
+        for (hash, receipts) in self.receipt_sync.take_empty() {
+            chain.import_block_receipts(&hash, &receipts);
         }
     }
 
-    pub fn api_new_block(&self, data: &[u8]) -> Result<Task, ErrorAct> {
-        match decode_new_block(data) {
-            Ok(new_block) => {
-                info!("NewBlock: {:?}", new_block);
-                Ok(Task::None)
+    /// Penalties for peers that are found to be misbehaving outside of their own response.
+    pub fn take_penalties(&mut self) -> Vec<Task> {
+        std::mem::take(&mut self.penalties)
+    }
+
+    pub fn process_block_bodies(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        if !self.body_sync.is_outstanding(task_id) {
+            return Ok(Task::None);
+        }
+        let bodies = match decode_block_bodies(&data) {
+            Ok(bodies) => bodies,
+            Err(err) => {
+                self.body_sync.failed(task_id);
+                return ErrorAct::new_invalid_generic(format!("Could not decode block bodies: {}", err));
+            }
+        };
+        debug!("Got {} block bodies from peer {}", bodies.len(), peer);
+        let (matched, result) = self.body_sync.response(task_id, bodies);
+        let mut chain = self.chain.lock().unwrap();
+        for (hash, body) in matched.iter() {
+            chain.import_block_body(hash, body);
+        }
+        result.map(|_| Task::None)
+    }
+
+    pub fn api_new_block(&mut self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let new_block = match decode_new_block(data) {
+            Ok(new_block) => new_block,
+            Err(err) => {
+                return ErrorAct::new_invalid_generic(
+                format!("Invalid NewBlock request: {}", err)
+                )
+            }
+        };
+        let header = new_block.header;
+        verify_header_fields(&header)?;
+        let body = BlockBody { transactions: new_block.transactions, ommers: new_block.ommers };
+        if !body.matches(&header) {
+            return ErrorAct::new_invalid_generic("NewBlock body does not match its header".into());
+        }
+        let hash = header.hash();
+        self.broadcast.mark_known(peer, hash);
+        debug!("Peer {} announced new block #{} {}", peer, header.number, hash);
+        let number = header.number;
+        let imported = self.announced_header(peer, header);
+        if !imported && self.chain.lock().unwrap().block_number(&hash).is_none() {
+            // number of block that does not fit on top of our chain is not trusted, its header
+            // is requested from peer same as announced hash before it can move sync target
+            debug!("Peer {} block #{} is queued for head request", peer, number);
+            let announced = self.announced.entry(*peer).or_default();
+            if !announced.contains(&hash) && announced.len() < MAX_ANNOUNCED_PER_PEER {
+                announced.push_back(hash);
+            }
+        }
+        if let Some(head) = self.peers.get_mut(peer) {
+            if head.hash == hash {
+                head.total_difficulty = Some(new_block.score);
+            }
+        }
+        if imported && self.body_sync.provided(&hash, &body) {
+            self.chain.lock().unwrap().import_block_body(&hash, &body);
+        }
+        Ok(Task::None)
+    }
+
+    fn retrieve_receipts(&self, hashes: &[H256]) -> Vec<Vec<Receipt>> {
+        let mut receipts = vec![];
+        for hash in hashes.iter().take(MAX_RECEIPTS_SERVE) {
+            if let Some(block_receipts) = self.chain.lock().unwrap().block_receipts(hash) {
+                receipts.push(block_receipts);
+            }
+        }
+        within_soft_limit(receipts, |block_receipts| rlp::encode_list(block_receipts).len())
+    }
+
+    pub fn api_get_receipts(&self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        match decode_get_receipts(data) {
+            Ok(ref hashes) => {
+                Ok(Task::Responde(
+                    *peer,
+                    ProtocolId::Eth,
+                    MessageId::Eth(EthMessageId::Receipts),
+                    encode_receipts(&self.retrieve_receipts(hashes)),
+                ))
             },
             Err(err) => {
-                ErrorAct::new_kick_generic(
-                format!("Invalid NewBlockHashes request: {}", err)
+                ErrorAct::new_invalid_generic::<Task>(
+                    format!("Invalid GetReceipts request: {}", err)
                 )
             }
         }
     }
 
-    pub fn api_get_receipts(&self) {}
+    pub fn process_receipts(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        if !self.receipt_sync.is_outstanding(task_id) {
+            return Ok(Task::None);
+        }
+        let receipts = match decode_receipts(data) {
+            Ok(receipts) => receipts,
+            Err(err) => {
+                self.receipt_sync.failed(task_id);
+                return ErrorAct::new_invalid_generic(format!("Could not decode receipts: {}", err));
+            }
+        };
+        debug!("Got receipts of {} blocks from peer {}", receipts.len(), peer);
+        let (matched, result) = self.receipt_sync.response(task_id, receipts);
+        let mut chain = self.chain.lock().unwrap();
+        for (hash, block_receipts) in matched.iter() {
+            chain.import_block_receipts(hash, block_receipts);
+        }
+        result.map(|_| Task::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{body, child, empty_body, header};
+    use super::super::rlp_en_de::{encode_new_block, encode_new_block_hashes};
+    use crate::client_adapter::headers_in_memory::HeadersInMemory;
+    use crate::common_types::{NewBlock, NewBlockHash};
+
+    fn genesis() -> BlockHeader {
+        header(0, &empty_body())
+    }
+
+    // manager on top of genesis, with peer 1 that has the same head
+    fn synced_manager(chain: &Arc<Mutex<HeadersInMemory>>) -> Arc<Mutex<BlockManager>> {
+        chain.lock().unwrap().import_block_header(&genesis());
+        let manager = BlockManager::new(chain.clone());
+        {
+            let mut manager = manager.lock().unwrap();
+            manager.new_peer(&1, genesis().hash(), None);
+            let request = manager.next_sync_task(&1, &MsgRate::default()).unwrap();
+            let response = encode_block_headers(&[genesis()]);
+            manager.process_block_headers(&1, &request.task_id, &response).unwrap();
+            assert!(!manager.is_syncing());
+        }
+        manager
+    }
+
+    #[test]
+    fn test_new_block_is_imported() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let header = child(&genesis(), &body(1));
+        let new_block = NewBlock {
+            header: header.clone(),
+            transactions: body(1).transactions,
+            ommers: vec![],
+            score: U256::from(2),
+        };
+        manager.api_new_block(&1, &encode_new_block(&new_block)).unwrap();
+
+        let chain = chain.lock().unwrap();
+        assert_eq!(chain.block_number(&header.hash()), Some(1));
+        assert_eq!(chain.block_body(&header.hash()), Some(body(1)));
+        assert_eq!(manager.peers[&1].number, Some(1));
+        assert_eq!(manager.peers[&1].total_difficulty, Some(U256::from(2)));
+    }
+
+    #[test]
+    fn test_new_block_with_wrong_body_is_rejected() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let new_block = NewBlock {
+            header: child(&genesis(), &body(1)),
+            transactions: body(2).transactions,
+            ommers: vec![],
+            score: U256::from(2),
+        };
+        assert!(manager.api_new_block(&1, &encode_new_block(&new_block)).is_err());
+        assert_eq!(chain.lock().unwrap().block_header(1), None);
+    }
+
+    #[test]
+    fn test_announced_hash_is_fetched_from_announcer() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let header = child(&genesis(), &body(1));
+        let announcement = encode_new_block_hashes(&[NewBlockHash::new(header.hash(), 1)]);
+        manager.api_new_block_hashes(&1, &announcement).unwrap();
+
+        let request = manager.next_sync_task(&1, &MsgRate::default()).unwrap();
+        let response = encode_block_headers(std::slice::from_ref(&header));
+        manager.process_block_headers(&1, &request.task_id, &response).unwrap();
+        assert_eq!(chain.lock().unwrap().block_number(&header.hash()), Some(1));
+
+        // body is fetched next
+        let request = manager.next_sync_task(&1, &MsgRate::default()).unwrap();
+        assert_eq!(request.message_id, MessageId::Eth(EthMessageId::GetBlockBodies));
+        assert!(manager.is_syncing());
+    }
+
+    #[test]
+    fn test_forged_new_block_does_not_move_target() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let mut header = child(&genesis(), &body(1));
+        header.number = u64::MAX;
+        let new_block = NewBlock {
+            header: header.clone(),
+            transactions: body(1).transactions,
+            ommers: vec![],
+            score: U256::from(2),
+        };
+        manager.api_new_block(&1, &encode_new_block(&new_block)).unwrap();
+        assert!(!manager.is_syncing());
+        assert_eq!(manager.peers[&1].number, Some(0));
+
+        // its header is requested from announcer instead
+        let request = manager.next_sync_task(&1, &MsgRate::default()).unwrap();
+        assert_eq!(manager.head_requests.get(&request.task_id), Some(&(1, header.hash())));
+    }
+
+    #[test]
+    fn test_disconnected_peer_head_is_not_sync_target() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let far = header(500, &empty_body());
+        manager.new_peer(&2, far.hash(), None);
+        let request = manager.next_sync_task(&2, &MsgRate::default()).unwrap();
+        manager.process_block_headers(&2, &request.task_id, &encode_block_headers(&[far])).unwrap();
+        assert_eq!(manager.sync_target(), Some(500));
+        assert!(manager.is_syncing());
+
+        manager.peer_disconnected(&2);
+        assert_eq!(manager.sync_target(), Some(0));
+        assert!(!manager.is_syncing());
+    }
+
+    #[test]
+    fn test_served_headers_are_capped() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        for header in crate::test_fixtures::chain(MAX_HEADERS_SERVE + 10) {
+            chain.lock().unwrap().import_block_header(&header);
+        }
+        let manager = BlockManager::new(chain);
+        let request = GetBlockHeaders::new(BlockId::Number(0), u64::MAX, 0, false);
+        let task = manager.lock().unwrap().api_get_block_headers(&1, &encode_get_block_headers(&request)).unwrap();
+        match task {
+            Task::Responde(_, _, _, data) => {
+                assert_eq!(decode_block_headers(&data).unwrap().len() as u64, MAX_HEADERS_SERVE)
+            }
+            _ => panic!("Headers are served"),
+        }
+    }
+
+    #[test]
+    fn test_served_bodies_are_capped() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let mut hashes = Vec::new();
+        for number in 0..MAX_BODIES_SERVE as u64 + 10 {
+            let header = header(number, &body(number));
+            chain.lock().unwrap().import_block_header(&header);
+            chain.lock().unwrap().import_block_body(&header.hash(), &body(number));
+            hashes.push(header.hash());
+        }
+        let manager = BlockManager::new(chain);
+        let task = manager.lock().unwrap().api_get_block_bodies(&1, &encode_get_block_bodies(&hashes)).unwrap();
+        match task {
+            Task::Responde(_, _, _, data) => assert_eq!(decode_block_bodies(&data).unwrap().len(), MAX_BODIES_SERVE),
+            _ => panic!("Bodies are served"),
+        }
+    }
+
+    #[test]
+    fn test_served_receipts_are_capped() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let mut hashes = Vec::new();
+        for number in 0..MAX_RECEIPTS_SERVE as u64 + 10 {
+            let header = header(number, &empty_body());
+            chain.lock().unwrap().import_block_header(&header);
+            chain.lock().unwrap().import_block_receipts(&header.hash(), &[]);
+            hashes.push(header.hash());
+        }
+        let manager = BlockManager::new(chain);
+        let task = manager.lock().unwrap().api_get_receipts(&1, &encode_get_receipts(&hashes)).unwrap();
+        match task {
+            Task::Responde(_, _, _, data) => assert_eq!(decode_receipts(&data).unwrap().len(), MAX_RECEIPTS_SERVE),
+            _ => panic!("Receipts are served"),
+        }
+    }
+
+    #[test]
+    fn test_soft_limit_keeps_item_that_crosses_it() {
+        let items = vec![SOFT_RESPONSE_LIMIT - 1, 10, 10];
+        assert_eq!(within_soft_limit(items, |size| *size), vec![SOFT_RESPONSE_LIMIT - 1, 10]);
+    }
+
+    #[test]
+    fn test_repeated_announcements_are_queued_once() {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        let manager = synced_manager(&chain);
+        let mut manager = manager.lock().unwrap();
+
+        let header = child(&genesis(), &body(1));
+        let announcement = encode_new_block_hashes(&[NewBlockHash::new(header.hash(), 1)]);
+        manager.api_new_block_hashes(&1, &announcement).unwrap();
+        manager.api_new_block_hashes(&1, &announcement).unwrap();
+        assert_eq!(manager.announced[&1].len(), 1);
+    }
 }
@@ -0,0 +1,116 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::rlp_en_de::{encode_new_block, encode_new_block_hashes};
+use crate::{
+    common_types::{NewBlock, NewBlockHash},
+    scheduler::peer_organizer::{PeerId, Task},
+    scheduler::propagation::{split_propagation, KnownHashes},
+    scheduler::protocol::{EthMessageId, MessageId, ProtocolId},
+};
+use primitive_types::H256;
+
+/// Max number of block hashes remembered per peer, set is cleared when it grows over it.
+pub const MAX_KNOWN_BLOCKS: usize = 1024;
+
+/// Remembers which blocks every peer has, so that block is never sent to peer that
+/// announced it to us or already got it from us.
+pub struct BlockBroadcast {
+    known: KnownHashes,
+}
+
+impl BlockBroadcast {
+    pub fn new() -> Self {
+        BlockBroadcast {
+            known: KnownHashes::new(MAX_KNOWN_BLOCKS),
+        }
+    }
+
+    pub fn mark_known(&mut self, peer: &PeerId, hash: H256) {
+        self.known.mark_known(peer, hash);
+    }
+
+    pub fn is_known(&self, peer: &PeerId, hash: &H256) -> bool {
+        self.known.is_known(peer, hash)
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.known.peer_disconnected(peer);
+    }
+
+    /// Square root of `peers` that don't have block gets full NewBlock, others get NewBlockHashes.
+    pub fn broadcast(&mut self, block: &NewBlock, peers: &[PeerId]) -> Vec<Task> {
+        let hash = block.header.hash();
+        let peers: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer| !self.is_known(peer, &hash))
+            .copied()
+            .collect();
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let full_block = encode_new_block(block);
+        let announcement = encode_new_block_hashes(&[NewBlockHash::new(hash, block.header.number)]);
+        let mut tasks = Vec::new();
+        for (peer, full) in split_propagation(&hash, &peers) {
+            let (message_id, data) = if full {
+                (EthMessageId::NewBlock, full_block.clone())
+            } else {
+                (EthMessageId::NewBlockHashes, announcement.clone())
+            };
+            tasks.push(Task::Responde(*peer, ProtocolId::Eth, MessageId::Eth(message_id), data));
+            self.mark_known(peer, hash);
+        }
+        tasks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::test_fixtures::{empty_body, header};
+    use primitive_types::U256;
+
+    fn block() -> NewBlock {
+        NewBlock {
+            header: header(10, &empty_body()),
+            transactions: vec![],
+            ommers: vec![],
+            score: U256::from(100),
+        }
+    }
+
+    fn message_ids(tasks: &[Task]) -> HashMap<PeerId, EthMessageId> {
+        tasks
+            .iter()
+            .map(|task| match task {
+                Task::Responde(peer, _, MessageId::Eth(message_id), _) => (*peer, *message_id),
+                _ => panic!("Unexpected task {:?}", task),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_block_is_sent_to_sqrt_of_peers() {
+        let mut broadcast = BlockBroadcast::new();
+        let peers: Vec<PeerId> = (1..=10).collect();
+        broadcast.mark_known(&3, block().header.hash());
+        let sent = message_ids(&broadcast.broadcast(&block(), &peers));
+        assert_eq!(sent.len(), 9);
+        assert!(!sent.contains_key(&3));
+        let full = sent.values().filter(|id| **id == EthMessageId::NewBlock).count();
+        assert_eq!(full, 3);
+        // block is not sent twice
+        assert!(broadcast.broadcast(&block(), &peers).is_empty());
+    }
+
+    #[test]
+    fn test_known_blocks_are_forgotten_with_peer() {
+        let mut broadcast = BlockBroadcast::new();
+        let hash = block().header.hash();
+        broadcast.mark_known(&1, hash);
+        broadcast.peer_disconnected(&1);
+        assert!(!broadcast.is_known(&1, &hash));
+    }
+}
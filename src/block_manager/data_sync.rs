@@ -0,0 +1,358 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    common_types::{BlockBody, BlockHeader, BlockNumber, Receipt, trie::ordered_trie_root},
+    scheduler::peer_organizer::{ErrorAct, PeerId, TaskId},
+};
+use primitive_types::H256;
+use std::collections::{BTreeMap, HashMap};
+
+/// Max number of items (bodies or block receipts) we ask from one peer in a single request.
+pub const MAX_ITEMS_PER_REQUEST: usize = 128;
+
+/// Part of block that is downloaded separately from header and that can be verified by roots in header.
+pub trait BlockData: Sized {
+    type Root: PartialEq + Copy;
+    /// Roots from header that data needs to match.
+    fn expected_root(header: &BlockHeader) -> Self::Root;
+    fn root(&self) -> Self::Root;
+    fn empty() -> Self;
+}
+
+impl BlockData for BlockBody {
+    type Root = (H256, H256);
+
+    fn expected_root(header: &BlockHeader) -> Self::Root {
+        (header.transactions_root, header.ommers_hash)
+    }
+
+    fn root(&self) -> Self::Root {
+        (self.transactions_root(), self.ommers_hash())
+    }
+
+    fn empty() -> Self {
+        BlockBody { transactions: Vec::new(), ommers: Vec::new() }
+    }
+}
+
+impl BlockData for Vec<Receipt> {
+    type Root = H256;
+
+    fn expected_root(header: &BlockHeader) -> Self::Root {
+        header.receipts_root
+    }
+
+    fn root(&self) -> Self::Root {
+        ordered_trie_root(self.iter().map(rlp::encode))
+    }
+
+    fn empty() -> Self {
+        Vec::new()
+    }
+}
+
+/// Tracks imported headers that don't have their data (body or receipts) yet. Their hashes are
+/// requested from peers in batches and returned data is matched back to headers by roots.
+pub struct BlockDataSync<T: BlockData> {
+    // roots that data of header with hash needs to match
+    targets: HashMap<H256, (BlockNumber, T::Root)>,
+    // hashes that need to be requested, ordered by block number
+    pending: BTreeMap<BlockNumber, H256>,
+    outstanding: HashMap<TaskId, (PeerId, Vec<H256>)>,
+    // roots of data that was provided while it was requested, it is skipped in response
+    provided_in_flight: HashMap<H256, T::Root>,
+    // data that doesn't need to be downloaded
+    empty: Vec<(H256, T)>,
+    empty_root: T::Root,
+}
+
+pub type BodySync = BlockDataSync<BlockBody>;
+pub type ReceiptSync = BlockDataSync<Vec<Receipt>>;
+
+impl<T: BlockData> BlockDataSync<T> {
+    pub fn new() -> Self {
+        BlockDataSync {
+            targets: HashMap::new(),
+            pending: BTreeMap::new(),
+            outstanding: HashMap::new(),
+            provided_in_flight: HashMap::new(),
+            empty: Vec::new(),
+            empty_root: T::empty().root(),
+        }
+    }
+
+    /// Nothing is pending or in flight.
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty() && self.outstanding.is_empty() && self.empty.is_empty()
+    }
+
+    /// Header was imported and its data needs to be downloaded.
+    pub fn header_imported(&mut self, header: &BlockHeader) {
+        let hash = header.hash();
+        let root = T::expected_root(header);
+        if root == self.empty_root {
+            self.empty.push((hash, T::empty()));
+            return;
+        }
+        self.targets.insert(hash, (header.number, root));
+        self.pending.insert(header.number, hash);
+    }
+
+    /// Data of empty blocks, it is known without asking anybody.
+    pub fn take_empty(&mut self) -> Vec<(H256, T)> {
+        std::mem::take(&mut self.empty)
+    }
+
+    /// Next batch of at most `max` hashes that peer with head at `peer_head` can serve.
+    pub fn next_batch(&mut self, peer_head: BlockNumber, max: usize) -> Option<Vec<H256>> {
+        let numbers: Vec<BlockNumber> = self
+            .pending
+            .range(..=peer_head)
+            .take(max)
+            .map(|(number, _)| *number)
+            .collect();
+        if numbers.is_empty() {
+            return None;
+        }
+        Some(numbers.iter().filter_map(|number| self.pending.remove(number)).collect())
+    }
+
+    pub fn request_sent(&mut self, task_id: TaskId, peer: PeerId, hashes: Vec<H256>) {
+        self.outstanding.insert(task_id, (peer, hashes));
+    }
+
+    pub fn is_outstanding(&self, task_id: &TaskId) -> bool {
+        self.outstanding.contains_key(task_id)
+    }
+
+    fn requeue(&mut self, hashes: &[H256]) {
+        for hash in hashes {
+            if let Some((number, _)) = self.targets.get(hash) {
+                self.pending.insert(*number, *hash);
+            }
+        }
+    }
+
+    /// Matches data to hashes requested with `task_id`. Peers return items in order of
+    /// request but can skip ones they don't have. Matched items are returned with hash of
+    /// their header, and hashes without data are requested again. Items that were provided
+    /// meanwhile are skipped. If some item does not belong to any requested header, matched
+    /// ones are still returned with error for peer.
+    pub fn response(&mut self, task_id: &TaskId, items: Vec<T>) -> (Vec<(H256, T)>, Result<(), ErrorAct>) {
+        let hashes = match self.outstanding.remove(task_id) {
+            Some((_, hashes)) => hashes,
+            None => return (Vec::new(), Ok(())),
+        };
+        let mut matched = Vec::new();
+        let mut missing = Vec::new();
+        let mut skipped = 0;
+        let mut result = Ok(());
+        let mut requested = hashes.iter();
+        'items: for item in items {
+            let root = item.root();
+            for hash in requested.by_ref() {
+                if self.targets.get(hash).map(|(_, target)| *target) == Some(root) {
+                    matched.push((*hash, item));
+                    continue 'items;
+                }
+                if self.provided_in_flight.get(hash) == Some(&root) {
+                    skipped += 1;
+                    continue 'items;
+                }
+                missing.push(*hash);
+            }
+            result = ErrorAct::new_invalid("Block data does not match any requested header".into());
+            break;
+        }
+        missing.extend(requested);
+        for hash in hashes.iter() {
+            self.provided_in_flight.remove(hash);
+        }
+        if matched.is_empty() && skipped == 0 && result.is_ok() {
+            result = ErrorAct::new_useless("Peer returned no block data".into());
+        }
+        for (hash, _) in matched.iter() {
+            self.targets.remove(hash);
+        }
+        self.requeue(&missing);
+        (matched, result)
+    }
+
+    /// Data that came without request, like body in NewBlock. It is accepted only if
+    /// imported header waits for it.
+    pub fn provided(&mut self, hash: &H256, item: &T) -> bool {
+        match self.targets.get(hash) {
+            Some((number, root)) if *root == item.root() => {
+                let (number, root) = (*number, *root);
+                self.targets.remove(hash);
+                self.pending.remove(&number);
+                if self.outstanding.values().any(|(_, hashes)| hashes.contains(hash)) {
+                    self.provided_in_flight.insert(*hash, root);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Header was replaced in chain by other one with the same number, its data is not synced anymore.
+    pub fn header_removed(&mut self, hash: &H256) {
+        if let Some((number, _)) = self.targets.remove(hash) {
+            if self.pending.get(&number) == Some(hash) {
+                self.pending.remove(&number);
+            }
+        }
+    }
+
+    /// Request was not answered, hashes need to be requested from someone else.
+    pub fn failed(&mut self, task_id: &TaskId) {
+        if let Some((_, hashes)) = self.outstanding.remove(task_id) {
+            for hash in hashes.iter() {
+                self.provided_in_flight.remove(hash);
+            }
+            self.requeue(&hashes);
+        }
+    }
+
+    /// Request `task_id` is going to be sent again to `peer` with head at `peer_head`.
+    /// Returns false if request is not ours or peer can't serve it.
+    pub fn reassign(&mut self, task_id: &TaskId, peer: PeerId, peer_head: BlockNumber) -> bool {
+        let targets = &self.targets;
+        match self.outstanding.get_mut(task_id) {
+            Some((request_peer, hashes))
+                if hashes
+                    .iter()
+                    .all(|hash| targets.get(hash).is_some_and(|(number, _)| *number <= peer_head)) =>
+            {
+                *request_peer = peer;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_types::ReceiptOutcome;
+    use crate::test_fixtures::{body, empty_body, header};
+    use primitive_types::U256;
+
+    #[test]
+    fn test_receipts_are_matched_by_root() {
+        let receipts = vec![Receipt {
+            outcome: ReceiptOutcome::StatusCode(1),
+            cumulative_gas_used: U256::from(21000),
+            logs_bloom: vec![0; 256],
+            logs: vec![],
+        }];
+        let mut header = header(1, &body(1));
+        header.receipts_root = receipts.root();
+        let mut sync = ReceiptSync::new();
+        sync.header_imported(&header);
+        let batch = sync.next_batch(10, MAX_ITEMS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, batch);
+        let (matched, result) = sync.response(&1, vec![receipts.clone()]);
+        assert!(result.is_ok());
+        assert_eq!(matched, vec![(header.hash(), receipts)]);
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_provided_body_is_not_requested() {
+        let mut sync = BodySync::new();
+        let header = header(1, &body(1));
+        assert!(!sync.provided(&header.hash(), &body(1)));
+        sync.header_imported(&header);
+        assert!(!sync.provided(&header.hash(), &body(2)));
+        assert!(sync.provided(&header.hash(), &body(1)));
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), None);
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_replaced_header_is_not_requested() {
+        let mut sync = BodySync::new();
+        let old = header(1, &body(1));
+        let new = header(1, &body(2));
+        sync.header_imported(&old);
+        sync.header_imported(&new);
+        sync.header_removed(&old.hash());
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), Some(vec![new.hash()]));
+        assert!(!sync.provided(&old.hash(), &body(1)));
+    }
+
+    #[test]
+    fn test_body_provided_while_requested_is_skipped() {
+        let mut sync = BodySync::new();
+        let headers: Vec<BlockHeader> = (1..=3).map(|n| header(n, &body(n))).collect();
+        headers.iter().for_each(|header| sync.header_imported(header));
+        let first = sync.next_batch(2, MAX_ITEMS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, first);
+        let second = sync.next_batch(3, MAX_ITEMS_PER_REQUEST).unwrap();
+        sync.request_sent(2, 11, second);
+        assert!(sync.provided(&headers[0].hash(), &body(1)));
+        assert!(sync.provided(&headers[2].hash(), &body(3)));
+
+        let (matched, result) = sync.response(&1, vec![body(1), body(2)]);
+        assert!(result.is_ok());
+        assert_eq!(matched, vec![(headers[1].hash(), body(2))]);
+        // only provided body is not useless either
+        let (matched, result) = sync.response(&2, vec![body(3)]);
+        assert!(result.is_ok());
+        assert!(matched.is_empty());
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_empty_bodies_are_not_requested() {
+        let mut sync = BodySync::new();
+        sync.header_imported(&header(1, &empty_body()));
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), None);
+        assert_eq!(sync.take_empty().len(), 1);
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_bodies_are_matched_and_missing_requeued() {
+        let mut sync = BodySync::new();
+        let headers: Vec<BlockHeader> = (1..=3).map(|n| header(n, &body(n))).collect();
+        headers.iter().for_each(|header| sync.header_imported(header));
+        assert_eq!(sync.next_batch(2, MAX_ITEMS_PER_REQUEST).unwrap().len(), 2);
+
+        let mut sync = BodySync::new();
+        headers.iter().for_each(|header| sync.header_imported(header));
+        let batch = sync.next_batch(10, MAX_ITEMS_PER_REQUEST).unwrap();
+        assert_eq!(batch.len(), 3);
+        sync.request_sent(1, 10, batch);
+
+        // peer skipped second body
+        let (matched, result) = sync.response(&1, vec![body(1), body(3)]);
+        assert!(result.is_ok());
+        let matched: Vec<H256> = matched.into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(matched, vec![headers[0].hash(), headers[2].hash()]);
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), Some(vec![headers[1].hash()]));
+    }
+
+    #[test]
+    fn test_invalid_body_is_rejected() {
+        let mut sync = BodySync::new();
+        let headers: Vec<BlockHeader> = (1..=2).map(|n| header(n, &body(n))).collect();
+        headers.iter().for_each(|header| sync.header_imported(header));
+        let batch = sync.next_batch(10, MAX_ITEMS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, batch.clone());
+        let (matched, result) = sync.response(&1, vec![body(1), body(7)]);
+        assert_eq!(matched.len(), 1);
+        assert!(result.is_err());
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), Some(vec![headers[1].hash()]));
+
+        sync.request_sent(2, 11, vec![headers[1].hash()]);
+        // retry can go only to peer that has the block
+        assert!(!sync.reassign(&2, 12, 1));
+        assert!(sync.reassign(&2, 12, 2));
+        sync.failed(&2);
+        assert_eq!(sync.next_batch(10, MAX_ITEMS_PER_REQUEST), Some(vec![headers[1].hash()]));
+    }
+}
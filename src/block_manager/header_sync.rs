@@ -0,0 +1,580 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    common_types::{BlockHeader, BlockNumber},
+    scheduler::peer_organizer::{ErrorAct, PeerId, TaskId},
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Max number of headers we ask from one peer in a single request.
+pub const MAX_HEADERS_PER_REQUEST: u64 = 192;
+/// Distance between two skeleton headers, every gap is filled with one request.
+pub const SKELETON_STEP: u64 = MAX_HEADERS_PER_REQUEST;
+/// How many downloaded but not yet imported ranges we keep before we stop requesting new ones.
+const MAX_BUFFERED_RANGES: usize = 64;
+/// Max number of skeleton headers requested at once.
+const MAX_SKELETON_SIZE: u64 = MAX_BUFFERED_RANGES as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeaderRange {
+    pub start: BlockNumber,
+    pub count: u64,
+}
+
+impl HeaderRange {
+    pub fn new(start: BlockNumber, count: u64) -> Self {
+        HeaderRange { start, count }
+    }
+
+    pub fn end(&self) -> BlockNumber {
+        self.start + self.count - 1
+    }
+}
+
+/// Sparse skeleton request, headers at `start`, `start + SKELETON_STEP`, ...
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkeletonRequest {
+    pub start: BlockNumber,
+    pub count: u64,
+}
+
+/// Tracks contiguous header ranges that are requested from peers in parallel.
+/// Ranges are handed out in ascending order, filled by responses in any order
+/// and released for import only when they connect to the last imported header.
+///
+/// While we are far from target, ranges are not handed out blindly. First one peer gives us
+/// skeleton (every `SKELETON_STEP`th header) and every range is then filled by other peers
+/// and must end with matching skeleton header. This way no single filler can feed us
+/// alternative chain.
+pub struct HeaderSync {
+    // first block number that was not handed out yet
+    next: BlockNumber,
+    // first block number that is not imported yet
+    import_from: BlockNumber,
+    // highest head advertised by peers
+    target: Option<BlockNumber>,
+    // ranges that failed or were partially filled and need to be requested again
+    pending: VecDeque<HeaderRange>,
+    outstanding: HashMap<TaskId, (PeerId, HeaderRange)>,
+    // downloaded ranges by first block number, with peer that served them
+    downloaded: BTreeMap<BlockNumber, (PeerId, Vec<BlockHeader>)>,
+    // skeleton request in flight, with peer that serves it
+    skeleton: Option<(TaskId, PeerId, SkeletonRequest)>,
+    // skeleton headers that fills need to match, by block number, with peer that served them
+    anchors: HashMap<BlockNumber, (PeerId, BlockHeader)>,
+    // last two batches returned by `next_ready`, with peers that served them
+    ready: [Option<(PeerId, HeaderRange)>; 2],
+}
+
+impl HeaderSync {
+    pub fn new(start: BlockNumber) -> Self {
+        HeaderSync {
+            next: start,
+            import_from: start,
+            target: None,
+            pending: VecDeque::new(),
+            outstanding: HashMap::new(),
+            downloaded: BTreeMap::new(),
+            skeleton: None,
+            anchors: HashMap::new(),
+            ready: [None, None],
+        }
+    }
+
+    pub fn target(&self) -> Option<BlockNumber> {
+        self.target
+    }
+
+    pub fn set_target(&mut self, number: BlockNumber) {
+        self.target = std::cmp::max(self.target, Some(number));
+    }
+
+    /// Replaces target with highest head of peers that are still connected. Without any known
+    /// head, target is what we have already imported.
+    pub fn retarget(&mut self, target: Option<BlockNumber>) {
+        self.target = target.or_else(|| self.import_from.checked_sub(1));
+    }
+
+    /// All headers up to target are imported and there is nothing in flight.
+    pub fn is_complete(&self) -> bool {
+        match self.target {
+            Some(target) => self.import_from > target && self.outstanding.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Header announced by peer when everything before it is already imported. It is queued
+    /// for import directly instead of being requested again. Returns false if header doesn't follow.
+    pub fn announced(&mut self, peer: PeerId, header: BlockHeader) -> bool {
+        if header.number != self.next || self.next != self.import_from {
+            return false;
+        }
+        self.set_target(header.number);
+        self.next += 1;
+        self.downloaded.insert(header.number, (peer, vec![header]));
+        true
+    }
+
+    /// Next range of at most `max` headers that peer with head at `peer_head` can serve.
+    /// Bigger pending range is split and its tail stays pending.
+    pub fn next_range(&mut self, peer_head: BlockNumber, max: u64) -> Option<HeaderRange> {
+        if let Some(index) = self.pending.iter().position(|range| range.start <= peer_head) {
+            let range = self.pending.get_mut(index)?;
+            if range.count > max {
+                let head = HeaderRange::new(range.start, max);
+                *range = HeaderRange::new(range.start + max, range.count - max);
+                return Some(head);
+            }
+            return self.pending.remove(index);
+        }
+        if self.downloaded.len() >= MAX_BUFFERED_RANGES {
+            return None;
+        }
+        let last = std::cmp::min(self.target?, peer_head);
+        if self.next > last || self.needs_skeleton(last) {
+            return None;
+        }
+        let count = std::cmp::min(max, last - self.next + 1);
+        let range = HeaderRange::new(self.next, count);
+        self.next += count;
+        Some(range)
+    }
+
+    /// There is at least one full gap before `last`, it should be covered by skeleton.
+    fn needs_skeleton(&self, last: BlockNumber) -> bool {
+        last >= self.next && last - self.next + 1 >= SKELETON_STEP
+    }
+
+    /// Skeleton that should be requested from peer with head at `peer_head`, any peer that has
+    /// at least one full gap can serve it. Only one skeleton is in flight and new one is
+    /// requested after all gaps of the previous one are handed out.
+    pub fn next_skeleton(&mut self, peer_head: BlockNumber) -> Option<SkeletonRequest> {
+        if self.skeleton.is_some() || !self.pending.is_empty() {
+            return None;
+        }
+        if self.downloaded.len() >= MAX_BUFFERED_RANGES {
+            return None;
+        }
+        let last = std::cmp::min(self.target?, peer_head);
+        if !self.needs_skeleton(last) {
+            return None;
+        }
+        let count = std::cmp::min(MAX_SKELETON_SIZE, (last - self.next + 1) / SKELETON_STEP);
+        if count == 0 {
+            return None;
+        }
+        Some(SkeletonRequest {
+            start: self.next + SKELETON_STEP - 1,
+            count,
+        })
+    }
+
+    pub fn skeleton_sent(&mut self, task_id: TaskId, peer: PeerId, request: SkeletonRequest) {
+        self.skeleton = Some((task_id, peer, request));
+    }
+
+    pub fn is_skeleton(&self, task_id: &TaskId) -> bool {
+        matches!(self.skeleton, Some((id, _, _)) if id == *task_id)
+    }
+
+    /// Every received skeleton header becomes end of one range that is filled from other peers.
+    /// Peer was asked only for headers up to its head, so short skeleton is not accepted.
+    pub fn skeleton_response(&mut self, task_id: &TaskId, headers: Vec<BlockHeader>) -> Result<(), ErrorAct> {
+        let (peer, request) = match self.skeleton {
+            Some((id, peer, request)) if id == *task_id => (peer, request),
+            _ => return Ok(()),
+        };
+        self.skeleton = None;
+        let matching = headers
+            .iter()
+            .enumerate()
+            .all(|(i, header)| header.number == request.start + i as u64 * SKELETON_STEP);
+        if !matching || headers.len() as u64 > request.count {
+            return ErrorAct::new_invalid("Skeleton headers do not match request".into());
+        }
+        if (headers.len() as u64) < request.count {
+            return ErrorAct::new_useless(format!("Peer returned {} of {} skeleton headers", headers.len(), request.count));
+        }
+        for header in headers {
+            if header.number < self.next {
+                continue;
+            }
+            self.pending
+                .push_back(HeaderRange::new(header.number + 1 - SKELETON_STEP, SKELETON_STEP));
+            self.next = header.number + 1;
+            self.anchors.insert(header.number, (peer, header));
+        }
+        Ok(())
+    }
+
+    /// Drops skeleton headers served by `peer`, together with everything that was requested or
+    /// downloaded against them. Their gaps are covered by new skeleton from other peer.
+    fn drop_skeleton(&mut self, peer: PeerId) {
+        let first_gap = self
+            .anchors
+            .iter()
+            .filter(|(_, (anchor_peer, _))| *anchor_peer == peer)
+            .map(|(number, _)| number + 1 - SKELETON_STEP)
+            .min();
+        let from = match first_gap {
+            Some(first_gap) => std::cmp::max(first_gap, self.import_from),
+            None => return,
+        };
+        self.anchors.retain(|number, _| *number < from);
+        self.pending.retain(|range| range.start < from);
+        self.outstanding.retain(|_, (_, range)| range.start < from);
+        self.downloaded.retain(|start, _| *start < from);
+        self.next = std::cmp::min(self.next, from);
+    }
+
+    pub fn request_sent(&mut self, task_id: TaskId, peer: PeerId, range: HeaderRange) {
+        self.outstanding.insert(task_id, (peer, range));
+    }
+
+    pub fn is_outstanding(&self, task_id: &TaskId) -> bool {
+        self.outstanding.contains_key(task_id)
+    }
+
+    /// Fills range requested with `task_id`. Missing tail of range is queued again. Headers that
+    /// are valid chain but do not match skeleton disprove the skeleton, it is dropped and peer
+    /// that served it is returned to be penalized.
+    pub fn response(&mut self, task_id: &TaskId, headers: Vec<BlockHeader>) -> Result<Option<PeerId>, ErrorAct> {
+        let (peer, range) = match self.outstanding.remove(task_id) {
+            Some(outstanding) => outstanding,
+            None => return Ok(None),
+        };
+        if headers.is_empty() {
+            self.pending.push_front(range);
+            return ErrorAct::new_useless("Peer returned no headers".into());
+        }
+        let contiguous = headers
+            .iter()
+            .enumerate()
+            .all(|(i, header)| header.number == range.start + i as u64);
+        if !contiguous || headers.len() as u64 > range.count {
+            self.pending.push_front(range);
+            return ErrorAct::new_invalid_generic("Headers do not match requested range".into());
+        }
+        let disproved = headers.iter().find_map(|header| match self.anchors.get(&header.number) {
+            Some((anchor_peer, anchor)) if anchor != header => Some(*anchor_peer),
+            _ => None,
+        });
+        if let Some(skeleton_peer) = disproved {
+            self.drop_skeleton(skeleton_peer);
+            return Ok(Some(skeleton_peer));
+        }
+        let received = headers.len() as u64;
+        if received < range.count {
+            self.pending
+                .push_front(HeaderRange::new(range.start + received, range.count - received));
+        }
+        self.downloaded.insert(range.start, (peer, headers));
+        Ok(None)
+    }
+
+    /// Request was not answered, range needs to be requested from someone else.
+    pub fn failed(&mut self, task_id: &TaskId) {
+        if self.is_skeleton(task_id) {
+            self.skeleton = None;
+        }
+        if let Some((_, range)) = self.outstanding.remove(task_id) {
+            self.pending.push_front(range);
+        }
+    }
+
+    /// Request `task_id` is going to be sent again to `peer` with head at `peer_head`.
+    /// Returns false if request is not ours or peer can't serve it.
+    pub fn reassign(&mut self, task_id: &TaskId, peer: PeerId, peer_head: BlockNumber) -> bool {
+        if let Some((id, skeleton_peer, request)) = self.skeleton.as_mut() {
+            if *id == *task_id {
+                if request.start + (request.count - 1) * SKELETON_STEP > peer_head {
+                    return false;
+                }
+                *skeleton_peer = peer;
+                return true;
+            }
+        }
+        match self.outstanding.get_mut(task_id) {
+            Some((range_peer, range)) if range.end() <= peer_head => {
+                *range_peer = peer;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Next downloaded batch that connects to already imported chain, with peer that served it.
+    pub fn next_ready(&mut self) -> Option<(PeerId, Vec<BlockHeader>)> {
+        // anchors bellow import point are not needed anymore
+        let import_from = self.import_from;
+        self.anchors.retain(|number, _| *number >= import_from);
+        let (peer, headers) = self.downloaded.remove(&self.import_from)?;
+        let range = HeaderRange::new(self.import_from, headers.len() as u64);
+        self.ready = [self.ready[1], Some((peer, range))];
+        self.import_from += range.count;
+        Some((peer, headers))
+    }
+
+    /// Batch returned by `next_ready` does not fit on top of its parent and needs to be requested
+    /// again. It is not known which side is wrong, so batch with parent is requested again too,
+    /// if it was synced, and peer that served it is returned.
+    pub fn reject(&mut self, range: HeaderRange) -> Option<PeerId> {
+        let parent = match self.ready {
+            [Some((peer, parent)), Some((_, rejected))] if rejected == range && parent.end() + 1 == range.start => {
+                Some((peer, parent))
+            }
+            _ => None,
+        };
+        self.ready = [None, None];
+        self.pending.push_front(range);
+        self.import_from = range.start;
+        let (peer, parent) = parent?;
+        self.pending.push_front(parent);
+        self.import_from = parent.start;
+        Some(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, empty_body};
+
+    fn header(number: BlockNumber) -> BlockHeader {
+        test_fixtures::header(number, &empty_body())
+    }
+
+    fn headers(range: HeaderRange) -> Vec<BlockHeader> {
+        (range.start..=range.end()).map(header).collect()
+    }
+
+    fn drain_ready(sync: &mut HeaderSync) -> Vec<BlockHeader> {
+        let mut ready = Vec::new();
+        while let Some((_, headers)) = sync.next_ready() {
+            ready.extend(headers);
+        }
+        ready
+    }
+
+    #[test]
+    fn test_announced_header_is_imported_directly() {
+        let mut sync = HeaderSync::new(1);
+        // there is a gap before announced header
+        assert!(!sync.announced(1, header(2)));
+        assert!(sync.announced(1, header(1)));
+        assert_eq!(sync.target(), Some(1));
+        assert_eq!(drain_ready(&mut sync), vec![header(1)]);
+        assert!(sync.is_complete());
+        assert!(sync.announced(2, header(2)));
+        assert_eq!(sync.next_range(10, MAX_HEADERS_PER_REQUEST), None);
+    }
+
+    #[test]
+    fn test_ranges_are_split_up_to_peer_head() {
+        let mut sync = HeaderSync::new(1);
+        assert_eq!(sync.next_range(1000, MAX_HEADERS_PER_REQUEST), None);
+        sync.set_target(150);
+        assert_eq!(sync.next_skeleton(150), None);
+        assert_eq!(sync.next_range(100, MAX_HEADERS_PER_REQUEST), Some(HeaderRange::new(1, 100)));
+        assert_eq!(sync.next_range(1000, MAX_HEADERS_PER_REQUEST), Some(HeaderRange::new(101, 50)));
+        assert_eq!(sync.next_range(1000, MAX_HEADERS_PER_REQUEST), None);
+    }
+
+    #[test]
+    fn test_skeleton_gaps_are_filled() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(500);
+        // nothing is handed out blindly before skeleton arrives
+        assert_eq!(sync.next_range(500, MAX_HEADERS_PER_REQUEST), None);
+        let skeleton = sync.next_skeleton(500).unwrap();
+        assert_eq!(skeleton, SkeletonRequest { start: 192, count: 2 });
+        sync.skeleton_sent(1, 9, skeleton);
+        assert_eq!(sync.next_skeleton(500), None);
+        sync.skeleton_response(&1, vec![header(192), header(384)]).unwrap();
+
+        let first = sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        let second = sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        assert_eq!(first, HeaderRange::new(1, 192));
+        assert_eq!(second, HeaderRange::new(193, 192));
+        // tail shorter than one gap is requested without skeleton
+        assert_eq!(sync.next_range(500, MAX_HEADERS_PER_REQUEST), Some(HeaderRange::new(385, 116)));
+
+        sync.request_sent(2, 10, first);
+        sync.request_sent(3, 11, second);
+        assert_eq!(sync.response(&3, headers(second)).unwrap(), None);
+        assert_eq!(sync.response(&2, headers(first)).unwrap(), None);
+        assert_eq!(drain_ready(&mut sync).len(), 384);
+    }
+
+    #[test]
+    fn test_fill_that_disproves_skeleton_drops_it() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(500);
+        let skeleton = sync.next_skeleton(500).unwrap();
+        sync.skeleton_sent(1, 9, skeleton);
+        let mut forged = header(384);
+        forged.timestamp += 1;
+        sync.skeleton_response(&1, vec![header(192), forged]).unwrap();
+        let first = sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        let second = sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.request_sent(2, 10, first);
+        sync.request_sent(3, 11, second);
+        assert_eq!(sync.response(&3, headers(second)).unwrap(), Some(9));
+
+        // nothing that was requested against forged skeleton is used
+        assert!(!sync.is_outstanding(&2));
+        assert_eq!(sync.next_range(500, MAX_HEADERS_PER_REQUEST), None);
+        assert_eq!(sync.next_skeleton(500), Some(skeleton));
+        assert!(drain_ready(&mut sync).is_empty());
+    }
+
+    #[test]
+    fn test_short_skeleton_is_rejected() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(500);
+        let skeleton = sync.next_skeleton(500).unwrap();
+        sync.skeleton_sent(1, 9, skeleton);
+        assert!(sync.skeleton_response(&1, vec![header(192)]).is_err());
+        sync.skeleton_sent(2, 9, skeleton);
+        assert!(sync.skeleton_response(&2, vec![]).is_err());
+        assert_eq!(sync.next_skeleton(500), Some(skeleton));
+    }
+
+    #[test]
+    fn test_peers_below_target_serve_skeleton_and_tail() {
+        let mut sync = HeaderSync::new(1);
+        // target of peer that does not serve its chain
+        sync.set_target(100_000);
+        let skeleton = sync.next_skeleton(500).unwrap();
+        assert_eq!(skeleton, SkeletonRequest { start: 192, count: 2 });
+        sync.skeleton_sent(1, 9, skeleton);
+        sync.skeleton_response(&1, vec![header(192), header(384)]).unwrap();
+        sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        assert_eq!(sync.next_range(500, MAX_HEADERS_PER_REQUEST), Some(HeaderRange::new(385, 116)));
+    }
+
+    #[test]
+    fn test_target_is_lowered_by_retarget() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(1000);
+        sync.retarget(Some(150));
+        assert_eq!(sync.target(), Some(150));
+        assert_eq!(sync.next_skeleton(1000), None);
+        assert_eq!(sync.next_range(1000, MAX_HEADERS_PER_REQUEST), Some(HeaderRange::new(1, 150)));
+        sync.retarget(None);
+        assert_eq!(sync.target(), Some(0));
+    }
+
+    #[test]
+    fn test_slow_peer_gets_part_of_skeleton_gap() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(500);
+        let skeleton = sync.next_skeleton(500).unwrap();
+        sync.skeleton_sent(1, 9, skeleton);
+        sync.skeleton_response(&1, vec![header(192), header(384)]).unwrap();
+
+        let slow = sync.next_range(500, 50).unwrap();
+        assert_eq!(slow, HeaderRange::new(1, 50));
+        let fast = sync.next_range(500, MAX_HEADERS_PER_REQUEST).unwrap();
+        assert_eq!(fast, HeaderRange::new(51, 142));
+        sync.request_sent(2, 10, slow);
+        sync.request_sent(3, 11, fast);
+        sync.response(&3, headers(fast)).unwrap();
+        sync.response(&2, headers(slow)).unwrap();
+        assert_eq!(drain_ready(&mut sync).len(), 192);
+    }
+
+    #[test]
+    fn test_invalid_skeleton_is_rejected() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(1000);
+        let skeleton = sync.next_skeleton(1000).unwrap();
+        sync.skeleton_sent(1, 9, skeleton);
+        assert!(sync.skeleton_response(&1, vec![header(192), header(200)]).is_err());
+        assert_eq!(sync.next_skeleton(1000), Some(skeleton));
+    }
+
+    #[test]
+    fn test_out_of_order_responses_are_imported_in_order() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(400);
+        let skeleton = sync.next_skeleton(400).unwrap();
+        sync.skeleton_sent(3, 9, skeleton);
+        sync.skeleton_response(&3, vec![header(192), header(384)]).unwrap();
+        let first = sync.next_range(400, MAX_HEADERS_PER_REQUEST).unwrap();
+        let second = sync.next_range(400, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, first);
+        sync.request_sent(2, 11, second);
+
+        sync.response(&2, headers(second)).unwrap();
+        assert!(drain_ready(&mut sync).is_empty());
+
+        sync.response(&1, headers(first)).unwrap();
+        let ready = drain_ready(&mut sync);
+        assert_eq!(ready.len() as u64, first.count + second.count);
+        assert_eq!(ready.last().unwrap().number, second.end());
+    }
+
+    #[test]
+    fn test_rejected_batch_is_requested_again() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(100);
+        let range = sync.next_range(100, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, range);
+        sync.response(&1, headers(range)).unwrap();
+        let (peer, ready) = sync.next_ready().unwrap();
+        assert_eq!((peer, ready.len()), (10, 100));
+        assert_eq!(sync.reject(range), None);
+        assert!(!sync.is_complete());
+        assert_eq!(sync.next_range(100, MAX_HEADERS_PER_REQUEST), Some(range));
+    }
+
+    #[test]
+    fn test_rejected_batch_is_requested_again_with_parent() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(150);
+        let first = sync.next_range(150, 100).unwrap();
+        let second = sync.next_range(150, 100).unwrap();
+        sync.request_sent(1, 10, first);
+        sync.request_sent(2, 11, second);
+        sync.response(&1, headers(first)).unwrap();
+        sync.response(&2, headers(second)).unwrap();
+        assert_eq!(drain_ready(&mut sync).len(), 150);
+        assert_eq!(sync.reject(second), Some(10));
+        assert_eq!(sync.next_range(150, MAX_HEADERS_PER_REQUEST), Some(first));
+        assert_eq!(sync.next_range(150, MAX_HEADERS_PER_REQUEST), Some(second));
+    }
+
+    #[test]
+    fn test_partial_and_failed_ranges_are_requeued() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(150);
+        let range = sync.next_range(150, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, range);
+        sync.response(&1, headers(HeaderRange::new(1, 100))).unwrap();
+        let rest = sync.next_range(150, MAX_HEADERS_PER_REQUEST).unwrap();
+        assert_eq!(rest, HeaderRange::new(101, 50));
+
+        sync.request_sent(2, 11, rest);
+        assert!(!sync.reassign(&2, 12, 149));
+        assert!(sync.reassign(&2, 12, 150));
+        sync.failed(&2);
+        assert_eq!(sync.next_range(150, MAX_HEADERS_PER_REQUEST), Some(rest));
+        sync.request_sent(3, 12, rest);
+        sync.response(&3, headers(rest)).unwrap();
+        assert_eq!(drain_ready(&mut sync).len(), 150);
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_unexpected_headers_are_rejected() {
+        let mut sync = HeaderSync::new(1);
+        sync.set_target(10);
+        let range = sync.next_range(10, MAX_HEADERS_PER_REQUEST).unwrap();
+        sync.request_sent(1, 10, range);
+        assert!(sync.response(&1, headers(HeaderRange::new(2, 3))).is_err());
+        assert_eq!(sync.next_range(10, MAX_HEADERS_PER_REQUEST), Some(range));
+    }
+}
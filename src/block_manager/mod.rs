@@ -2,6 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod block_manager;
-mod rlp_en_de;
+mod broadcast;
+mod data_sync;
+mod header_sync;
+pub(crate) mod rlp_en_de;
+pub mod verification;
 
 pub use block_manager::BlockManager;
\ No newline at end of file
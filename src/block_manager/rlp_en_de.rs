@@ -5,7 +5,8 @@ use primitive_types::{H160, H256, U256};
 use rlp::{RlpStream, Rlp, DecoderError};
 use crate::common_types::{
     BlockHeader, BlockId, BlockNumber, GetBlockHeaders,
-    BlockBody, BlockTransaction, NewBlock, NewBlockHash
+    BlockBody, BlockTransaction, NewBlock, NewBlockHash,
+    LogEntry, Receipt, ReceiptOutcome
 };
 
 pub fn encode_new_block_hashes(request: &[NewBlockHash]) -> Vec<u8> {
@@ -72,23 +73,7 @@ pub fn decode_get_block_headers(data: &[u8]) -> Result<GetBlockHeaders, DecoderE
 }
 
 fn encode_block_header(stream: &mut RlpStream, header: &BlockHeader) {
-    let mut header_stream = stream.begin_list(15);
-    header_stream
-        .append(&header.parent_hash)
-        .append(&header.ommers_hash)
-        .append(&header.beneficiary_address)
-        .append(&header.state_root)
-        .append(&header.transactions_root)
-        .append(&header.receipts_root)
-        .append(&header.logs_bloom)
-        .append(&header.difficulty)
-        .append(&header.number)
-        .append(&header.gas_limit)
-        .append(&header.gas_used)
-        .append(&header.timestamp)
-        .append(&header.extra_data)
-        .append(&header.mix_hash)
-        .append(&header.nonce);
+    stream.append(header);
 }
 
 pub fn encode_block_headers(headers: &[BlockHeader]) -> Vec<u8> {
@@ -115,10 +100,19 @@ fn decode_block_header(header: &Rlp) -> Result<BlockHeader, DecoderError> {
         timestamp: header.val_at(11)?,
         extra_data: header.val_at(12)?,
         mix_hash: H256::from_slice(header.at(13)?.data()?),
-        nonce: header.val_at(14)?,
+        nonce: decode_nonce(&header.at(14)?)?,
     })
 }
 
+// nonce is encoded as 8 bytes hash and can have leading zeros
+fn decode_nonce(nonce: &Rlp) -> Result<u64, DecoderError> {
+    let data = nonce.data()?;
+    if data.len() > 8 {
+        return Err(DecoderError::RlpIsTooBig);
+    }
+    Ok(data.iter().fold(0u64, |nonce, byte| (nonce << 8) | *byte as u64))
+}
+
 pub fn decode_block_headers(data: &[u8]) -> Result<Vec<BlockHeader>, DecoderError> {
     let encoded_headers = Rlp::new(data);
     let mut decoded_headers = vec![];
@@ -146,17 +140,7 @@ pub fn decode_get_block_bodies(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
 }
 
 fn encode_block_transaction(stream: &mut RlpStream, transaction: &BlockTransaction) {
-    let tx_stream = stream.begin_list(9);
-    tx_stream
-        .append(&transaction.nonce)
-        .append(&transaction.gas_price)
-        .append(&transaction.gas_limit)
-        .append(&transaction.to)
-        .append(&transaction.value)
-        .append(&transaction.input_data)
-        .append(&transaction.v)
-        .append(&transaction.r)
-        .append(&transaction.s);
+    stream.append(transaction);
 }
 
 fn encode_block_body(stream: &mut RlpStream, block_body: &BlockBody) {
@@ -179,12 +163,16 @@ pub fn encode_block_bodies(block_bodies: &[BlockBody]) -> Vec<u8> {
     stream.out()
 }
 
-fn decode_block_transaction(transaction: &Rlp) -> Result<BlockTransaction, DecoderError> {
+pub(crate) fn decode_block_transaction(transaction: &Rlp) -> Result<BlockTransaction, DecoderError> {
     Ok(BlockTransaction {
         nonce: U256::from_big_endian(transaction.at(0)?.data()?),
         gas_price: U256::from_big_endian(transaction.at(1)?.data()?),
         gas_limit: U256::from_big_endian(transaction.at(2)?.data()?),
-        to: H160::from_slice(transaction.at(3)?.data()?),
+        to: match transaction.at(3)?.data()? {
+            [] => None,
+            to if to.len() == 20 => Some(H160::from_slice(to)),
+            _ => return Err(DecoderError::RlpInvalidLength),
+        },
         value: U256::from_big_endian(transaction.at(4)?.data()?),
         input_data: transaction.val_at(5)?,
         v: transaction.val_at(6)?,
@@ -255,6 +243,65 @@ pub fn decode_new_block(data: &[u8]) -> Result<NewBlock, DecoderError> {
     Ok(NewBlock{ header, transactions, ommers, score })
 }
 
+pub fn encode_get_receipts(hashes: &[H256]) -> Vec<u8> {
+    encode_get_block_bodies(hashes)
+}
+
+pub fn decode_get_receipts(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
+    decode_get_block_bodies(data)
+}
+
+pub fn encode_receipts(receipts: &[Vec<Receipt>]) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(receipts.len());
+    for block_receipts in receipts {
+        stream.append_list(block_receipts);
+    }
+    stream.out()
+}
+
+fn decode_log_entry(log: &Rlp) -> Result<LogEntry, DecoderError> {
+    let mut topics = vec![];
+    for topic in log.at(1)?.iter() {
+        topics.push(topic.as_val()?);
+    }
+    Ok(LogEntry {
+        address: log.val_at(0)?,
+        topics,
+        data: log.val_at(2)?,
+    })
+}
+
+fn decode_receipt(receipt: &Rlp) -> Result<Receipt, DecoderError> {
+    let outcome = receipt.at(0)?;
+    let outcome = match outcome.size() {
+        32 => ReceiptOutcome::StateRoot(H256::from_slice(outcome.data()?)),
+        _ => ReceiptOutcome::StatusCode(outcome.as_val()?),
+    };
+    let mut logs = vec![];
+    for ref log in receipt.at(3)?.iter() {
+        logs.push(decode_log_entry(log)?);
+    }
+    Ok(Receipt {
+        outcome,
+        cumulative_gas_used: U256::from_big_endian(receipt.at(1)?.data()?),
+        logs_bloom: receipt.val_at(2)?,
+        logs,
+    })
+}
+
+pub fn decode_receipts(data: &[u8]) -> Result<Vec<Vec<Receipt>>, DecoderError> {
+    let encoded = Rlp::new(data);
+    let mut decoded = vec![];
+    for block_receipts in encoded.iter() {
+        let mut receipts = vec![];
+        for ref receipt in block_receipts.iter() {
+            receipts.push(decode_receipt(receipt)?);
+        }
+        decoded.push(receipts);
+    }
+    Ok(decoded)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -319,7 +366,7 @@ mod tests {
             nonce: U256::from(11),
             gas_price: U256::from(77000000),
             gas_limit: U256::from(21000),
-            to: H160::repeat_byte(3u8),
+            to: Some(H160::repeat_byte(3u8)),
             value: U256::from(0),
             input_data: vec![1, 2, 3],
             v: 6,
@@ -340,4 +387,111 @@ mod tests {
         let recovered = encode_block_bodies(&decoded);
         assert_eq!(encoded, recovered);
     }
-}
\ No newline at end of file
+
+    fn eip155_transaction() -> BlockTransaction {
+        BlockTransaction {
+            nonce: U256::from(1),
+            gas_price: U256::from(1),
+            gas_limit: U256::from(21000),
+            to: Some(H160::repeat_byte(3u8)),
+            value: U256::from(0),
+            input_data: vec![],
+            // EIP-155 signature on Sepolia
+            v: 11155111 * 2 + 35,
+            r: U256::from(7),
+            s: U256::from(8),
+        }
+    }
+
+    #[test]
+    fn test_transaction_with_eip155_v_roundtrip() {
+        let tx = eip155_transaction();
+        let encoded = rlp::encode(&tx);
+        assert_eq!(decode_block_transaction(&Rlp::new(&encoded)).unwrap(), tx);
+    }
+
+    #[test]
+    fn test_transaction_with_short_address_is_rejected() {
+        let tx = eip155_transaction();
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&tx.nonce).append(&tx.gas_price).append(&tx.gas_limit);
+        stream.append(&vec![3u8; 19]);
+        stream.append(&tx.value).append(&tx.input_data).append(&tx.v).append(&tx.r).append(&tx.s);
+        assert_eq!(
+            decode_block_transaction(&Rlp::new(&stream.out())),
+            Err(DecoderError::RlpInvalidLength)
+        );
+    }
+
+    struct KeccakHasher;
+
+    impl hash_db::Hasher for KeccakHasher {
+        type Out = H256;
+        type StdHasher = hash256_std_hasher::Hash256StdHasher;
+        const LENGTH: usize = 32;
+
+        fn hash(data: &[u8]) -> H256 {
+            crate::common_types::keccak(data)
+        }
+    }
+
+    #[test]
+    fn test_block_transactions_root() {
+        let encoded = std::fs::read("src/block_manager/test_data/block_11_927_383").unwrap();
+        let body = decode_block_bodies(&encoded).unwrap().remove(0);
+        assert!(body.transactions.len() > 16);
+        let expected = triehash::ordered_trie_root::<KeccakHasher, _>(body.transactions.iter().map(rlp::encode));
+        assert_eq!(body.transactions_root(), expected);
+    }
+
+    #[test]
+    fn test_receipts_roundtrip() {
+        let log = LogEntry {
+            address: H160::repeat_byte(5),
+            topics: vec![H256::repeat_byte(1), H256::repeat_byte(2)],
+            data: vec![1, 2, 3],
+        };
+        let receipts = vec![
+            vec![
+                Receipt {
+                    outcome: ReceiptOutcome::StatusCode(1),
+                    cumulative_gas_used: U256::from(21000),
+                    logs_bloom: vec![0; 256],
+                    logs: vec![log],
+                },
+                Receipt {
+                    outcome: ReceiptOutcome::StatusCode(0),
+                    cumulative_gas_used: U256::from(42000),
+                    logs_bloom: vec![0; 256],
+                    logs: vec![],
+                },
+            ],
+            vec![],
+            vec![Receipt {
+                outcome: ReceiptOutcome::StateRoot(H256::repeat_byte(7)),
+                cumulative_gas_used: U256::from(1),
+                logs_bloom: vec![0; 256],
+                logs: vec![],
+            }],
+        ];
+        let encoded = encode_receipts(&receipts);
+        assert_eq!(decode_receipts(&encoded).unwrap(), receipts);
+    }
+
+    #[test]
+    fn test_malformed_receipt_logs() {
+        let receipt = |address: &[u8], topic: &[u8]| {
+            let mut log = RlpStream::new_list(3);
+            log.append(&address).begin_list(1).append(&topic);
+            log.append(&vec![1u8]);
+            let mut stream = RlpStream::new_list(1);
+            stream.begin_list(1).begin_list(4).append(&1u8).append(&21000u64).append(&vec![0u8; 256]);
+            stream.begin_list(1).append_raw(&log.out(), 1);
+            stream.out()
+        };
+        assert!(decode_receipts(&receipt(&[5; 20], &[1; 32])).is_ok());
+        assert!(decode_receipts(&receipt(&[5; 19], &[1; 32])).is_err());
+        assert!(decode_receipts(&receipt(&[5; 21], &[1; 32])).is_err());
+        assert!(decode_receipts(&receipt(&[5; 20], &[1; 31])).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    common_types::BlockHeader,
+    devp2p_adapter::PeerPenal,
+    scheduler::peer_organizer::ErrorAct,
+};
+
+/// Max size of extra data field in header.
+pub const MAX_EXTRA_DATA_SIZE: usize = 32;
+/// Gas limit can change by less then `parent.gas_limit / GAS_LIMIT_BOUND_DIVISOR` between blocks.
+pub const GAS_LIMIT_BOUND_DIVISOR: u64 = 1024;
+pub const MIN_GAS_LIMIT: u64 = 5000;
+
+fn invalid(header: &BlockHeader, reason: &str) -> Result<(), ErrorAct> {
+    ErrorAct::new(
+        PeerPenal::InvalidData,
+        format!("Invalid header #{}: {}", header.number, reason),
+    )
+}
+
+/// Checks that can be done on header without knowing its parent.
+pub fn verify_header_fields(header: &BlockHeader) -> Result<(), ErrorAct> {
+    if header.gas_used > header.gas_limit {
+        invalid(header, "gas used is above gas limit")?
+    }
+    if header.gas_limit < MIN_GAS_LIMIT {
+        invalid(header, "gas limit is below minimum")?
+    }
+    if header.extra_data.len() > MAX_EXTRA_DATA_SIZE {
+        invalid(header, "extra data is too big")?
+    }
+    Ok(())
+}
+
+/// Checks header against its parent.
+pub fn verify_header(parent: &BlockHeader, header: &BlockHeader) -> Result<(), ErrorAct> {
+    verify_header_fields(header)?;
+    if header.number != parent.number + 1 {
+        invalid(header, "number is not consecutive to parent")?
+    }
+    if header.parent_hash != parent.hash() {
+        invalid(header, "parent hash does not match")?
+    }
+    if header.timestamp <= parent.timestamp {
+        invalid(header, "timestamp is not after parent")?
+    }
+    let bound = parent.gas_limit / GAS_LIMIT_BOUND_DIVISOR;
+    if header.gas_limit.abs_diff(parent.gas_limit) >= bound {
+        invalid(header, "gas limit changed too much")?
+    }
+    Ok(())
+}
+
+/// Checks consecutive headers. If `parent` is known first header is checked against it.
+pub fn verify_header_chain(parent: Option<&BlockHeader>, headers: &[BlockHeader]) -> Result<(), ErrorAct> {
+    let mut parent = parent;
+    for header in headers {
+        match parent {
+            Some(parent) => verify_header(parent, header)?,
+            None => verify_header_fields(header)?,
+        }
+        parent = Some(header);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, empty_body};
+    use primitive_types::{H160, H256};
+    use std::str::FromStr;
+
+    fn genesis() -> BlockHeader {
+        BlockHeader {
+            parent_hash: H256::zero(),
+            ommers_hash: H256::from_str("1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347").unwrap(),
+            beneficiary_address: H160::zero(),
+            state_root: H256::from_str("d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544").unwrap(),
+            transactions_root: H256::from_str("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").unwrap(),
+            receipts_root: H256::from_str("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").unwrap(),
+            logs_bloom: vec![0; 256],
+            difficulty: 0x4_0000_0000,
+            number: 0,
+            gas_limit: 5000,
+            gas_used: 0,
+            timestamp: 0,
+            extra_data: H256::from_str("11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa").unwrap().as_bytes().to_vec(),
+            mix_hash: H256::zero(),
+            nonce: 0x42,
+        }
+    }
+
+    fn child(parent: &BlockHeader) -> BlockHeader {
+        test_fixtures::child(parent, &empty_body())
+    }
+
+    #[test]
+    fn test_mainnet_genesis_hash() {
+        let expected = H256::from_str("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3").unwrap();
+        assert_eq!(genesis().hash(), expected);
+    }
+
+    #[test]
+    fn test_valid_chain() {
+        let first = child(&genesis());
+        let second = child(&first);
+        assert!(verify_header_chain(Some(&genesis()), &[first, second]).is_ok());
+    }
+
+    type Corruption = Box<dyn Fn(&mut BlockHeader)>;
+
+    #[test]
+    fn test_invalid_headers() {
+        let parent = genesis();
+        let cases: Vec<Corruption> = vec![
+            Box::new(|header| header.parent_hash = H256::repeat_byte(1)),
+            Box::new(|header| header.number += 1),
+            Box::new(|header| header.timestamp = 0),
+            Box::new(|header| header.gas_used = header.gas_limit + 1),
+            Box::new(|header| header.gas_limit += 5),
+            Box::new(|header| header.extra_data = vec![0; MAX_EXTRA_DATA_SIZE + 1]),
+        ];
+        for corrupt in cases {
+            let mut header = child(&parent);
+            corrupt(&mut header);
+            let act = verify_header(&parent, &header).unwrap_err();
+            assert_eq!(act.penal(), PeerPenal::InvalidData, "{:?}", header);
+        }
+    }
+}
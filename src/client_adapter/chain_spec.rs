@@ -0,0 +1,149 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common_types::BlockNumber;
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+/// Network parameters that peers are checked against in handshake.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainSpec {
+    pub name: String,
+    pub network_id: u64,
+    pub genesis_hash: H256,
+    /// Block numbers of hard forks, used for EIP-2124 fork id.
+    #[serde(default)]
+    pub forks: Vec<BlockNumber>,
+    /// Enode urls of nodes that we use to enter the network.
+    #[serde(default)]
+    pub bootnodes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ChainSpecError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ChainSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainSpecError::Io(err) => write!(f, "Chain spec read error: {}", err),
+            ChainSpecError::Json(err) => write!(f, "Chain spec json error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ChainSpecError {}
+
+impl From<io::Error> for ChainSpecError {
+    fn from(err: io::Error) -> Self {
+        ChainSpecError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ChainSpecError {
+    fn from(err: serde_json::Error) -> Self {
+        ChainSpecError::Json(err)
+    }
+}
+
+fn preset(
+    name: &str,
+    network_id: u64,
+    genesis_hash: &str,
+    forks: &[BlockNumber],
+    bootnodes: &[&str],
+) -> ChainSpec {
+    ChainSpec {
+        name: name.into(),
+        network_id,
+        genesis_hash: H256::from_str(genesis_hash).unwrap(),
+        forks: forks.to_vec(),
+        bootnodes: bootnodes.iter().map(|node| node.to_string()).collect(),
+    }
+}
+
+impl ChainSpec {
+    pub fn mainnet() -> ChainSpec {
+        preset(
+            "mainnet",
+            1,
+            "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
+            &[
+                1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000,
+                9_069_000, 9_200_000, 12_244_000, 12_965_000, 13_773_000, 15_050_000,
+            ],
+            &[
+                "enode://d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666@18.138.108.67:30303",
+                "enode://22a8232c3abc76a16ae9d6c3b164f98775fe226f0917b0ca871128a74a8e9630b458460865bab457221f1d448dd9791d24c4e5d88786180ac185df813a68d4de@3.209.45.79:30303",
+                "enode://2b252ab6a1d0f971d9722cb839a42cb81db019ba44c08754628ab4a823487071b5695317c8ccd085219c3a03af063495b2f1da8d18218da2d6a82981b45e6ffc@65.108.70.101:30303",
+                "enode://4aeb4ab6c14b23e2c4cfdce879c04b0748a20d8e9b59e25ded2a08143e265c6c25936e74cbc8e641e3312ca288673d91f2f93f8e277de3cfa444ecdaaf982052@157.90.35.166:30303",
+            ],
+        )
+    }
+
+    /// Preset by its name, as used in `name` field. Other networks are loaded from file.
+    pub fn by_name(name: &str) -> Option<ChainSpec> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            _ => None,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<ChainSpec, ChainSpecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn load(path: &Path) -> Result<ChainSpec, ChainSpecError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_forkid::{ForkFilter, ForkHash, ForkId};
+
+    fn genesis_fork_id(spec: &ChainSpec) -> ForkId {
+        ForkFilter::new(0, spec.genesis_hash, spec.forks.iter().copied()).current()
+    }
+
+    #[test]
+    fn test_preset_fork_ids() {
+        assert_eq!(
+            genesis_fork_id(&ChainSpec::mainnet()),
+            ForkId { hash: ForkHash(0xfc64ec04), next: 1_150_000 }
+        );
+        assert_eq!(ChainSpec::by_name("mainnet"), Some(ChainSpec::mainnet()));
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let spec = preset("dev", 1337, &"11".repeat(32), &[10, 20], &["enode://ab@127.0.0.1:30303"]);
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(ChainSpec::from_json(&json).unwrap(), spec);
+    }
+
+    #[test]
+    fn test_json_without_optional_fields() {
+        let json = r#"{
+            "name": "dev",
+            "networkId": 1337,
+            "genesisHash": "0x25a5cc106eea7138acab33231d7160d69cb777ee0c2c553fcddf5138993e6dd9"
+        }"#;
+        let spec = ChainSpec::from_json(json).unwrap();
+        assert_eq!(spec.network_id, 1337);
+        assert!(spec.forks.is_empty());
+        assert!(spec.bootnodes.is_empty());
+        assert!(ChainSpec::from_json("{}").is_err());
+    }
+}
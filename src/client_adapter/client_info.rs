@@ -1,14 +1,10 @@
 use crate::common_types::*;
-use ethereum_forkid::{ForkHash, ForkId};
+use crate::scheduler::scheduler::SchedulerEvent;
 use primitive_types::{H256, U256};
-use std::str::FromStr;
 
 pub struct ClientStatus {
     pub total_difficulty: U256,
     pub highest_block: (BlockNumber, H256),
-    pub genesis_block_hash: H256,
-    pub network_id: u64,
-    pub fork: ForkId,
 }
 
 //2020-12-30 01:38:20 UTC protocol:64,network:1,diff:19749264164891984230159,
@@ -24,27 +20,11 @@ Task pushed: InsertPeer(HandshakeInfo { peer_id: 22, eth_protocol_version: 64,
      snapshot: None })*/
 
 pub trait Client: Send + Sync {
-    //pub fn get
-    fn status(&self) -> ClientStatus {
-        //TODO dummy values
-        ClientStatus {
-            total_difficulty: U256::from_str("321371050299138").unwrap(),
-            highest_block: (
-                9792,
-                H256::from_str("e5e55fc298c68782ecb71b95f6202362be01b9c7706d9732e2083a82939bb849")
-                    .unwrap(),
-            ),
-            genesis_block_hash: H256::from_str(
-                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
-            )
-            .unwrap(),
-            network_id: 1,
-            fork: ForkId {
-                hash: ForkHash(4234472452),
-                next: 1150000,
-            },
-        }
-    }
+    /// Our best block and its total difficulty. Network id, genesis and fork id come from `ChainSpec`.
+    fn status(&self) -> ClientStatus;
+
+    /// Called from scheduler thread when sync progresses. Scheduler is not locked while this is called.
+    fn on_event(&self, _event: SchedulerEvent) {}
 }
 
 pub struct SnapshotManifestStatus {
@@ -65,4 +45,25 @@ pub trait Snapshot: Send + Sync {
             hash: H256::zero(),
         }
     }
+
+    /// Manifest of our snapshot that is served to peers.
+    fn manifest(&self) -> Option<ManifestData> {
+        None
+    }
+
+    /// Compressed chunk of our snapshot that is served to peers.
+    fn chunk(&self, _hash: &H256) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Called when manifest of snapshot that we are going to restore is downloaded.
+    fn begin_restore(&self, _manifest: &ManifestData) {}
+
+    /// Called when restore that was begun is given up, chunks fed so far can be dropped.
+    fn abort_restore(&self) {}
+
+    /// Called with every downloaded chunk, chunk hash is already checked against manifest.
+    fn feed_state_chunk(&self, _hash: &H256, _chunk: &[u8]) {}
+
+    fn feed_block_chunk(&self, _hash: &H256, _chunk: &[u8]) {}
 }
@@ -3,16 +3,24 @@
 
 use std::collections::HashMap;
 use super::blockchain::Blockchain;
-use crate::common_types::{BlockNumber, BlockHeader, BlockBody, GetBlockHeaders, BlockId};
+use crate::common_types::{BlockNumber, BlockHeader, BlockBody, GetBlockHeaders, BlockId, Receipt};
 use primitive_types::H256;
 
 pub struct HeadersInMemory {
     headers: HashMap<BlockNumber, BlockHeader>,
+    hashes: HashMap<H256, BlockNumber>,
+    bodies: HashMap<H256, BlockBody>,
+    receipts: HashMap<H256, Vec<Receipt>>,
 }
 
 impl HeadersInMemory {
     pub fn new() -> Self {
-        HeadersInMemory { headers: HashMap::new() }
+        HeadersInMemory {
+            headers: HashMap::new(),
+            hashes: HashMap::new(),
+            bodies: HashMap::new(),
+            receipts: HashMap::new(),
+        }
     }
 }
 
@@ -25,10 +33,20 @@ impl Blockchain for HeadersInMemory {
         clone_option(self.headers.get(&number))
     }
 
+    fn block_number(&self, hash: &H256) -> Option<BlockNumber> {
+        self.hashes.get(hash).copied()
+    }
+
     fn block_headers(&self, request: GetBlockHeaders) -> Vec<BlockHeader> {
         let mut headers = vec![];
+        if request.max_headers == 0 {
+            return headers;
+        }
         let mut block_number = match request.block_id {
-            BlockId::Hash(hash) => { return headers; }, // TODO
+            BlockId::Hash(hash) => match self.block_number(&hash) {
+                Some(number) => number,
+                None => return headers,
+            },
             BlockId::Number(number) => number
         };
         while let Some(header) = self.block_header(block_number) {
@@ -36,21 +54,25 @@ impl Blockchain for HeadersInMemory {
             if headers.len() as u64 >= request.max_headers {
                 break;
             }
-            if request.reverse {
-                block_number -= request.skip + 1
+            let next = if request.reverse {
+                block_number.checked_sub(request.skip + 1)
             } else {
-                block_number += request.skip + 1
-            }
+                block_number.checked_add(request.skip + 1)
+            };
+            block_number = match next {
+                Some(number) => number,
+                None => break,
+            };
         }
         headers
     }
 
     fn block_body(&self, hash: &H256) -> Option<BlockBody> {
-        None
+        self.bodies.get(hash).cloned()
     }
 
-    fn block_receipt(&self) {
-        unimplemented!()
+    fn block_receipts(&self, hash: &H256) -> Option<Vec<Receipt>> {
+        self.receipts.get(hash).cloned()
     }
 
     fn best_block_header(&self) -> Option<&BlockNumber> {
@@ -58,14 +80,70 @@ impl Blockchain for HeadersInMemory {
     }
 
     fn import_block_header(&mut self, header: &BlockHeader) {
-        self.headers.insert(header.number, header.clone());
+        let hash = header.hash();
+        // header with same number is replaced, drop index of the old one
+        if let Some(old) = self.headers.insert(header.number, header.clone()) {
+            self.hashes.remove(&old.hash());
+        }
+        self.hashes.insert(hash, header.number);
     }
 
-    fn import_block_body(&mut self, body: &BlockBody) {
-        info!("Received block body, ignoring.");
+    fn import_block_body(&mut self, hash: &H256, body: &BlockBody) {
+        if self.hashes.contains_key(hash) {
+            self.bodies.insert(*hash, body.clone());
+        } else {
+            info!("Received block body for unknown header {}, ignoring.", hash);
+        }
+    }
+
+    fn import_block_receipts(&mut self, hash: &H256, receipts: &[Receipt]) {
+        if self.hashes.contains_key(hash) {
+            self.receipts.insert(*hash, receipts.to_vec());
+        } else {
+            info!("Received receipts for unknown header {}, ignoring.", hash);
+        }
     }
 
     fn import_old_block(&self) {
         unimplemented!()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    fn chain(len: u64) -> (HeadersInMemory, Vec<BlockHeader>) {
+        let mut chain = HeadersInMemory::new();
+        let headers = test_fixtures::chain(len);
+        for header in headers.iter() {
+            chain.import_block_header(header);
+        }
+        (chain, headers)
+    }
+
+    #[test]
+    fn test_block_headers_by_hash() {
+        let (chain, headers) = chain(10);
+        let request = GetBlockHeaders::new(BlockId::Hash(headers[8].hash()), 5, 1, true);
+        let numbers: Vec<BlockNumber> = chain.block_headers(request).iter().map(|h| h.number).collect();
+        assert_eq!(numbers, vec![8, 6, 4, 2, 0]);
+
+        let request = GetBlockHeaders::new(BlockId::Hash(H256::repeat_byte(1)), 5, 0, false);
+        assert!(chain.block_headers(request).is_empty());
+        let request = GetBlockHeaders::new(BlockId::Number(0), 0, 0, false);
+        assert!(chain.block_headers(request).is_empty());
+    }
+
+    #[test]
+    fn test_block_body_by_hash() {
+        let (mut chain, headers) = chain(2);
+        let body = BlockBody { transactions: vec![], ommers: vec![headers[0].clone()] };
+        chain.import_block_body(&headers[1].hash(), &body);
+        chain.import_block_body(&H256::repeat_byte(1), &body);
+        assert_eq!(chain.block_body(&headers[1].hash()), Some(body));
+        assert_eq!(chain.block_body(&H256::repeat_byte(1)), None);
+        assert_eq!(chain.block_number(&headers[0].hash()), Some(0));
+    }
+}
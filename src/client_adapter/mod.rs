@@ -1,7 +1,11 @@
+pub mod chain_spec;
 pub mod client_info;
 pub mod blockchain;
 pub mod headers_in_memory;
+pub mod transaction_pool;
 
 
 pub use blockchain::Blockchain;
-pub use client_info::Client;
\ No newline at end of file
+pub use chain_spec::ChainSpec;
+pub use client_info::Client;
+pub use transaction_pool::TransactionPool;
\ No newline at end of file
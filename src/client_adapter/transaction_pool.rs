@@ -0,0 +1,42 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use crate::common_types::BlockTransaction;
+use primitive_types::H256;
+
+/// Pool of pending transactions that are served to peers and filled from the network.
+pub trait TransactionPool {
+    fn contains(&self, hash: &H256) -> bool;
+    fn transaction(&self, hash: &H256) -> Option<BlockTransaction>;
+    fn import_transactions(&mut self, transactions: Vec<BlockTransaction>);
+}
+
+#[derive(Default)]
+pub struct TransactionsInMemory {
+    transactions: HashMap<H256, BlockTransaction>,
+}
+
+impl TransactionsInMemory {
+    pub fn new() -> Self {
+        TransactionsInMemory {
+            transactions: HashMap::new(),
+        }
+    }
+}
+
+impl TransactionPool for TransactionsInMemory {
+    fn contains(&self, hash: &H256) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    fn transaction(&self, hash: &H256) -> Option<BlockTransaction> {
+        self.transactions.get(hash).cloned()
+    }
+
+    fn import_transactions(&mut self, transactions: Vec<BlockTransaction>) {
+        for transaction in transactions {
+            self.transactions.insert(transaction.hash(), transaction);
+        }
+    }
+}
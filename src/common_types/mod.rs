@@ -1,6 +1,10 @@
 // will be extracted to separate library. Maybe in util :)
 
+pub mod trie;
+
 use primitive_types::{H160, H256, U256};
+use rlp::{Encodable, RlpStream};
+use tiny_keccak::{Hasher, Keccak};
 
 pub type BlockNumber = u64;
 
@@ -55,25 +59,145 @@ pub struct BlockHeader {
     pub nonce: u64,
 }
 
+impl BlockHeader {
+    /// Keccak of canonical RLP encoded header.
+    pub fn hash(&self) -> H256 {
+        keccak(&rlp::encode(self))
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn rlp_append(&self, stream: &mut RlpStream) {
+        stream
+            .begin_list(15)
+            .append(&self.parent_hash)
+            .append(&self.ommers_hash)
+            .append(&self.beneficiary_address)
+            .append(&self.state_root)
+            .append(&self.transactions_root)
+            .append(&self.receipts_root)
+            .append(&self.logs_bloom)
+            .append(&self.difficulty)
+            .append(&self.number)
+            .append(&self.gas_limit)
+            .append(&self.gas_used)
+            .append(&self.timestamp)
+            .append(&self.extra_data)
+            .append(&self.mix_hash)
+            // nonce is fixed size hash and it keeps its leading zeros
+            .append(&&self.nonce.to_be_bytes()[..]);
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct BlockTransaction {
     pub nonce: U256,
     pub gas_price: U256,
     pub gas_limit: U256,
-    pub to: H160,
+    pub to: Option<H160>, // None for contract creation
     pub value: U256,
     pub input_data: Vec<u8>,
-    pub v: u8,
+    // with EIP-155 it holds chain id, which does not fit in byte
+    pub v: u64,
     pub r: U256,
     pub s: U256,
 }
 
+impl Encodable for BlockTransaction {
+    fn rlp_append(&self, stream: &mut RlpStream) {
+        stream
+            .begin_list(9)
+            .append(&self.nonce)
+            .append(&self.gas_price)
+            .append(&self.gas_limit);
+        match self.to {
+            Some(ref to) => stream.append(to),
+            None => stream.append_empty_data(),
+        };
+        stream
+            .append(&self.value)
+            .append(&self.input_data)
+            .append(&self.v)
+            .append(&self.r)
+            .append(&self.s);
+    }
+}
+
+impl BlockTransaction {
+    pub fn hash(&self) -> H256 {
+        keccak(&rlp::encode(self))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct BlockBody {
     pub transactions: Vec<BlockTransaction>,
     pub ommers: Vec<BlockHeader>,
 }
 
+impl BlockBody {
+    /// Root of transactions trie, needs to match header's `transactions_root`.
+    pub fn transactions_root(&self) -> H256 {
+        trie::ordered_trie_root(self.transactions.iter().map(rlp::encode))
+    }
+
+    /// Hash of ommers list, needs to match header's `ommers_hash`.
+    pub fn ommers_hash(&self) -> H256 {
+        keccak(&rlp::encode_list(&self.ommers))
+    }
+
+    /// Checks if body belongs to header.
+    pub fn matches(&self, header: &BlockHeader) -> bool {
+        self.transactions_root() == header.transactions_root && self.ommers_hash() == header.ommers_hash
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub address: H160,
+    pub topics: Vec<H256>,
+    pub data: Vec<u8>,
+}
+
+impl Encodable for LogEntry {
+    fn rlp_append(&self, stream: &mut RlpStream) {
+        stream
+            .begin_list(3)
+            .append(&self.address)
+            .append_list(&self.topics)
+            .append(&self.data);
+    }
+}
+
+/// Receipts before Byzantium contain intermediate state root, after it status code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReceiptOutcome {
+    StateRoot(H256),
+    StatusCode(u8),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Receipt {
+    pub outcome: ReceiptOutcome,
+    pub cumulative_gas_used: U256,
+    pub logs_bloom: Vec<u8>,
+    pub logs: Vec<LogEntry>,
+}
+
+impl Encodable for Receipt {
+    fn rlp_append(&self, stream: &mut RlpStream) {
+        stream.begin_list(4);
+        match self.outcome {
+            ReceiptOutcome::StateRoot(ref root) => stream.append(root),
+            ReceiptOutcome::StatusCode(status) => stream.append(&status),
+        };
+        stream
+            .append(&self.cumulative_gas_used)
+            .append(&self.logs_bloom)
+            .append_list(&self.logs);
+    }
+}
+
 #[derive(Debug)]
 pub struct NewBlock {
     pub header: BlockHeader,
@@ -81,3 +205,35 @@ pub struct NewBlock {
     pub ommers: Vec<BlockHeader>,
     pub score: U256,
 }
+
+/// Description of snapshot, lists hashes of all state and block chunks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestData {
+    pub version: u64,
+    pub state_hashes: Vec<H256>,
+    pub block_hashes: Vec<H256>,
+    pub state_root: H256,
+    pub block_number: BlockNumber,
+    pub block_hash: H256,
+}
+
+impl Encodable for ManifestData {
+    fn rlp_append(&self, stream: &mut RlpStream) {
+        stream
+            .begin_list(6)
+            .append(&self.version)
+            .append_list(&self.state_hashes)
+            .append_list(&self.block_hashes)
+            .append(&self.state_root)
+            .append(&self.block_number)
+            .append(&self.block_hash);
+    }
+}
+
+pub fn keccak(data: &[u8]) -> H256 {
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    H256::from(output)
+}
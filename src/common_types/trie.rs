@@ -0,0 +1,143 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Root calculation of Merkle Patricia trie. Only what is needed to verify
+// transactions and receipts roots from block header is implemented.
+
+use super::keccak;
+use primitive_types::H256;
+use rlp::RlpStream;
+
+/// Root of trie where key is RLP encoded index of item in `items`.
+pub fn ordered_trie_root<I, V>(items: I) -> H256
+where
+    I: IntoIterator<Item = V>,
+    V: AsRef<[u8]>,
+{
+    let mut input: Vec<(Vec<u8>, V)> = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| (to_nibbles(&rlp::encode(&index)), item))
+        .collect();
+    input.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut stream = RlpStream::new();
+    encode_node(&input, 0, &mut stream);
+    keccak(&stream.out())
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|byte| vec![byte >> 4, byte & 0x0f]).collect()
+}
+
+// compact encoding of nibbles with flag that tells if it is leaf and if length is odd.
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 0x20 } else { 0x00 };
+    let mut encoded = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if nibbles.len() % 2 == 1 {
+        encoded.push(flag | 0x10 | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        encoded.push(flag);
+        nibbles
+    };
+    for pair in rest.chunks(2) {
+        encoded.push(pair[0] << 4 | pair[1]);
+    }
+    encoded
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+// `input` is sorted and all keys share first `prefix` nibbles.
+fn encode_node<V: AsRef<[u8]>>(input: &[(Vec<u8>, V)], prefix: usize, stream: &mut RlpStream) {
+    let (key, value) = match input.first() {
+        Some(first) => first,
+        None => {
+            stream.append_empty_data();
+            return;
+        }
+    };
+    if input.len() == 1 {
+        stream.begin_list(2);
+        stream.append(&hex_prefix(&key[prefix..], true));
+        stream.append(&value.as_ref());
+        return;
+    }
+
+    let shared = input
+        .iter()
+        .skip(1)
+        .map(|(other, _)| shared_prefix_len(key, other))
+        .min()
+        .unwrap_or(key.len());
+    if shared > prefix {
+        stream.begin_list(2);
+        stream.append(&hex_prefix(&key[prefix..shared], false));
+        encode_child(input, shared, stream);
+        return;
+    }
+
+    stream.begin_list(17);
+    // key that ends here is stored as value of branch
+    let (mut begin, branch_value) = if key.len() == prefix {
+        (1, Some(value))
+    } else {
+        (0, None)
+    };
+    for nibble in 0..16u8 {
+        let len = input[begin..]
+            .iter()
+            .take_while(|(key, _)| key[prefix] == nibble)
+            .count();
+        if len == 0 {
+            stream.append_empty_data();
+        } else {
+            encode_child(&input[begin..begin + len], prefix + 1, stream);
+        }
+        begin += len;
+    }
+    match branch_value {
+        Some(value) => stream.append(&value.as_ref()),
+        None => stream.append_empty_data(),
+    };
+}
+
+// child nodes shorter than hash are inlined, others are referenced by hash.
+fn encode_child<V: AsRef<[u8]>>(input: &[(Vec<u8>, V)], prefix: usize, stream: &mut RlpStream) {
+    let mut child = RlpStream::new();
+    encode_node(input, prefix, &mut child);
+    let child = child.out();
+    if child.len() < 32 {
+        stream.append_raw(&child, 1);
+    } else {
+        stream.append(&keccak(&child));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_empty_root() {
+        let expected = H256::from_str("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").unwrap();
+        assert_eq!(ordered_trie_root(Vec::<Vec<u8>>::new()), expected);
+    }
+
+    #[test]
+    fn test_known_root() {
+        let expected = H256::from_str("e766d5d51b89dc39d981b41bda63248d7abce4f0225eefd023792a540bcffee3").unwrap();
+        assert_eq!(ordered_trie_root(["doe", "reindeer"]), expected);
+    }
+
+    #[test]
+    fn test_hex_prefix() {
+        assert_eq!(hex_prefix(&[1, 2, 3, 4, 5], false), vec![0x11, 0x23, 0x45]);
+        assert_eq!(hex_prefix(&[0, 1, 2, 3, 4, 5], false), vec![0x00, 0x01, 0x23, 0x45]);
+        assert_eq!(hex_prefix(&[0, 15, 1, 12, 11, 8], true), vec![0x20, 0x0f, 0x1c, 0xb8]);
+        assert_eq!(hex_prefix(&[15, 1, 12, 11, 8], true), vec![0x3f, 0x1c, 0xb8]);
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
+use primitive_types::H512;
 use std::sync::Arc;
 
 use crate::scheduler::{
@@ -15,11 +16,28 @@ pub trait Devp2pAdapter: Send + Sync {
     //unregister handler?
     fn send_mesage(&self, protocol: ProtocolId, peer: &PeerId, mesage_id: u8, data: &[u8]);
     fn penalize_peer(&self, peer: &PeerId, penal: PeerPenal);
+    /// Public key of connected peer. Reputation and bans are persisted only for peers with known identity.
+    fn node_id(&self, _peer: &PeerId) -> Option<NodeId> {
+        None
+    }
 }
-#[derive(Debug, Copy, Clone)]
+
+/// Identity of node that stays the same across sessions, unlike `PeerId`.
+pub type NodeId = H512;
+/// Penalties lower peer reputation and peer is kicked when it drops too low.
+/// Adapter is only asked to `Kick`, `Ban` or drop `IncompatibleChain` peer.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PeerPenal {
+    /// Peer did not answer request in time.
+    Timeout,
+    /// Peer answered with nothing that we could use.
+    UselessResponse,
+    /// Peer sent data that is malformed or does not match request.
+    InvalidData,
     Kick,
     Ban,
+    /// Peer follows other network, genesis or fork. It is dropped right away, but it is not an offence.
+    IncompatibleChain,
 }
 
 pub trait Devp2pInbound: Send + Sync {
@@ -29,4 +47,6 @@ pub trait Devp2pInbound: Send + Sync {
     fn connected(&self, peer: &PeerId, capability: &PeerCapability);
     /// Called when a previously connected peer disconnects.
     fn disconnected(&self, peer: &PeerId);
+    /// Called when adapter drops message because peer broke transport rules, e.g. sent too big message.
+    fn report_peer(&self, _peer: &PeerId, _penal: PeerPenal, _reason: &str) {}
 }
@@ -0,0 +1,339 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! In-process devp2p that lets schedulers be driven by tests, without any network.
+
+use super::adapter::{Devp2pAdapter, Devp2pInbound, NodeId, PeerPenal};
+use crate::{
+    client_adapter::{
+        client_info::{Client, Snapshot},
+        Blockchain, ChainSpec,
+    },
+    scheduler::{
+        clock::Clock,
+        peer_organizer::{PeerCapability, PeerId, PeerOrganizer},
+        peer_store::NoPeerStore,
+        protocol::{EthProtocolVersion, ProtocolId},
+        scheduler::LoopTrigger,
+        Scheduler,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Message that scheduler sent to peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentMessage {
+    pub protocol: ProtocolId,
+    pub peer: PeerId,
+    pub message_id: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MockState {
+    handler: Option<Arc<dyn Devp2pInbound>>,
+    running: bool,
+    sent: Vec<SentMessage>,
+    penalties: Vec<(PeerId, PeerPenal)>,
+    // peers that were kicked or banned and need to be disconnected
+    dropped: Vec<PeerId>,
+    node_ids: HashMap<PeerId, NodeId>,
+}
+
+/// Adapter that records everything scheduler sends. Events are injected by test with `connect`,
+/// `receive` and `disconnect`. Adapter is cheap to clone and clones share state, so test can
+/// keep one clone after other is given to scheduler.
+#[derive(Clone, Default)]
+pub struct MockAdapter {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capability of peer that speaks eth/66 and nothing else.
+    pub fn eth66_capability() -> PeerCapability {
+        let mut capability = PeerCapability::new();
+        capability.insert(
+            ProtocolId::Eth,
+            [EthProtocolVersion::VERSION_66.to_number()].iter().copied().collect::<HashSet<u8>>(),
+        );
+        capability
+    }
+
+    // handler is called without state being locked, as it calls back into adapter
+    fn handler(&self) -> Arc<dyn Devp2pInbound> {
+        self.state.lock().unwrap().handler.clone().expect("Handler is registered")
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().running
+    }
+
+    pub fn set_node_id(&self, peer: PeerId, node_id: NodeId) {
+        self.state.lock().unwrap().node_ids.insert(peer, node_id);
+    }
+
+    pub fn connect(&self, peer: &PeerId, capability: &PeerCapability) {
+        self.handler().connected(peer, capability);
+    }
+
+    pub fn receive(&self, peer: &PeerId, protocol: ProtocolId, message_id: u8, data: &[u8]) {
+        self.handler().receive_message(peer, protocol, message_id, data);
+    }
+
+    pub fn disconnect(&self, peer: &PeerId) {
+        self.handler().disconnected(peer);
+    }
+
+    /// Messages sent since last call.
+    pub fn take_sent(&self) -> Vec<SentMessage> {
+        std::mem::take(&mut self.state.lock().unwrap().sent)
+    }
+
+    /// All penalties that scheduler asked for.
+    pub fn penalties(&self) -> Vec<(PeerId, PeerPenal)> {
+        self.state.lock().unwrap().penalties.clone()
+    }
+
+    /// Peers that were kicked or banned since last call.
+    pub fn take_dropped(&self) -> Vec<PeerId> {
+        std::mem::take(&mut self.state.lock().unwrap().dropped)
+    }
+}
+
+impl Devp2pAdapter for MockAdapter {
+    fn start(&self) {
+        self.state.lock().unwrap().running = true;
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().running = false;
+    }
+
+    fn register_handler(&self, handle: Arc<dyn Devp2pInbound>) {
+        self.state.lock().unwrap().handler = Some(handle);
+    }
+
+    fn send_mesage(&self, protocol: ProtocolId, peer: &PeerId, mesage_id: u8, data: &[u8]) {
+        self.state.lock().unwrap().sent.push(SentMessage {
+            protocol,
+            peer: *peer,
+            message_id: mesage_id,
+            data: data.to_vec(),
+        });
+    }
+
+    fn penalize_peer(&self, peer: &PeerId, penal: PeerPenal) {
+        let mut state = self.state.lock().unwrap();
+        state.penalties.push((*peer, penal));
+        if matches!(penal, PeerPenal::Kick | PeerPenal::Ban | PeerPenal::IncompatibleChain) {
+            state.dropped.push(*peer);
+        }
+    }
+
+    fn node_id(&self, peer: &PeerId) -> Option<NodeId> {
+        self.state.lock().unwrap().node_ids.get(peer).copied()
+    }
+}
+
+/// Index of scheduler in `MockNetwork`.
+pub type NodeIndex = usize;
+
+type MessageFilter = Box<dyn Fn(&SentMessage) -> bool>;
+
+/// Several schedulers connected with mock adapters. Messages are delivered in `step`, so that
+/// no scheduler is called while other one holds its locks. All schedulers share one clock,
+/// that only moves forward with `advance`.
+#[derive(Default)]
+pub struct MockNetwork {
+    nodes: Vec<(Arc<Scheduler>, MockAdapter)>,
+    // (node, peer id of other node at it) -> (other node, our peer id at other node)
+    links: HashMap<(NodeIndex, PeerId), (NodeIndex, PeerId)>,
+    next_peer_id: PeerId,
+    clock: Clock,
+    // messages of node that are kept back until `release`
+    hold: Vec<(NodeIndex, MessageFilter)>,
+    held: Vec<(NodeIndex, SentMessage)>,
+    // only first message of node that matches is lost
+    drop_next: Vec<(NodeIndex, MessageFilter)>,
+    lost: Vec<(NodeIndex, SentMessage)>,
+}
+
+impl MockNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds started scheduler that syncs into `chain`. It has no thread of its own, its main loop
+    /// runs only in `step`, so that tests are deterministic.
+    pub fn add_node(
+        &mut self,
+        client: Arc<dyn Client>,
+        snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+        chain: Arc<Mutex<dyn Blockchain + Send + Sync>>,
+    ) -> NodeIndex {
+        let adapter = MockAdapter::new();
+        let devp2p: Arc<Box<dyn Devp2pAdapter>> = Arc::new(Box::new(adapter.clone()));
+        let peer_organizer = PeerOrganizer::with_clock(devp2p.clone(), Box::new(NoPeerStore), self.clock.clone());
+        let scheduler = Scheduler::build(peer_organizer, client, snapshot, chain_spec, chain, LoopTrigger::Manual);
+        devp2p.register_handler(scheduler.clone());
+        scheduler.start();
+        self.nodes.push((scheduler, adapter));
+        self.nodes.len() - 1
+    }
+
+    pub fn scheduler(&self, node: NodeIndex) -> &Arc<Scheduler> {
+        &self.nodes[node].0
+    }
+
+    pub fn adapter(&self, node: NodeIndex) -> &MockAdapter {
+        &self.nodes[node].1
+    }
+
+    /// Peer id under which `other` node is known to `node`.
+    pub fn peer_id(&self, node: NodeIndex, other: NodeIndex) -> Option<PeerId> {
+        self.links
+            .iter()
+            .find(|((from, _), (to, _))| *from == node && *to == other)
+            .map(|((_, peer), _)| *peer)
+    }
+
+    /// Connects two nodes over eth/66, both of them start handshake.
+    pub fn connect(&mut self, a: NodeIndex, b: NodeIndex) {
+        let peer_at_a = self.new_peer_id();
+        let peer_at_b = self.new_peer_id();
+        self.links.insert((a, peer_at_a), (b, peer_at_b));
+        self.links.insert((b, peer_at_b), (a, peer_at_a));
+        let capability = MockAdapter::eth66_capability();
+        self.adapter(a).connect(&peer_at_a, &capability);
+        self.adapter(b).connect(&peer_at_b, &capability);
+    }
+
+    pub fn disconnect(&mut self, a: NodeIndex, b: NodeIndex) {
+        if let Some(peer) = self.peer_id(a, b) {
+            self.drop_link(a, peer);
+        }
+    }
+
+    fn new_peer_id(&mut self) -> PeerId {
+        self.next_peer_id += 1;
+        self.next_peer_id
+    }
+
+    fn drop_link(&mut self, node: NodeIndex, peer: PeerId) {
+        if let Some((other, other_peer)) = self.links.remove(&(node, peer)) {
+            self.links.remove(&(other, other_peer));
+            self.adapter(node).disconnect(&peer);
+            self.adapter(other).disconnect(&other_peer);
+        }
+    }
+
+    /// Moves time of all schedulers forward. Tasks that time out are handled in next `step`.
+    pub fn advance(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// Messages sent by `node` that match `filter` are not delivered until `release`.
+    pub fn hold<F: Fn(&SentMessage) -> bool + 'static>(&mut self, node: NodeIndex, filter: F) {
+        self.hold.push((node, Box::new(filter)));
+    }
+
+    /// Messages kept back by `hold`, with node that sent them.
+    pub fn held(&self) -> &[(NodeIndex, SentMessage)] {
+        &self.held
+    }
+
+    /// Stops holding messages, held ones are delivered in next `deliver`.
+    pub fn release(&mut self) {
+        self.hold.clear();
+    }
+
+    /// Next message sent by `node` that matches `filter` is lost.
+    pub fn drop_next<F: Fn(&SentMessage) -> bool + 'static>(&mut self, node: NodeIndex, filter: F) {
+        self.drop_next.push((node, Box::new(filter)));
+    }
+
+    /// Messages lost because of `drop_next`, with node that sent them.
+    pub fn lost(&self) -> &[(NodeIndex, SentMessage)] {
+        &self.lost
+    }
+
+    // true if message is lost or held instead of being delivered
+    fn intercept(&mut self, node: NodeIndex, message: &SentMessage) -> bool {
+        let matches = |(from, filter): &(NodeIndex, MessageFilter)| *from == node && filter(message);
+        if let Some(index) = self.drop_next.iter().position(matches) {
+            let _ = self.drop_next.remove(index);
+            self.lost.push((node, message.clone()));
+            return true;
+        }
+        if self.hold.iter().any(matches) {
+            self.held.push((node, message.clone()));
+            return true;
+        }
+        false
+    }
+
+    /// Delivers messages that are sent so far and drops kicked peers. Returns number of delivered messages.
+    pub fn deliver(&mut self) -> usize {
+        let mut delivered = 0;
+        let mut released = Vec::new();
+        if self.hold.is_empty() {
+            released = std::mem::take(&mut self.held);
+        }
+        for node in 0..self.nodes.len() {
+            let sent = self.adapter(node).take_sent();
+            let released = released.iter().filter(|(from, _)| *from == node).map(|(_, message)| message.clone());
+            for message in released.chain(sent) {
+                if self.intercept(node, &message) {
+                    continue;
+                }
+                // peer could be disconnected while message was in flight
+                if let Some((other, peer)) = self.links.get(&(node, message.peer)).copied() {
+                    self.adapter(other)
+                        .receive(&peer, message.protocol, message.message_id, &message.data);
+                    delivered += 1;
+                }
+            }
+            for peer in self.adapter(node).take_dropped() {
+                self.drop_link(node, peer);
+            }
+        }
+        delivered
+    }
+
+    /// Delivers all messages and runs main loop of every scheduler once.
+    pub fn step(&mut self) -> usize {
+        let delivered = self.deliver();
+        for (scheduler, _) in self.nodes.iter() {
+            scheduler.main_loop();
+        }
+        delivered
+    }
+
+    /// Steps until `done` returns true, at most `max_steps` times. Returns if `done` was reached.
+    pub fn run_until<F: FnMut(&MockNetwork) -> bool>(&mut self, max_steps: usize, mut done: F) -> bool {
+        for _ in 0..max_steps {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+}
+
+impl Drop for MockNetwork {
+    fn drop(&mut self) {
+        for (scheduler, _) in self.nodes.iter() {
+            scheduler.stop();
+        }
+    }
+}
@@ -2,5 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod adapter;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(feature = "rlpx")]
+pub mod rlpx;
 
 pub use adapter::*;
\ No newline at end of file
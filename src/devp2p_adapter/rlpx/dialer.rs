@@ -0,0 +1,85 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dialer keeps us connected: static nodes are redialed when they drop, and nodes from discovery
+//! are dialed while we have less than `min_peers` peers. Above that we only accept incoming
+//! connections, up to `max_peers`.
+
+use super::{discovery::Discovery, p2p::Enode, Inner};
+use crate::devp2p_adapter::NodeId;
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often peer count is checked.
+pub const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+/// Node is not dialed again sooner than this.
+pub const DIAL_BACKOFF: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(super) struct Dialer {
+    inner: Arc<Inner>,
+    discovery: Option<Discovery>,
+    // nodes that are dialed when discovery is off
+    bootnodes: Vec<Enode>,
+    last_dial: HashMap<NodeId, Instant>,
+}
+
+impl Dialer {
+    pub fn new(inner: Arc<Inner>, discovery: Option<Discovery>) -> Self {
+        let bootnodes = if discovery.is_none() { inner.config.bootnodes.clone() } else { Vec::new() };
+        Dialer { inner, discovery, bootnodes, last_dial: HashMap::new() }
+    }
+
+    pub fn run(mut self) {
+        let mut last_check = Instant::now() - DIAL_INTERVAL;
+        while self.inner.running.load(Ordering::Relaxed) {
+            if last_check.elapsed() >= DIAL_INTERVAL {
+                last_check = Instant::now();
+                self.dial();
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn can_dial(&self, enode: &Enode) -> bool {
+        enode.id != self.inner.node_id
+            && !self.inner.is_connected(&enode.id)
+            && !self.inner.dialing.lock().unwrap().contains(&enode.id)
+            && self.last_dial.get(&enode.id).is_none_or(|last| last.elapsed() >= DIAL_BACKOFF)
+    }
+
+    fn connect(&mut self, enode: &Enode) {
+        self.last_dial.insert(enode.id, Instant::now());
+        self.inner.connect(enode);
+    }
+
+    fn dial(&mut self) {
+        self.last_dial.retain(|_, last| last.elapsed() < DIAL_BACKOFF);
+        let mut static_nodes = self.inner.config.static_nodes.clone();
+        static_nodes.retain(|enode| self.can_dial(enode));
+        for enode in static_nodes.iter() {
+            self.connect(enode);
+        }
+
+        let peers = self.inner.sessions.lock().unwrap().len() + self.inner.dialing.lock().unwrap().len();
+        let missing = self.inner.config.min_peers.min(self.inner.config.max_peers).saturating_sub(peers);
+        if missing == 0 {
+            return;
+        }
+        let mut candidates: Vec<Enode> = match self.discovery {
+            Some(ref discovery) => discovery.nodes().iter().map(|node| node.enode()).collect(),
+            None => self.bootnodes.clone(),
+        };
+        candidates.retain(|enode| self.can_dial(enode));
+        candidates.shuffle(&mut rand::thread_rng());
+        for enode in candidates.iter().take(missing) {
+            trace!("Dialing {}", enode);
+            self.connect(enode);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Ethereum node records (EIP-778) with "v4" identity scheme.
+
+use super::super::{ecies, RlpxError};
+use crate::{common_types::keccak, devp2p_adapter::NodeId};
+use k256::{
+    ecdsa::{signature::hazmat::PrehashVerifier, Signature, SigningKey, VerifyingKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey, SecretKey,
+};
+use rlp::{Rlp, RlpStream};
+use std::{
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Records bigger than this are invalid.
+pub const MAX_RECORD_SIZE: usize = 300;
+
+/// Signed node record. Only keys that we use are decoded, record is kept as it was signed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Enr {
+    pub seq: u64,
+    pub id: NodeId,
+    pub ip: Option<IpAddr>,
+    pub tcp: Option<u16>,
+    pub udp: Option<u16>,
+    raw: Vec<u8>,
+}
+
+fn invalid(reason: &'static str) -> RlpxError {
+    RlpxError::Crypto(reason)
+}
+
+impl Enr {
+    /// Creates and signs record of our node.
+    pub fn sign(secret: &SecretKey, seq: u64, ip: IpAddr, tcp: u16, udp: u16) -> Result<Enr, RlpxError> {
+        let public = secret.public_key().to_encoded_point(true);
+        // keys have to be sorted
+        let mut pairs: Vec<(&[u8], Vec<u8>)> = vec![(b"id", rlp::encode(&b"v4".to_vec()).to_vec())];
+        match ip {
+            IpAddr::V4(ip) => pairs.push((b"ip", rlp::encode(&ip.octets().to_vec()).to_vec())),
+            IpAddr::V6(ip) => pairs.push((b"ip6", rlp::encode(&ip.octets().to_vec()).to_vec())),
+        }
+        pairs.push((b"secp256k1", rlp::encode(&public.as_bytes().to_vec()).to_vec()));
+        pairs.push((b"tcp", rlp::encode(&tcp).to_vec()));
+        pairs.push((b"udp", rlp::encode(&udp).to_vec()));
+
+        let content = |signature: Option<&[u8]>| {
+            let mut stream = RlpStream::new_list(1 + pairs.len() * 2 + signature.is_some() as usize);
+            if let Some(signature) = signature {
+                stream.append(&signature);
+            }
+            stream.append(&seq);
+            for (key, value) in pairs.iter() {
+                stream.append(key);
+                stream.append_raw(value, 1);
+            }
+            stream.out().to_vec()
+        };
+        let (signature, _) = SigningKey::from(secret)
+            .sign_prehash_recoverable(keccak(&content(None)).as_bytes())
+            .map_err(|_| invalid("could not sign node record"))?;
+        Enr::decode(&content(Some(&signature.to_bytes())))
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Enr, RlpxError> {
+        if raw.len() > MAX_RECORD_SIZE {
+            return Err(invalid("node record is too big"));
+        }
+        let rlp = Rlp::new(raw);
+        let items = rlp.item_count()?;
+        if items < 2 || items % 2 != 0 {
+            return Err(invalid("invalid node record"));
+        }
+        let signature: Vec<u8> = rlp.val_at(0)?;
+        let seq: u64 = rlp.val_at(1)?;
+        let (mut scheme, mut public, mut ip, mut tcp, mut udp) = (None, None, None, None, None);
+        for i in (2..items).step_by(2) {
+            let key: Vec<u8> = rlp.val_at(i)?;
+            match &key[..] {
+                b"id" => scheme = Some(rlp.val_at::<Vec<u8>>(i + 1)?),
+                b"secp256k1" => public = Some(rlp.val_at::<Vec<u8>>(i + 1)?),
+                b"ip" => {
+                    let octets: Vec<u8> = rlp.val_at(i + 1)?;
+                    let octets: [u8; 4] = octets.try_into().map_err(|_| invalid("invalid ip in node record"))?;
+                    ip = Some(IpAddr::V4(Ipv4Addr::from(octets)));
+                }
+                b"ip6" if ip.is_none() => {
+                    let octets: Vec<u8> = rlp.val_at(i + 1)?;
+                    let octets: [u8; 16] = octets.try_into().map_err(|_| invalid("invalid ip6 in node record"))?;
+                    ip = Some(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                b"tcp" => tcp = Some(rlp.val_at(i + 1)?),
+                b"udp" => udp = Some(rlp.val_at(i + 1)?),
+                _ => (),
+            }
+        }
+        if scheme.as_deref() != Some(b"v4") {
+            return Err(invalid("unknown node record identity scheme"));
+        }
+        let public = public.ok_or_else(|| invalid("node record without public key"))?;
+        let key = VerifyingKey::from_sec1_bytes(&public).map_err(|_| invalid("invalid node record key"))?;
+
+        let mut content = RlpStream::new_list(items - 1);
+        for item in rlp.iter().skip(1) {
+            content.append_raw(item.as_raw(), 1);
+        }
+        let signature = Signature::from_slice(&signature).map_err(|_| invalid("invalid node record signature"))?;
+        key.verify_prehash(keccak(&content.out()).as_bytes(), &signature)
+            .map_err(|_| invalid("node record signature mismatch"))?;
+
+        Ok(Enr { seq, id: ecies::node_id(&PublicKey::from(key)), ip, tcp, udp, raw: raw.to_vec() })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitive_types::H256;
+    use std::str::FromStr;
+
+    // example record from EIP-778
+    const EXAMPLE: &str = "f884b8407098ad865b00a582051940cb9cf36836572411a47278783077011599ed5cd16b76f2635f4e234738f30813a89eb9137e3e3df5266e3a1f11df72ecf1145ccb9c01826964827634826970847f00000189736563703235366b31a103ca634cae0d49acb401d8a4c6b6fe8c55b70d115bf400769cc1400f3258cd31388375647082765f";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_eip778_example() {
+        let secret = SecretKey::from_slice(
+            H256::from_str("b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291").unwrap().as_bytes(),
+        )
+        .unwrap();
+        let record = Enr::decode(&from_hex(EXAMPLE)).unwrap();
+        assert_eq!(record.seq, 1);
+        assert_eq!(record.id, ecies::node_id(&secret.public_key()));
+        assert_eq!(record.ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(record.udp, Some(30303));
+        assert_eq!(record.tcp, None);
+    }
+
+    #[test]
+    fn test_signed_record_roundtrip() {
+        let secret = ecies::random_secret();
+        let record = Enr::sign(&secret, 7, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 30303, 30301).unwrap();
+        let decoded = Enr::decode(record.as_bytes()).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.id, ecies::node_id(&secret.public_key()));
+        assert_eq!((decoded.tcp, decoded.udp), (Some(30303), Some(30301)));
+
+        let mut tampered = record.as_bytes().to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(Enr::decode(&tampered).is_err());
+    }
+}
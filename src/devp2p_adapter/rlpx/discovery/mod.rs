@@ -0,0 +1,552 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Node discovery v4. Nodes are found with Kademlia lookups over UDP and kept in routing table,
+//! from which dialer takes nodes to connect to.
+
+pub mod enr;
+pub mod node_store;
+mod packet;
+mod table;
+
+use self::{
+    enr::Enr,
+    node_store::{NodeRecord, NodeStore},
+    packet::{Endpoint, Packet, MAX_NEIGHBOURS, MAX_PACKET_SIZE},
+    table::{Table, BUCKET_SIZE},
+};
+use super::{ecies, p2p::Enode};
+use crate::devp2p_adapter::NodeId;
+use k256::SecretKey;
+use primitive_types::H256;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Time in which node needs to answer our packet.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often we look for new nodes.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Lookup that did not finish in this time is abandoned.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Node that answered our ping is trusted with neighbours for this long.
+const BOND_EXPIRATION: Duration = Duration::from_secs(12 * 60 * 60);
+/// Packets that we send are valid for this long.
+const PACKET_EXPIRATION: Duration = Duration::from_secs(20);
+/// Number of nodes asked in parallel in lookup.
+const ALPHA: usize = 3;
+/// Pings that can wait for pong at once, nodes are not pinged while it is reached.
+const MAX_PENDING_PINGS: usize = 256;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn expiration() -> u64 {
+    unix_now() + PACKET_EXPIRATION.as_secs()
+}
+
+struct Lookup {
+    target: NodeId,
+    asked: HashSet<NodeId>,
+    // asked nodes that did not answer yet
+    pending: HashMap<NodeId, Instant>,
+    started: Instant,
+}
+
+struct State {
+    table: Table,
+    // nodes that we pinged, by hash of ping
+    pings: HashMap<H256, (NodeRecord, Instant)>,
+    // nodes that answered our ping
+    verified: HashMap<NodeId, Instant>,
+    // nodes whose ping we answered, they answer our requests
+    verified_by: HashMap<NodeId, Instant>,
+    enr_requests: HashMap<H256, (NodeId, Instant)>,
+    lookup: Option<Lookup>,
+    last_refresh: Option<Instant>,
+}
+
+struct Inner {
+    secret: SecretKey,
+    node_id: NodeId,
+    bootnodes: Vec<NodeRecord>,
+    store: Mutex<Box<dyn NodeStore>>,
+    state: Mutex<State>,
+    running: AtomicBool,
+    // our endpoint and record, known after start
+    local: Mutex<Option<(Endpoint, Enr)>>,
+    // table is saved periodically, so that nodes are not lost if process is killed
+    last_save: Mutex<Instant>,
+}
+
+/// Discovery v4 service. Clones share the same service.
+#[derive(Clone)]
+pub struct Discovery {
+    inner: Arc<Inner>,
+}
+
+fn is_fresh(bonds: &HashMap<NodeId, Instant>, node_id: &NodeId) -> bool {
+    bonds.get(node_id).is_some_and(|time| time.elapsed() < BOND_EXPIRATION)
+}
+
+fn is_lan(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        // unique local fc00::/7 and link local fe80::/10
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+fn is_special(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() || ip.octets()[0] == 0,
+        IpAddr::V6(ip) => ip.is_multicast(),
+    }
+}
+
+/// Tells if node `address` that `sender` sent in neighbours can be pinged. Loopback and LAN addresses
+/// are accepted only from nodes in the same network, like geth does.
+fn is_relayable(sender: &IpAddr, address: &SocketAddr) -> bool {
+    let ip = address.ip();
+    if ip.is_unspecified() || is_special(&ip) || address.port() <= 1024 {
+        return false;
+    }
+    if ip.is_loopback() && !sender.is_loopback() {
+        return false;
+    }
+    !is_lan(&ip) || is_lan(sender) || sender.is_loopback()
+}
+
+impl Discovery {
+    pub fn new(secret: SecretKey, bootnodes: &[Enode], store: Box<dyn NodeStore>) -> Self {
+        let node_id = ecies::node_id(&secret.public_key());
+        Discovery {
+            inner: Arc::new(Inner {
+                secret,
+                node_id,
+                bootnodes: bootnodes.iter().map(NodeRecord::from_enode).collect(),
+                store: Mutex::new(store),
+                state: Mutex::new(State {
+                    table: Table::new(&node_id),
+                    pings: HashMap::new(),
+                    verified: HashMap::new(),
+                    verified_by: HashMap::new(),
+                    enr_requests: HashMap::new(),
+                    lookup: None,
+                    last_refresh: None,
+                }),
+                running: AtomicBool::new(false),
+                local: Mutex::new(None),
+                last_save: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Listens on `listen` UDP address and announces `tcp_port` as port of our RLPx transport.
+    pub fn start(&self, listen: SocketAddr, tcp_port: u16) -> io::Result<SocketAddr> {
+        let socket = UdpSocket::bind(listen)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let address = socket.local_addr()?;
+        let endpoint = Endpoint { ip: address.ip(), udp_port: address.port(), tcp_port };
+        // record sequence only needs to grow between restarts
+        let record = Enr::sign(&self.inner.secret, unix_now(), address.ip(), tcp_port, address.port())
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        *self.inner.local.lock().unwrap() = Some((endpoint, record));
+        self.inner.running.store(true, Ordering::Relaxed);
+        let inner = self.inner.clone();
+        thread::Builder::new().name("discovery".into()).spawn(move || inner.run(socket))?;
+        info!("Discovery listening on {}", address);
+        Ok(address)
+    }
+
+    pub fn stop(&self) {
+        self.inner.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.inner.node_id
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let local = self.inner.local.lock().unwrap();
+        local.as_ref().map(|(endpoint, _)| SocketAddr::new(endpoint.ip, endpoint.udp_port))
+    }
+
+    /// Our signed node record, known after start.
+    pub fn record(&self) -> Option<Enr> {
+        self.inner.local.lock().unwrap().as_ref().map(|(_, record)| record.clone())
+    }
+
+    /// Nodes in routing table, all of them answered our ping.
+    pub fn nodes(&self) -> Vec<NodeRecord> {
+        self.inner.state.lock().unwrap().table.iter().cloned().collect()
+    }
+}
+
+impl Inner {
+    fn run(&self, socket: UdpSocket) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let mut last_tick = Instant::now() - POLL_INTERVAL;
+        while self.running.load(Ordering::Relaxed) {
+            match socket.recv_from(&mut buf) {
+                Ok((size, from)) => self.handle_packet(&socket, &buf[..size], from),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+                Err(err) => debug!("Discovery receive failed: {}", err),
+            }
+            if last_tick.elapsed() >= POLL_INTERVAL {
+                last_tick = Instant::now();
+                self.tick(&socket);
+            }
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        let mut records = self.state.lock().unwrap().table.iter().cloned().collect::<Vec<_>>();
+        records.sort_by_key(|record| record.node_id);
+        self.store.lock().unwrap().save(&records);
+    }
+
+    fn local(&self) -> (Endpoint, Enr) {
+        self.local.lock().unwrap().clone().expect("Discovery is started")
+    }
+
+    fn send(&self, socket: &UdpSocket, to: SocketAddr, packet: Packet) -> Option<H256> {
+        let (data, hash) = match packet.encode(&self.secret) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                warn!("Could not encode discovery packet: {}", err);
+                return None;
+            }
+        };
+        if let Err(err) = socket.send_to(&data, to) {
+            debug!("Discovery send to {} failed: {}", to, err);
+        }
+        Some(hash)
+    }
+
+    fn ping(&self, socket: &UdpSocket, state: &mut State, node: NodeRecord) {
+        if node.node_id == self.node_id || state.pings.len() >= MAX_PENDING_PINGS {
+            return;
+        }
+        if state.pings.values().any(|(pinged, _)| pinged.node_id == node.node_id) {
+            return;
+        }
+        let (from, record) = self.local();
+        let to = Endpoint { ip: node.ip, udp_port: node.udp_port, tcp_port: node.tcp_port };
+        let packet = Packet::Ping { from, to, expiration: expiration(), enr_seq: Some(record.seq) };
+        if let Some(hash) = self.send(socket, node.udp_address(), packet) {
+            state.pings.insert(hash, (node, Instant::now()));
+        }
+    }
+
+    /// Asks for node record if node has newer one than we know. Node answers only after it has seen our pong.
+    fn request_enr(&self, socket: &UdpSocket, state: &mut State, node_id: NodeId, to: SocketAddr, seq: Option<u64>) {
+        let known = match state.table.get(&node_id) {
+            Some(node) => node.enr_seq,
+            None => return,
+        };
+        let requested = state.enr_requests.values().any(|(requested, _)| *requested == node_id);
+        if requested || seq.is_none_or(|seq| seq <= known) || !is_fresh(&state.verified_by, &node_id) {
+            return;
+        }
+        if let Some(hash) = self.send(socket, to, Packet::EnrRequest { expiration: expiration() }) {
+            state.enr_requests.insert(hash, (node_id, Instant::now()));
+        }
+    }
+
+    fn handle_packet(&self, socket: &UdpSocket, data: &[u8], from: SocketAddr) {
+        let (packet, node_id, hash) = match Packet::decode(data) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                trace!("Invalid discovery packet from {}: {}", from, err);
+                return;
+            }
+        };
+        if node_id == self.node_id || packet.expiration().is_some_and(|expiration| expiration < unix_now()) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        match packet {
+            Packet::Ping { from: endpoint, enr_seq, .. } => {
+                let to = Endpoint { ip: from.ip(), udp_port: from.port(), tcp_port: endpoint.tcp_port };
+                let (_, record) = self.local();
+                let pong = Packet::Pong { to, ping_hash: hash, expiration: expiration(), enr_seq: Some(record.seq) };
+                self.send(socket, from, pong);
+                state.verified_by.insert(node_id, Instant::now());
+                let node = NodeRecord {
+                    node_id,
+                    ip: from.ip(),
+                    udp_port: from.port(),
+                    tcp_port: endpoint.tcp_port,
+                    enr_seq: state.table.get(&node_id).map(|node| node.enr_seq).unwrap_or(0),
+                };
+                // node needs to prove its endpoint before it gets to table
+                if is_fresh(&state.verified, &node_id) {
+                    state.table.add(node);
+                    self.request_enr(socket, state, node_id, from, enr_seq);
+                } else {
+                    self.ping(socket, state, node);
+                }
+            }
+            Packet::Pong { ping_hash, enr_seq, .. } => {
+                let mut node = match state.pings.get(&ping_hash) {
+                    Some((node, _)) if node.node_id == node_id => node.clone(),
+                    _ => return,
+                };
+                state.pings.remove(&ping_hash);
+                state.verified.insert(node_id, Instant::now());
+                if let Some(known) = state.table.get(&node_id) {
+                    node.enr_seq = node.enr_seq.max(known.enr_seq);
+                }
+                // full bucket keeps its nodes while they answer
+                if let Some(oldest) = state.table.add(node) {
+                    self.ping(socket, state, oldest);
+                }
+                self.request_enr(socket, state, node_id, from, enr_seq);
+            }
+            Packet::FindNode { target, .. } => {
+                if !is_fresh(&state.verified, &node_id) {
+                    trace!("Find node from unverified node {:x}", node_id);
+                    return;
+                }
+                let closest = state.table.closest(&target, BUCKET_SIZE);
+                for nodes in closest.chunks(MAX_NEIGHBOURS) {
+                    let packet = Packet::Neighbours { nodes: nodes.to_vec(), expiration: expiration() };
+                    self.send(socket, from, packet);
+                }
+            }
+            Packet::Neighbours { nodes, .. } => {
+                match state.lookup.as_mut() {
+                    Some(lookup) if lookup.asked.contains(&node_id) => lookup.pending.remove(&node_id),
+                    _ => return,
+                };
+                for node in nodes {
+                    if !is_relayable(&from.ip(), &node.udp_address()) {
+                        trace!("Node {:x} sent unusable address {}", node_id, node.udp_address());
+                        continue;
+                    }
+                    if state.table.get(&node.node_id).is_none() {
+                        self.ping(socket, state, node);
+                    }
+                }
+            }
+            Packet::EnrRequest { .. } => {
+                if is_fresh(&state.verified, &node_id) {
+                    let (_, record) = self.local();
+                    self.send(socket, from, Packet::EnrResponse { request_hash: hash, record });
+                }
+            }
+            Packet::EnrResponse { request_hash, record } => {
+                match state.enr_requests.remove(&request_hash) {
+                    Some((requested, _)) if requested == node_id && record.id == node_id => (),
+                    _ => return,
+                }
+                if let Some(node) = state.table.get(&node_id) {
+                    let node = NodeRecord {
+                        node_id,
+                        ip: record.ip.unwrap_or(node.ip),
+                        udp_port: record.udp.unwrap_or(node.udp_port),
+                        tcp_port: record.tcp.unwrap_or(node.tcp_port),
+                        enr_seq: record.seq,
+                    };
+                    state.table.update(node);
+                }
+            }
+        }
+    }
+
+    fn tick(&self, socket: &UdpSocket) {
+        let save = {
+            let mut last_save = self.last_save.lock().unwrap();
+            let save = last_save.elapsed() >= REFRESH_INTERVAL;
+            if save {
+                *last_save = Instant::now();
+            }
+            save
+        };
+        if save {
+            self.save();
+        }
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        // nodes that did not answer ping are not worth keeping
+        let expired: Vec<H256> = state
+            .pings
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() > REQUEST_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            if let Some((node, _)) = state.pings.remove(&hash) {
+                trace!("Node {:x} did not answer ping", node.node_id);
+                state.table.remove(&node.node_id);
+            }
+        }
+        state.enr_requests.retain(|_, (_, sent)| sent.elapsed() <= REQUEST_TIMEOUT);
+        state.verified.retain(|_, time| time.elapsed() < BOND_EXPIRATION);
+        state.verified_by.retain(|_, time| time.elapsed() < BOND_EXPIRATION);
+
+        let refresh = state.last_refresh.is_none_or(|last| last.elapsed() >= REFRESH_INTERVAL);
+        if state.lookup.is_none() && refresh {
+            state.last_refresh = Some(Instant::now());
+            if state.table.is_empty() {
+                let seeds: Vec<NodeRecord> =
+                    self.bootnodes.iter().cloned().chain(self.store.lock().unwrap().load()).collect();
+                for node in seeds {
+                    self.ping(socket, state, node);
+                }
+            }
+            // first lookup fills buckets near us, later ones random parts of network
+            let target = if state.table.is_empty() { self.node_id } else { NodeId::from_slice(&ecies::random_bytes::<64>()) };
+            state.lookup =
+                Some(Lookup { target, asked: HashSet::new(), pending: HashMap::new(), started: Instant::now() });
+            return;
+        }
+        self.lookup_step(socket, state);
+    }
+
+    fn lookup_step(&self, socket: &UdpSocket, state: &mut State) {
+        let mut lookup = match state.lookup.take() {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        lookup.pending.retain(|_, sent| sent.elapsed() <= REQUEST_TIMEOUT);
+        let candidates: Vec<NodeRecord> = state
+            .table
+            .closest(&lookup.target, BUCKET_SIZE)
+            .into_iter()
+            .filter(|node| !lookup.asked.contains(&node.node_id))
+            .collect();
+        for node in candidates.iter() {
+            if lookup.pending.len() >= ALPHA {
+                break;
+            }
+            // node answers only if it has seen our pong
+            if is_fresh(&state.verified_by, &node.node_id) {
+                let packet = Packet::FindNode { target: lookup.target, expiration: expiration() };
+                self.send(socket, node.udp_address(), packet);
+                lookup.asked.insert(node.node_id);
+                lookup.pending.insert(node.node_id, Instant::now());
+            } else {
+                self.ping(socket, state, node.clone());
+            }
+        }
+        let done = lookup.pending.is_empty() && candidates.is_empty() && state.pings.is_empty();
+        if done || lookup.started.elapsed() > LOOKUP_TIMEOUT {
+            debug!("Lookup finished, {} nodes in table", state.table.len());
+        } else {
+            state.lookup = Some(lookup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_store::NoNodeStore;
+
+    fn start(bootnodes: &[Enode]) -> Discovery {
+        let discovery = Discovery::new(ecies::random_secret(), bootnodes, Box::new(NoNodeStore));
+        discovery.start("127.0.0.1:0".parse().unwrap(), 30303).unwrap();
+        discovery
+    }
+
+    fn enode(discovery: &Discovery) -> Enode {
+        Enode { id: discovery.node_id(), address: discovery.local_addr().unwrap() }
+    }
+
+    fn wait_for(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    fn knows(discovery: &Discovery, other: &Discovery) -> bool {
+        discovery.nodes().iter().any(|node| node.node_id == other.node_id())
+    }
+
+    #[test]
+    fn test_nodes_are_found_through_bootnode() {
+        let boot = start(&[]);
+        let first = start(&[enode(&boot)]);
+        assert!(wait_for(|| knows(&boot, &first)));
+        let second = start(&[enode(&boot)]);
+        assert!(wait_for(|| knows(&second, &first)));
+        let node = second.nodes().into_iter().find(|node| node.node_id == first.node_id()).unwrap();
+        assert_eq!(node.udp_address(), first.local_addr().unwrap());
+        // ENR is requested after pong, record has the same tcp port
+        assert!(wait_for(|| second.nodes().iter().all(|node| node.enr_seq > 0)));
+        assert_eq!(node.tcp_port, 30303);
+        for discovery in [boot, first, second] {
+            discovery.stop();
+        }
+    }
+
+    #[test]
+    fn test_unanswered_bootnode_is_not_kept() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bootnode = Enode { id: ecies::node_id(&ecies::random_secret().public_key()), address: silent.local_addr().unwrap() };
+        let discovery = start(&[bootnode]);
+        thread::sleep(REQUEST_TIMEOUT + Duration::from_millis(500));
+        assert!(discovery.nodes().is_empty());
+        assert!(discovery.inner.state.lock().unwrap().pings.is_empty());
+        discovery.stop();
+    }
+
+    #[test]
+    fn test_pending_pings_are_capped() {
+        let discovery = start(&[]);
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut state = discovery.inner.state.lock().unwrap();
+        // pings to the same endpoint in the same second would have the same hash
+        for port in 0..MAX_PENDING_PINGS as u16 + 10 {
+            let node = NodeRecord {
+                node_id: ecies::node_id(&ecies::random_secret().public_key()),
+                ip: silent.local_addr().unwrap().ip(),
+                udp_port: 40000 + port,
+                tcp_port: 40000 + port,
+                enr_seq: 0,
+            };
+            discovery.inner.ping(&silent, &mut state, node);
+        }
+        assert_eq!(state.pings.len(), MAX_PENDING_PINGS);
+        drop(state);
+        discovery.stop();
+    }
+
+    #[test]
+    fn test_relayed_addresses_are_checked() {
+        let public: IpAddr = "18.138.108.67".parse().unwrap();
+        let lan: IpAddr = "192.168.1.5".parse().unwrap();
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let address = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 30303);
+        assert!(is_relayable(&public, &address("3.3.3.3")));
+        assert!(!is_relayable(&public, &address("0.0.0.0")));
+        assert!(!is_relayable(&public, &address("127.0.0.1")));
+        assert!(!is_relayable(&public, &address("10.0.0.1")));
+        assert!(!is_relayable(&public, &address("fe80::1")));
+        assert!(!is_relayable(&public, &address("224.0.0.1")));
+        assert!(!is_relayable(&public, &SocketAddr::new(public, 80)));
+        assert!(is_relayable(&lan, &address("10.0.0.1")));
+        assert!(!is_relayable(&lan, &address("127.0.0.1")));
+        assert!(is_relayable(&loopback, &address("127.0.0.1")));
+        assert!(is_relayable(&loopback, &address("10.0.0.1")));
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::devp2p_adapter::{rlpx::p2p::Enode, NodeId};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+/// Node found by discovery, it is kept between restarts so that network can be entered without bootnodes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeRecord {
+    pub node_id: NodeId,
+    pub ip: IpAddr,
+    pub udp_port: u16,
+    pub tcp_port: u16,
+    /// Sequence number of last node record that we have seen.
+    #[serde(default)]
+    pub enr_seq: u64,
+}
+
+impl NodeRecord {
+    pub fn from_enode(enode: &Enode) -> Self {
+        NodeRecord {
+            node_id: enode.id,
+            ip: enode.address.ip(),
+            udp_port: enode.address.port(),
+            tcp_port: enode.address.port(),
+            enr_seq: 0,
+        }
+    }
+
+    pub fn udp_address(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.udp_port)
+    }
+
+    pub fn enode(&self) -> Enode {
+        Enode { id: self.node_id, address: SocketAddr::new(self.ip, self.tcp_port) }
+    }
+}
+
+/// Storage of discovered nodes. Errors are not propagated, store is only best effort.
+pub trait NodeStore: Send + Sync {
+    fn load(&self) -> Vec<NodeRecord>;
+    fn save(&mut self, records: &[NodeRecord]);
+}
+
+/// Store that keeps nothing, every start begins from bootnodes.
+#[derive(Debug, Default)]
+pub struct NoNodeStore;
+
+impl NodeStore for NoNodeStore {
+    fn load(&self) -> Vec<NodeRecord> {
+        Vec::new()
+    }
+
+    fn save(&mut self, _records: &[NodeRecord]) {}
+}
+
+/// Keeps records as json list in a file.
+#[derive(Debug)]
+pub struct FileNodeStore {
+    path: PathBuf,
+}
+
+impl FileNodeStore {
+    pub fn new(path: PathBuf) -> Self {
+        FileNodeStore { path }
+    }
+
+    fn read(&self) -> Result<Vec<NodeRecord>, io::Error> {
+        let json = fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    fn write(&self, records: &[NodeRecord]) -> Result<(), io::Error> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl NodeStore for FileNodeStore {
+    fn load(&self) -> Vec<NodeRecord> {
+        match self.read() {
+            Ok(records) => records,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                warn!("Could not load node store {}: {}", self.path.display(), err);
+                Vec::new()
+            }
+        }
+    }
+
+    fn save(&mut self, records: &[NodeRecord]) {
+        if let Err(err) = self.write(records) {
+            warn!("Could not save node store {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store_survives_restart() {
+        let path = std::env::temp_dir().join(format!("reth-scheduler-nodes-{}.json", std::process::id()));
+        let record = NodeRecord {
+            node_id: NodeId::from_low_u64_be(1),
+            ip: "10.0.0.1".parse().unwrap(),
+            udp_port: 30301,
+            tcp_port: 30303,
+            enr_seq: 3,
+        };
+        FileNodeStore::new(path.clone()).save(std::slice::from_ref(&record));
+        assert_eq!(FileNodeStore::new(path.clone()).load(), vec![record]);
+        fs::remove_file(&path).unwrap();
+    }
+}
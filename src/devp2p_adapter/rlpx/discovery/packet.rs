@@ -0,0 +1,296 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Discovery v4 packets: `hash || signature || packet-type || packet-data`.
+
+use super::{enr::Enr, node_store::NodeRecord};
+use crate::{
+    common_types::keccak,
+    devp2p_adapter::{
+        rlpx::{ecies, RlpxError},
+        NodeId,
+    },
+};
+use k256::{
+    ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey},
+    PublicKey, SecretKey,
+};
+use primitive_types::H256;
+use rlp::{DecoderError, Rlp, RlpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Packets bigger than this are dropped.
+pub const MAX_PACKET_SIZE: usize = 1280;
+const HASH_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 65;
+const HEADER_SIZE: usize = HASH_SIZE + SIGNATURE_SIZE;
+const DISCOVERY_VERSION: u8 = 4;
+/// Neighbours that fit in one packet.
+pub const MAX_NEIGHBOURS: usize = 12;
+
+const PING: u8 = 0x01;
+const PONG: u8 = 0x02;
+const FIND_NODE: u8 = 0x03;
+const NEIGHBOURS: u8 = 0x04;
+const ENR_REQUEST: u8 = 0x05;
+const ENR_RESPONSE: u8 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Endpoint {
+    pub ip: IpAddr,
+    pub udp_port: u16,
+    pub tcp_port: u16,
+}
+
+fn append_ip(stream: &mut RlpStream, ip: &IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => stream.append(&ip.octets().to_vec()),
+        IpAddr::V6(ip) => stream.append(&ip.octets().to_vec()),
+    };
+}
+
+fn decode_ip(rlp: &Rlp) -> Result<IpAddr, DecoderError> {
+    let octets: Vec<u8> = rlp.as_val()?;
+    match octets.len() {
+        4 => Ok(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))),
+        16 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&octets);
+            Ok(IpAddr::V6(Ipv6Addr::from(ip)))
+        }
+        _ => Err(DecoderError::Custom("Invalid ip address")),
+    }
+}
+
+impl Endpoint {
+    fn append(&self, stream: &mut RlpStream) {
+        stream.begin_list(3);
+        append_ip(stream, &self.ip);
+        stream.append(&self.udp_port);
+        stream.append(&self.tcp_port);
+    }
+
+    fn decode(rlp: &Rlp) -> Result<Endpoint, DecoderError> {
+        Ok(Endpoint { ip: decode_ip(&rlp.at(0)?)?, udp_port: rlp.val_at(1)?, tcp_port: rlp.val_at(2)? })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Ping { from: Endpoint, to: Endpoint, expiration: u64, enr_seq: Option<u64> },
+    Pong { to: Endpoint, ping_hash: H256, expiration: u64, enr_seq: Option<u64> },
+    FindNode { target: NodeId, expiration: u64 },
+    Neighbours { nodes: Vec<NodeRecord>, expiration: u64 },
+    EnrRequest { expiration: u64 },
+    EnrResponse { request_hash: H256, record: Enr },
+}
+
+impl Packet {
+    fn packet_type(&self) -> u8 {
+        match self {
+            Packet::Ping { .. } => PING,
+            Packet::Pong { .. } => PONG,
+            Packet::FindNode { .. } => FIND_NODE,
+            Packet::Neighbours { .. } => NEIGHBOURS,
+            Packet::EnrRequest { .. } => ENR_REQUEST,
+            Packet::EnrResponse { .. } => ENR_RESPONSE,
+        }
+    }
+
+    /// Unix time in seconds after which packet is not valid, `EnrResponse` does not expire.
+    pub fn expiration(&self) -> Option<u64> {
+        match self {
+            Packet::Ping { expiration, .. }
+            | Packet::Pong { expiration, .. }
+            | Packet::FindNode { expiration, .. }
+            | Packet::Neighbours { expiration, .. }
+            | Packet::EnrRequest { expiration } => Some(*expiration),
+            Packet::EnrResponse { .. } => None,
+        }
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new();
+        match self {
+            Packet::Ping { from, to, expiration, enr_seq } => {
+                stream.begin_list(4 + enr_seq.is_some() as usize);
+                stream.append(&DISCOVERY_VERSION);
+                from.append(&mut stream);
+                to.append(&mut stream);
+                stream.append(expiration);
+                if let Some(seq) = enr_seq {
+                    stream.append(seq);
+                }
+            }
+            Packet::Pong { to, ping_hash, expiration, enr_seq } => {
+                stream.begin_list(3 + enr_seq.is_some() as usize);
+                to.append(&mut stream);
+                stream.append(ping_hash);
+                stream.append(expiration);
+                if let Some(seq) = enr_seq {
+                    stream.append(seq);
+                }
+            }
+            Packet::FindNode { target, expiration } => {
+                stream.begin_list(2);
+                stream.append(target);
+                stream.append(expiration);
+            }
+            Packet::Neighbours { nodes, expiration } => {
+                stream.begin_list(2);
+                stream.begin_list(nodes.len());
+                for node in nodes.iter() {
+                    stream.begin_list(4);
+                    append_ip(&mut stream, &node.ip);
+                    stream.append(&node.udp_port);
+                    stream.append(&node.tcp_port);
+                    stream.append(&node.node_id);
+                }
+                stream.append(expiration);
+            }
+            Packet::EnrRequest { expiration } => {
+                stream.begin_list(1);
+                stream.append(expiration);
+            }
+            Packet::EnrResponse { request_hash, record } => {
+                stream.begin_list(2);
+                stream.append(request_hash);
+                stream.append_raw(record.as_bytes(), 1);
+            }
+        }
+        stream.out().to_vec()
+    }
+
+    // newer versions of packets can have more fields, they are ignored
+    fn decode_data(packet_type: u8, data: &[u8]) -> Result<Packet, RlpxError> {
+        let rlp = Rlp::new(data);
+        let packet = match packet_type {
+            PING => Packet::Ping {
+                from: Endpoint::decode(&rlp.at(1)?)?,
+                to: Endpoint::decode(&rlp.at(2)?)?,
+                expiration: rlp.val_at(3)?,
+                enr_seq: rlp.val_at(4).ok(),
+            },
+            PONG => Packet::Pong {
+                to: Endpoint::decode(&rlp.at(0)?)?,
+                ping_hash: rlp.val_at(1)?,
+                expiration: rlp.val_at(2)?,
+                enr_seq: rlp.val_at(3).ok(),
+            },
+            FIND_NODE => Packet::FindNode { target: rlp.val_at(0)?, expiration: rlp.val_at(1)? },
+            NEIGHBOURS => Packet::Neighbours {
+                nodes: rlp
+                    .at(0)?
+                    .iter()
+                    .map(|node| {
+                        Ok(NodeRecord {
+                            ip: decode_ip(&node.at(0)?)?,
+                            udp_port: node.val_at(1)?,
+                            tcp_port: node.val_at(2)?,
+                            node_id: node.val_at(3)?,
+                            enr_seq: 0,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, DecoderError>>()?,
+                expiration: rlp.val_at(1)?,
+            },
+            ENR_REQUEST => Packet::EnrRequest { expiration: rlp.val_at(0)? },
+            ENR_RESPONSE => Packet::EnrResponse {
+                request_hash: rlp.val_at(0)?,
+                record: Enr::decode(rlp.at(1)?.as_raw())?,
+            },
+            _ => return Err(RlpxError::Protocol(format!("Unknown discovery packet {}", packet_type))),
+        };
+        Ok(packet)
+    }
+
+    /// Signed packet and its hash, pong refers to ping by its hash.
+    pub fn encode(&self, secret: &SecretKey) -> Result<(Vec<u8>, H256), RlpxError> {
+        let mut signed = vec![self.packet_type()];
+        signed.extend(self.encode_data());
+        let (signature, recovery) = SigningKey::from(secret)
+            .sign_prehash_recoverable(keccak(&signed).as_bytes())
+            .map_err(|_| RlpxError::Crypto("could not sign discovery packet"))?;
+        let mut packet = vec![0u8; HASH_SIZE];
+        packet.extend_from_slice(&signature.to_bytes());
+        packet.push(recovery.to_byte());
+        packet.extend(signed);
+        let hash = keccak(&packet[HASH_SIZE..]);
+        packet[..HASH_SIZE].copy_from_slice(hash.as_bytes());
+        Ok((packet, hash))
+    }
+
+    /// Returns packet, id of node that signed it and hash of packet.
+    pub fn decode(packet: &[u8]) -> Result<(Packet, NodeId, H256), RlpxError> {
+        if packet.len() <= HEADER_SIZE || packet.len() > MAX_PACKET_SIZE {
+            return Err(RlpxError::Protocol(format!("Invalid discovery packet size {}", packet.len())));
+        }
+        let hash = keccak(&packet[HASH_SIZE..]);
+        if hash.as_bytes() != &packet[..HASH_SIZE] {
+            return Err(RlpxError::Crypto("discovery packet hash mismatch"));
+        }
+        let signature = &packet[HASH_SIZE..HEADER_SIZE];
+        let signed = &packet[HEADER_SIZE..];
+        let recovery = RecoveryId::from_byte(signature[64]).ok_or(RlpxError::Crypto("invalid packet signature"))?;
+        let signature =
+            Signature::from_slice(&signature[..64]).map_err(|_| RlpxError::Crypto("invalid packet signature"))?;
+        let key = VerifyingKey::recover_from_prehash(keccak(signed).as_bytes(), &signature, recovery)
+            .map_err(|_| RlpxError::Crypto("could not recover packet signer"))?;
+        let packet = Packet::decode_data(signed[0], &signed[1..])?;
+        Ok((packet, ecies::node_id(&PublicKey::from(key)), hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(port: u16) -> Endpoint {
+        Endpoint { ip: "127.0.0.1".parse().unwrap(), udp_port: port, tcp_port: port }
+    }
+
+    #[test]
+    fn test_packets_roundtrip() {
+        let secret = ecies::random_secret();
+        let id = ecies::node_id(&secret.public_key());
+        let node = NodeRecord {
+            node_id: NodeId::repeat_byte(3),
+            ip: "::1".parse().unwrap(),
+            udp_port: 1,
+            tcp_port: 2,
+            enr_seq: 0,
+        };
+        let packets = vec![
+            Packet::Ping { from: endpoint(1), to: endpoint(2), expiration: 100, enr_seq: Some(3) },
+            Packet::Ping { from: endpoint(1), to: endpoint(2), expiration: 100, enr_seq: None },
+            Packet::Pong { to: endpoint(1), ping_hash: H256::repeat_byte(1), expiration: 100, enr_seq: Some(1) },
+            Packet::FindNode { target: NodeId::repeat_byte(2), expiration: 100 },
+            Packet::Neighbours { nodes: vec![node; MAX_NEIGHBOURS], expiration: 100 },
+            Packet::EnrRequest { expiration: 100 },
+            Packet::EnrResponse {
+                request_hash: H256::repeat_byte(4),
+                record: Enr::sign(&secret, 1, "127.0.0.1".parse().unwrap(), 30303, 30303).unwrap(),
+            },
+        ];
+        for packet in packets {
+            let (encoded, hash) = packet.encode(&secret).unwrap();
+            assert!(encoded.len() <= MAX_PACKET_SIZE);
+            assert_eq!(Packet::decode(&encoded).unwrap(), (packet, id, hash));
+        }
+    }
+
+    #[test]
+    fn test_tampered_packet_is_rejected() {
+        let secret = ecies::random_secret();
+        let (mut encoded, _) = Packet::EnrRequest { expiration: 100 }.encode(&secret).unwrap();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(Packet::decode(&encoded).is_err());
+        // hash matches, but data was signed by someone else
+        let hash = keccak(&encoded[HASH_SIZE..]);
+        encoded[..HASH_SIZE].copy_from_slice(hash.as_bytes());
+        if let Ok((_, id, _)) = Packet::decode(&encoded) {
+            assert_ne!(id, ecies::node_id(&secret.public_key()));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Kademlia routing table. Nodes are put in buckets by log distance of keccak hashes of their ids.
+
+use super::node_store::NodeRecord;
+use crate::{common_types::keccak, devp2p_adapter::NodeId};
+use primitive_types::H256;
+
+pub const BUCKET_SIZE: usize = 16;
+const BUCKET_COUNT: usize = 256;
+const MAX_REPLACEMENTS: usize = 10;
+
+fn hash(id: &NodeId) -> H256 {
+    keccak(id.as_bytes())
+}
+
+/// Number of bits of highest differing bit, 0 for the same hashes.
+fn log_distance(a: &H256, b: &H256) -> usize {
+    for (i, (a, b)) in a.as_bytes().iter().zip(b.as_bytes()).enumerate() {
+        let xor = a ^ b;
+        if xor != 0 {
+            return (32 - i) * 8 - xor.leading_zeros() as usize;
+        }
+    }
+    0
+}
+
+fn distance(a: &H256, b: &H256) -> H256 {
+    let mut out = H256::zero();
+    for (out, (a, b)) in out.as_bytes_mut().iter_mut().zip(a.as_bytes().iter().zip(b.as_bytes())) {
+        *out = a ^ b;
+    }
+    out
+}
+
+#[derive(Default)]
+struct Bucket {
+    // least recently seen first
+    nodes: Vec<NodeRecord>,
+    // nodes that wait for place in full bucket, newest last
+    replacements: Vec<NodeRecord>,
+}
+
+pub struct Table {
+    local: H256,
+    buckets: Vec<Bucket>,
+}
+
+impl Table {
+    pub fn new(local: &NodeId) -> Self {
+        Table { local: hash(local), buckets: (0..BUCKET_COUNT).map(|_| Bucket::default()).collect() }
+    }
+
+    fn bucket(&mut self, id: &NodeId) -> Option<&mut Bucket> {
+        let distance = log_distance(&self.local, &hash(id));
+        if distance == 0 {
+            return None;
+        }
+        self.buckets.get_mut(distance - 1)
+    }
+
+    /// Adds node that answered us, or marks it as recently seen. If its bucket is full, node is kept
+    /// as replacement and least recently seen node of the bucket is returned, so it can be checked.
+    pub fn add(&mut self, record: NodeRecord) -> Option<NodeRecord> {
+        let bucket = self.bucket(&record.node_id)?;
+        if let Some(index) = bucket.nodes.iter().position(|node| node.node_id == record.node_id) {
+            bucket.nodes.remove(index);
+            bucket.nodes.push(record);
+            return None;
+        }
+        if bucket.nodes.len() < BUCKET_SIZE {
+            bucket.nodes.push(record);
+            return None;
+        }
+        bucket.replacements.retain(|node| node.node_id != record.node_id);
+        bucket.replacements.push(record);
+        if bucket.replacements.len() > MAX_REPLACEMENTS {
+            bucket.replacements.remove(0);
+        }
+        bucket.nodes.first().cloned()
+    }
+
+    /// Removes node that stopped answering, its place takes newest replacement.
+    pub fn remove(&mut self, id: &NodeId) -> Option<NodeRecord> {
+        let bucket = self.bucket(id)?;
+        bucket.replacements.retain(|node| node.node_id != *id);
+        let index = bucket.nodes.iter().position(|node| node.node_id == *id)?;
+        let removed = bucket.nodes.remove(index);
+        if let Some(replacement) = bucket.replacements.pop() {
+            bucket.nodes.push(replacement);
+        }
+        Some(removed)
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&NodeRecord> {
+        let distance = log_distance(&self.local, &hash(id));
+        self.buckets.get(distance.checked_sub(1)?)?.nodes.iter().find(|node| node.node_id == *id)
+    }
+
+    pub fn update(&mut self, record: NodeRecord) {
+        if let Some(node) = self
+            .bucket(&record.node_id)
+            .and_then(|bucket| bucket.nodes.iter_mut().find(|node| node.node_id == record.node_id))
+        {
+            *node = record;
+        }
+    }
+
+    /// Nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeRecord> {
+        let target = hash(target);
+        let mut nodes: Vec<(H256, &NodeRecord)> =
+            self.iter().map(|node| (distance(&target, &hash(&node.node_id)), node)).collect();
+        nodes.sort_by_key(|(distance, _)| *distance);
+        nodes.into_iter().take(count).map(|(_, node)| node.clone()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NodeRecord> {
+        self.buckets.iter().flat_map(|bucket| bucket.nodes.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(n: u64) -> NodeRecord {
+        NodeRecord {
+            node_id: NodeId::from_low_u64_be(n),
+            ip: "127.0.0.1".parse().unwrap(),
+            udp_port: 30301,
+            tcp_port: 30303,
+            enr_seq: 0,
+        }
+    }
+
+    #[test]
+    fn test_log_distances() {
+        let zero = H256::zero();
+        assert_eq!(log_distance(&zero, &zero), 0);
+        assert_eq!(log_distance(&zero, &H256::from_low_u64_be(1)), 1);
+        assert_eq!(log_distance(&zero, &H256::from_low_u64_be(0x80)), 8);
+        assert_eq!(log_distance(&zero, &H256::repeat_byte(0xff)), 256);
+    }
+
+    #[test]
+    fn test_full_bucket_keeps_replacements() {
+        let local = NodeId::zero();
+        let mut table = Table::new(&local);
+        assert_eq!(table.add(NodeRecord { node_id: local, ..record(0) }), None);
+        assert!(table.is_empty());
+
+        // most of random ids fall into the farthest bucket
+        let farthest: Vec<NodeRecord> = (1..200)
+            .map(record)
+            .filter(|node| log_distance(&table.local, &hash(&node.node_id)) == 256)
+            .collect();
+        assert!(farthest.len() > BUCKET_SIZE);
+        for node in farthest.iter().take(BUCKET_SIZE) {
+            assert_eq!(table.add(node.clone()), None);
+        }
+        // oldest node has to be checked before replacement gets in
+        assert_eq!(table.add(farthest[BUCKET_SIZE].clone()), Some(farthest[0].clone()));
+        assert!(table.get(&farthest[BUCKET_SIZE].node_id).is_none());
+        table.remove(&farthest[0].node_id);
+        assert!(table.get(&farthest[0].node_id).is_none());
+        assert!(table.get(&farthest[BUCKET_SIZE].node_id).is_some());
+        assert_eq!(table.len(), BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_closest_nodes_are_sorted() {
+        let mut table = Table::new(&NodeId::zero());
+        for n in 1..50 {
+            table.add(record(n));
+        }
+        let target = NodeId::from_low_u64_be(7);
+        let closest = table.closest(&target, 5);
+        assert_eq!(closest.len(), 5);
+        assert_eq!(closest[0].node_id, target);
+        let target = hash(&target);
+        for pair in closest.windows(2) {
+            assert!(distance(&target, &hash(&pair[0].node_id)) < distance(&target, &hash(&pair[1].node_id)));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! ECIES encryption of handshake messages and key helpers, as specified by RLPx.
+
+use super::RlpxError;
+use crate::devp2p_adapter::NodeId;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use k256::{
+    ecdh,
+    elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint},
+    EncodedPoint, PublicKey, SecretKey,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+pub type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Size of uncompressed public key, with 0x04 prefix.
+const PUBLIC_KEY_SIZE: usize = 65;
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+/// Bytes that ECIES adds to plain text.
+pub const ECIES_OVERHEAD: usize = PUBLIC_KEY_SIZE + IV_SIZE + MAC_SIZE;
+
+/// Node id is public key without 0x04 prefix.
+pub fn node_id(public: &PublicKey) -> NodeId {
+    NodeId::from_slice(&public.to_encoded_point(false).as_bytes()[1..])
+}
+
+pub fn public_key(node_id: &NodeId) -> Result<PublicKey, RlpxError> {
+    let point = EncodedPoint::from_untagged_bytes(node_id.as_fixed_bytes().into());
+    Option::from(PublicKey::from_encoded_point(&point)).ok_or(RlpxError::Crypto("invalid public key"))
+}
+
+/// X coordinate of shared point.
+pub fn ecdh_x(secret: &SecretKey, public: &PublicKey) -> [u8; 32] {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    let mut x = [0u8; 32];
+    x.copy_from_slice(shared.raw_secret_bytes());
+    x
+}
+
+pub fn random_secret() -> SecretKey {
+    SecretKey::random(&mut rand::thread_rng())
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+// NIST SP 800-56 concatenation KDF with single round, enough for 32 bytes of key material
+fn kdf(shared: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(shared);
+    hasher.finalize().into()
+}
+
+// encryption and mac key
+fn keys(shared: &[u8; 32]) -> ([u8; 16], [u8; 32]) {
+    let key = kdf(shared);
+    let mut enc = [0u8; 16];
+    enc.copy_from_slice(&key[..16]);
+    (enc, Sha256::digest(&key[16..]).into())
+}
+
+fn mac(key: &[u8; 32], iv: &[u8], cipher_text: &[u8], mac_data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes key of any size");
+    mac.update(iv);
+    mac.update(cipher_text);
+    mac.update(mac_data);
+    mac
+}
+
+/// Encrypts `plain` to `remote` key. `mac_data` is authenticated but not included in output.
+pub fn encrypt(remote: &PublicKey, plain: &[u8], mac_data: &[u8]) -> Vec<u8> {
+    let ephemeral = random_secret();
+    let (enc_key, mac_key) = keys(&ecdh_x(&ephemeral, remote));
+    let iv: [u8; IV_SIZE] = random_bytes();
+
+    let mut out = Vec::with_capacity(plain.len() + ECIES_OVERHEAD);
+    out.extend_from_slice(ephemeral.public_key().to_encoded_point(false).as_bytes());
+    out.extend_from_slice(&iv);
+    let mut cipher_text = plain.to_vec();
+    Aes128Ctr::new(&enc_key.into(), &iv.into()).apply_keystream(&mut cipher_text);
+    out.extend_from_slice(&cipher_text);
+    out.extend_from_slice(&mac(&mac_key, &iv, &cipher_text, mac_data).finalize().into_bytes());
+    out
+}
+
+pub fn decrypt(secret: &SecretKey, packet: &[u8], mac_data: &[u8]) -> Result<Vec<u8>, RlpxError> {
+    if packet.len() < ECIES_OVERHEAD {
+        return Err(RlpxError::Crypto("ecies message is too short"));
+    }
+    let (public, rest) = packet.split_at(PUBLIC_KEY_SIZE);
+    let (iv, rest) = rest.split_at(IV_SIZE);
+    let (cipher_text, tag) = rest.split_at(rest.len() - MAC_SIZE);
+    let public = PublicKey::from_sec1_bytes(public).map_err(|_| RlpxError::Crypto("invalid ecies public key"))?;
+    let (enc_key, mac_key) = keys(&ecdh_x(secret, &public));
+    mac(&mac_key, iv, cipher_text, mac_data)
+        .verify_slice(tag)
+        .map_err(|_| RlpxError::Crypto("ecies mac mismatch"))?;
+    let mut plain = cipher_text.to_vec();
+    Aes128Ctr::new(&enc_key.into(), iv.into()).apply_keystream(&mut plain);
+    Ok(plain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let secret = random_secret();
+        let packet = encrypt(&secret.public_key(), b"hello", b"shared");
+        assert_eq!(packet.len(), 5 + ECIES_OVERHEAD);
+        assert_eq!(decrypt(&secret, &packet, b"shared").unwrap(), b"hello");
+        assert!(decrypt(&secret, &packet, b"other").is_err());
+        assert!(decrypt(&random_secret(), &packet, b"shared").is_err());
+    }
+
+    #[test]
+    fn test_node_id_roundtrip() {
+        let public = random_secret().public_key();
+        assert_eq!(public_key(&node_id(&public)).unwrap(), public);
+        assert!(public_key(&NodeId::zero()).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encrypted and authenticated RLPx frames. Every frame carries one message.
+
+use super::{handshake::Secrets, RlpxError};
+use aes::{
+    cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher},
+    Aes256,
+};
+use std::io::Read;
+use tiny_keccak::{Hasher, Keccak};
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+pub const HEADER_SIZE: usize = 16;
+pub const MAC_SIZE: usize = 16;
+/// Frame size is encoded in three bytes.
+pub const MAX_FRAME_SIZE: usize = 0xff_ffff;
+// rlp of [capability-id, context-id], both zero, as other implementations send it
+const HEADER_DATA: [u8; 3] = [0xc2, 0x80, 0x80];
+
+fn padded(size: usize) -> usize {
+    size.div_ceil(16) * 16
+}
+
+/// Keccak state that authenticates frames in one direction.
+struct MacState {
+    cipher: Aes256,
+    hash: Keccak,
+}
+
+impl MacState {
+    fn digest(&self) -> [u8; MAC_SIZE] {
+        let mut digest = [0u8; 32];
+        self.hash.clone().finalize(&mut digest);
+        let mut out = [0u8; MAC_SIZE];
+        out.copy_from_slice(&digest[..MAC_SIZE]);
+        out
+    }
+
+    // mixes `seed` with encrypted digest and returns new digest
+    fn update_with_seed(&mut self, seed: &[u8]) -> [u8; MAC_SIZE] {
+        let mut block = self.digest();
+        self.cipher.encrypt_block((&mut block).into());
+        for (byte, seed) in block.iter_mut().zip(seed) {
+            *byte ^= seed;
+        }
+        self.hash.update(&block);
+        self.digest()
+    }
+
+    fn header_mac(&mut self, header: &[u8]) -> [u8; MAC_SIZE] {
+        self.update_with_seed(header)
+    }
+
+    fn frame_mac(&mut self, frame: &[u8]) -> [u8; MAC_SIZE] {
+        self.hash.update(frame);
+        let seed = self.digest();
+        self.update_with_seed(&seed)
+    }
+}
+
+/// Encrypts outgoing frames.
+pub struct FrameWriter {
+    cipher: Aes256Ctr,
+    mac: MacState,
+}
+
+/// Decrypts incoming frames.
+pub struct FrameReader {
+    cipher: Aes256Ctr,
+    mac: MacState,
+}
+
+/// Frame codec for both directions, they are used from different threads.
+pub fn frame_codec(secrets: Secrets) -> (FrameWriter, FrameReader) {
+    let (aes, mac) = (secrets.aes, secrets.mac);
+    let cipher = || Aes256Ctr::new(aes.as_fixed_bytes().into(), &[0u8; 16].into());
+    let mac_cipher = || Aes256::new(mac.as_fixed_bytes().into());
+    (
+        FrameWriter { cipher: cipher(), mac: MacState { cipher: mac_cipher(), hash: secrets.egress_mac } },
+        FrameReader { cipher: cipher(), mac: MacState { cipher: mac_cipher(), hash: secrets.ingress_mac } },
+    )
+}
+
+impl FrameWriter {
+    /// Frame with message, `message_id` is already offset by capability.
+    pub fn encode(&mut self, message_id: u8, data: &[u8]) -> Result<Vec<u8>, RlpxError> {
+        let mut frame = rlp::encode(&message_id).to_vec();
+        frame.extend_from_slice(data);
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(RlpxError::Protocol(format!("Message of {} bytes is too big", frame.len())));
+        }
+        let size = (frame.len() as u32).to_be_bytes();
+        let mut header = [0u8; HEADER_SIZE];
+        header[..3].copy_from_slice(&size[1..]);
+        header[3..6].copy_from_slice(&HEADER_DATA);
+        self.cipher.apply_keystream(&mut header);
+        frame.resize(padded(frame.len()), 0);
+        self.cipher.apply_keystream(&mut frame);
+
+        let mut out = Vec::with_capacity(HEADER_SIZE + MAC_SIZE * 2 + frame.len());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&self.mac.header_mac(&header));
+        out.extend_from_slice(&frame);
+        out.extend_from_slice(&self.mac.frame_mac(&frame));
+        Ok(out)
+    }
+}
+
+impl FrameReader {
+    /// Reads one frame and returns message id and data of message in it.
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> Result<(u8, Vec<u8>), RlpxError> {
+        let mut header = [0u8; HEADER_SIZE + MAC_SIZE];
+        reader.read_exact(&mut header)?;
+        let size = self.decode_header(&mut header)?;
+        let mut frame = vec![0u8; padded(size) + MAC_SIZE];
+        reader.read_exact(&mut frame)?;
+        self.decode_frame(&mut frame, size)
+    }
+
+    fn decode_header(&mut self, header: &mut [u8; HEADER_SIZE + MAC_SIZE]) -> Result<usize, RlpxError> {
+        let (header, mac) = header.split_at_mut(HEADER_SIZE);
+        if self.mac.header_mac(header) != *mac {
+            return Err(RlpxError::Crypto("frame header mac mismatch"));
+        }
+        self.cipher.apply_keystream(header);
+        Ok(u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize)
+    }
+
+    fn decode_frame(&mut self, frame: &mut [u8], size: usize) -> Result<(u8, Vec<u8>), RlpxError> {
+        let (frame, mac) = frame.split_at_mut(frame.len() - MAC_SIZE);
+        if self.mac.frame_mac(frame) != *mac {
+            return Err(RlpxError::Crypto("frame mac mismatch"));
+        }
+        self.cipher.apply_keystream(frame);
+        let frame = &frame[..size];
+        let id_rlp = rlp::Rlp::new(frame);
+        let message_id: u8 = id_rlp.as_val()?;
+        let id_size = id_rlp.payload_info()?.total();
+        Ok((message_id, frame[id_size..].to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitive_types::H256;
+
+    fn secrets(egress: &[u8], ingress: &[u8]) -> Secrets {
+        let keccak = |seed: &[u8]| {
+            let mut hash = Keccak::v256();
+            hash.update(seed);
+            hash
+        };
+        Secrets {
+            remote_id: Default::default(),
+            aes: H256::repeat_byte(1),
+            mac: H256::repeat_byte(2),
+            egress_mac: keccak(egress),
+            ingress_mac: keccak(ingress),
+        }
+    }
+
+    #[test]
+    fn test_frames_roundtrip() {
+        let (mut writer, _) = frame_codec(secrets(b"a", b"b"));
+        let (_, mut reader) = frame_codec(secrets(b"b", b"a"));
+        let mut stream = Vec::new();
+        stream.extend(writer.encode(0x10, &[1, 2, 3]).unwrap());
+        stream.extend(writer.encode(0x00, &[0xc0; 40]).unwrap());
+        let mut stream = &stream[..];
+        assert_eq!(reader.read(&mut stream).unwrap(), (0x10, vec![1, 2, 3]));
+        assert_eq!(reader.read(&mut stream).unwrap(), (0x00, vec![0xc0; 40]));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_tampered_frame_is_rejected() {
+        let (mut writer, _) = frame_codec(secrets(b"a", b"b"));
+        let (_, mut reader) = frame_codec(secrets(b"b", b"a"));
+        let mut frame = writer.encode(0x10, &[1, 2, 3]).unwrap();
+        frame[HEADER_SIZE + MAC_SIZE] ^= 1;
+        assert!(reader.read(&mut &frame[..]).is_err());
+    }
+}
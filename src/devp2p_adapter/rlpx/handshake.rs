@@ -0,0 +1,259 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! RLPx auth/ack handshake in EIP-8 format, it derives secrets that frames are encrypted with.
+
+use super::{
+    ecies::{self, ECIES_OVERHEAD},
+    RlpxError,
+};
+use crate::{common_types::keccak, devp2p_adapter::NodeId};
+use k256::{
+    ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey},
+    PublicKey, SecretKey,
+};
+use primitive_types::H256;
+use rand::Rng;
+use rlp::{Rlp, RlpStream};
+use std::io::Read;
+use tiny_keccak::{Hasher, Keccak};
+
+/// Version of auth and ack messages.
+const HANDSHAKE_VERSION: u8 = 4;
+/// Limit for auth and ack size, real ones are below 1KB.
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// Secrets of established connection.
+pub struct Secrets {
+    pub remote_id: NodeId,
+    pub aes: H256,
+    pub mac: H256,
+    pub egress_mac: Keccak,
+    pub ingress_mac: Keccak,
+}
+
+/// One side of handshake. Initiator sends `auth` and reads ack with `finish`,
+/// recipient answers auth with `accept`.
+pub struct Handshake {
+    secret: SecretKey,
+    ephemeral: SecretKey,
+    nonce: H256,
+}
+
+fn xor(a: &H256, b: &H256) -> H256 {
+    let mut out = H256::zero();
+    for (i, byte) in out.as_bytes_mut().iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    out
+}
+
+fn keccak_concat(a: &[u8], b: &[u8]) -> H256 {
+    keccak(&[a, b].concat())
+}
+
+fn mac_state(mac: &H256, nonce: &H256, packet: &[u8]) -> Keccak {
+    let mut state = Keccak::v256();
+    state.update(xor(mac, nonce).as_bytes());
+    state.update(packet);
+    state
+}
+
+/// Reads size prefixed handshake packet. Returned packet contains prefix, as it is part of mac.
+pub fn read_packet<R: Read>(reader: &mut R) -> Result<Vec<u8>, RlpxError> {
+    let mut packet = vec![0u8; 2];
+    reader.read_exact(&mut packet)?;
+    let size = u16::from_be_bytes([packet[0], packet[1]]) as usize;
+    if size > MAX_PACKET_SIZE {
+        return Err(RlpxError::Protocol(format!("Handshake packet of {} bytes is too big", size)));
+    }
+    packet.resize(2 + size, 0);
+    reader.read_exact(&mut packet[2..])?;
+    Ok(packet)
+}
+
+// Encrypts body with random padding, so that EIP-8 packet can't be told apart by its size.
+fn seal(remote: &PublicKey, mut body: Vec<u8>) -> Vec<u8> {
+    let padding = rand::thread_rng().gen_range(100..=250);
+    body.resize(body.len() + padding, 0);
+    let prefix = ((body.len() + ECIES_OVERHEAD) as u16).to_be_bytes();
+    let mut packet = prefix.to_vec();
+    packet.extend(ecies::encrypt(remote, &body, &prefix));
+    packet
+}
+
+fn open(secret: &SecretKey, packet: &[u8]) -> Result<Vec<u8>, RlpxError> {
+    if packet.len() < 2 {
+        return Err(RlpxError::Crypto("handshake packet is too short"));
+    }
+    ecies::decrypt(secret, &packet[2..], &packet[..2])
+}
+
+impl Handshake {
+    pub fn new(secret: SecretKey) -> Self {
+        Self::with_keys(secret, ecies::random_secret(), H256(ecies::random_bytes()))
+    }
+
+    fn with_keys(secret: SecretKey, ephemeral: SecretKey, nonce: H256) -> Self {
+        Handshake { secret, ephemeral, nonce }
+    }
+
+    fn ephemeral_id(&self) -> NodeId {
+        ecies::node_id(&self.ephemeral.public_key())
+    }
+
+    /// Auth packet that initiator sends to `remote`.
+    pub fn auth(&self, remote: &NodeId) -> Result<Vec<u8>, RlpxError> {
+        let remote = ecies::public_key(remote)?;
+        let shared = H256(ecies::ecdh_x(&self.secret, &remote));
+        let (signature, recovery) = SigningKey::from(&self.ephemeral)
+            .sign_prehash_recoverable(xor(&shared, &self.nonce).as_bytes())
+            .map_err(|_| RlpxError::Crypto("could not sign auth"))?;
+        let mut signature = signature.to_bytes().to_vec();
+        signature.push(recovery.to_byte());
+
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&signature);
+        stream.append(&ecies::node_id(&self.secret.public_key()));
+        stream.append(&self.nonce);
+        stream.append(&HANDSHAKE_VERSION);
+        Ok(seal(&remote, stream.out()))
+    }
+
+    /// Answers initiator's auth packet. Returns ack packet that needs to be sent back.
+    pub fn accept(&self, auth: &[u8]) -> Result<(Vec<u8>, Secrets), RlpxError> {
+        let body = open(&self.secret, auth)?;
+        let rlp = Rlp::new(&body);
+        let signature: Vec<u8> = rlp.val_at(0)?;
+        let remote_id: NodeId = rlp.val_at(1)?;
+        let remote_nonce: H256 = rlp.val_at(2)?;
+        if signature.len() != 65 {
+            return Err(RlpxError::Crypto("invalid auth signature"));
+        }
+
+        let shared = H256(ecies::ecdh_x(&self.secret, &ecies::public_key(&remote_id)?));
+        let recovery = RecoveryId::from_byte(signature[64]).ok_or(RlpxError::Crypto("invalid auth signature"))?;
+        let signature = Signature::from_slice(&signature[..64]).map_err(|_| RlpxError::Crypto("invalid auth signature"))?;
+        let remote_ephemeral =
+            VerifyingKey::recover_from_prehash(xor(&shared, &remote_nonce).as_bytes(), &signature, recovery)
+                .map_err(|_| RlpxError::Crypto("could not recover ephemeral key"))?;
+
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&self.ephemeral_id());
+        stream.append(&self.nonce);
+        stream.append(&HANDSHAKE_VERSION);
+        let ack = seal(&ecies::public_key(&remote_id)?, stream.out());
+
+        let secrets = self.secrets(remote_id, &PublicKey::from(remote_ephemeral), &remote_nonce, false, (&ack, auth));
+        Ok((ack, secrets))
+    }
+
+    /// Reads recipient's answer to `auth` that we sent to `remote`.
+    pub fn finish(&self, remote: &NodeId, auth: &[u8], ack: &[u8]) -> Result<Secrets, RlpxError> {
+        let body = open(&self.secret, ack)?;
+        let rlp = Rlp::new(&body);
+        let remote_ephemeral: NodeId = rlp.val_at(0)?;
+        let remote_nonce: H256 = rlp.val_at(1)?;
+        Ok(self.secrets(*remote, &ecies::public_key(&remote_ephemeral)?, &remote_nonce, true, (auth, ack)))
+    }
+
+    // `packets` are (sent, received) handshake packets
+    fn secrets(
+        &self,
+        remote_id: NodeId,
+        remote_ephemeral: &PublicKey,
+        remote_nonce: &H256,
+        initiator: bool,
+        packets: (&[u8], &[u8]),
+    ) -> Secrets {
+        let (initiator_nonce, recipient_nonce) = if initiator {
+            (&self.nonce, remote_nonce)
+        } else {
+            (remote_nonce, &self.nonce)
+        };
+        let ephemeral_key = ecies::ecdh_x(&self.ephemeral, remote_ephemeral);
+        let nonce_hash = keccak_concat(recipient_nonce.as_bytes(), initiator_nonce.as_bytes());
+        let shared = keccak_concat(&ephemeral_key, nonce_hash.as_bytes());
+        let aes = keccak_concat(&ephemeral_key, shared.as_bytes());
+        let mac = keccak_concat(&ephemeral_key, aes.as_bytes());
+        // egress mac starts with nonce of the other side and packet that we sent
+        Secrets {
+            remote_id,
+            aes,
+            mac,
+            egress_mac: mac_state(&mac, remote_nonce, packets.0),
+            ingress_mac: mac_state(&mac, &self.nonce, packets.1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn digest(state: &Keccak) -> H256 {
+        let mut out = [0u8; 32];
+        state.clone().finalize(&mut out);
+        H256(out)
+    }
+
+    fn key(hex: &str) -> SecretKey {
+        SecretKey::from_slice(H256::from_str(hex).unwrap().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_both_sides_derive_same_secrets() {
+        let initiator = Handshake::new(ecies::random_secret());
+        let recipient = Handshake::new(ecies::random_secret());
+        let recipient_id = ecies::node_id(&recipient.secret.public_key());
+
+        let auth = initiator.auth(&recipient_id).unwrap();
+        let (ack, recipient_secrets) = recipient.accept(&auth).unwrap();
+        let initiator_secrets = initiator.finish(&recipient_id, &auth, &ack).unwrap();
+
+        assert_eq!(recipient_secrets.remote_id, ecies::node_id(&initiator.secret.public_key()));
+        assert_eq!(initiator_secrets.aes, recipient_secrets.aes);
+        assert_eq!(initiator_secrets.mac, recipient_secrets.mac);
+        assert_eq!(digest(&initiator_secrets.egress_mac), digest(&recipient_secrets.ingress_mac));
+        assert_eq!(digest(&initiator_secrets.ingress_mac), digest(&recipient_secrets.egress_mac));
+    }
+
+    #[test]
+    fn test_auth_for_other_node_is_rejected() {
+        let initiator = Handshake::new(ecies::random_secret());
+        let other = ecies::node_id(&ecies::random_secret().public_key());
+        let auth = initiator.auth(&other).unwrap();
+        assert!(Handshake::new(ecies::random_secret()).accept(&auth).is_err());
+    }
+
+    // keys and nonces from EIP-8 test vectors
+    #[test]
+    fn test_eip8_secrets() {
+        let initiator = Handshake::with_keys(
+            key("49a7b37aa6f6645917e7b807e9d1c00d4fa71f18343b0d4122a4d2df64dd6fee"),
+            key("869d6ecf5211f1cc60418a13b9d870b22959d0c16f02bec714c960dd2298a32d"),
+            H256::from_str("7e968bba13b6c50e2c4cd7f241cc0d64d1ac25c7f5952df231ac6a2bda8ee5d6").unwrap(),
+        );
+        let recipient = Handshake::with_keys(
+            key("b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291"),
+            key("e238eb8e04fee6511ab04c6dd3c89ce097b11f25d584863ac2b6d5b35b1847e4"),
+            H256::from_str("559aead08264d5795d3909718cdd05abd49572e84fe55590eef31a88a08fdffd").unwrap(),
+        );
+        let secrets = initiator.secrets(
+            NodeId::zero(),
+            &recipient.ephemeral.public_key(),
+            &recipient.nonce,
+            true,
+            (&[], &[]),
+        );
+        assert_eq!(
+            secrets.aes,
+            H256::from_str("80e8632c05fed6fc2a13b0f8d31a3cf645366239170ea067065aba8e28bac487").unwrap()
+        );
+        assert_eq!(
+            secrets.mac,
+            H256::from_str("2ea74ec5dae199227dff1af715362700e989d889d7a493cb0639691efb8e5f98").unwrap()
+        );
+    }
+}
@@ -0,0 +1,844 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Native devp2p transport: RLPx sessions over TCP, so that scheduler can talk to other nodes
+//! without external devp2p stack. Every session has its own reader thread, messages are written
+//! from the thread that sends them. Peers are found with discovery v4 and dialed by dialer.
+
+mod dialer;
+pub mod discovery;
+mod ecies;
+mod framing;
+mod handshake;
+pub mod p2p;
+mod snappy;
+
+use self::{
+    dialer::Dialer,
+    discovery::{
+        node_store::{NoNodeStore, NodeStore},
+        Discovery,
+    },
+    framing::{frame_codec, FrameReader, FrameWriter},
+    handshake::{read_packet, Handshake},
+    p2p::{Capability, DisconnectReason, Enode, Hello, SharedCapabilities},
+};
+use super::adapter::{Devp2pAdapter, Devp2pInbound, NodeId, PeerPenal};
+use crate::{
+    client_adapter::ChainSpec,
+    scheduler::{peer_organizer::PeerId, protocol::ProtocolId},
+};
+use k256::SecretKey;
+use rlp::DecoderError;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time in which peer needs to finish handshake and send `Hello`.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Inbound connections that may be in handshake at once, further ones are closed right away.
+const MAX_PENDING_HANDSHAKES: usize = 50;
+/// Peer that was quiet for this long is pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Peer that does not answer ping in this time is disconnected.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// How often blocked threads check if transport is stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Oldest version of base protocol that we talk with.
+const MIN_P2P_VERSION: u64 = 4;
+
+#[derive(Debug)]
+pub enum RlpxError {
+    Io(io::Error),
+    Rlp(DecoderError),
+    Crypto(&'static str),
+    Protocol(String),
+    /// Peer disconnected us with given reason.
+    Disconnected(Option<DisconnectReason>),
+}
+
+impl fmt::Display for RlpxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RlpxError::Io(err) => write!(f, "Rlpx io error: {}", err),
+            RlpxError::Rlp(err) => write!(f, "Rlpx decoding error: {}", err),
+            RlpxError::Crypto(err) => write!(f, "Rlpx crypto error: {}", err),
+            RlpxError::Protocol(err) => write!(f, "Rlpx protocol error: {}", err),
+            RlpxError::Disconnected(reason) => write!(f, "Disconnected by peer: {:?}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RlpxError {}
+
+impl From<io::Error> for RlpxError {
+    fn from(err: io::Error) -> Self {
+        RlpxError::Io(err)
+    }
+}
+
+impl From<DecoderError> for RlpxError {
+    fn from(err: DecoderError) -> Self {
+        RlpxError::Rlp(err)
+    }
+}
+
+pub struct RlpxConfig {
+    pub secret: SecretKey,
+    pub listen: SocketAddr,
+    pub client_id: String,
+    /// Protocols and their versions that we announce in `Hello`.
+    pub capabilities: Vec<(ProtocolId, u8)>,
+    /// Dialer connects to new nodes while we have less peers than this.
+    pub min_peers: usize,
+    /// Incoming connections over this are refused.
+    pub max_peers: usize,
+    /// Nodes that we stay connected to, they are redialed when they drop.
+    pub static_nodes: Vec<Enode>,
+    /// Entry points for discovery. If discovery is off, they are dialed instead.
+    pub bootnodes: Vec<Enode>,
+    /// Runs discovery on UDP port that is the same as our TCP port.
+    pub discovery: bool,
+    /// Keeps discovered nodes between restarts.
+    pub node_store: Box<dyn NodeStore>,
+}
+
+impl RlpxConfig {
+    pub fn new(secret: SecretKey, listen: SocketAddr) -> Self {
+        RlpxConfig {
+            secret,
+            listen,
+            client_id: format!("reth-scheduler/{}", env!("CARGO_PKG_VERSION")),
+            capabilities: vec![(ProtocolId::Eth, 65), (ProtocolId::Eth, 66)],
+            min_peers: 8,
+            max_peers: 25,
+            static_nodes: Vec::new(),
+            bootnodes: Vec::new(),
+            discovery: true,
+            node_store: Box::new(NoNodeStore),
+        }
+    }
+
+    /// Config with random node key, useful for tests and short lived nodes.
+    pub fn random(listen: SocketAddr) -> Self {
+        Self::new(ecies::random_secret(), listen)
+    }
+
+    /// Uses bootnodes of `chain_spec`. Invalid urls are skipped.
+    pub fn with_bootnodes(mut self, chain_spec: &ChainSpec) -> Self {
+        for url in chain_spec.bootnodes.iter() {
+            match Enode::from_str(url) {
+                Ok(enode) => self.bootnodes.push(enode),
+                Err(err) => warn!("Skipping bootnode: {}", err),
+            }
+        }
+        self
+    }
+}
+
+struct PingState {
+    last_sent: Instant,
+    awaiting_pong: bool,
+}
+
+// Payload as it goes on wire, compressed if peer talks p2p v5.
+fn wire_payload(snappy: bool, data: &[u8]) -> Result<Cow<'_, [u8]>, RlpxError> {
+    if snappy {
+        Ok(Cow::Owned(snappy::compress(data)?))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+struct Session {
+    peer_id: PeerId,
+    remote_id: NodeId,
+    shared: SharedCapabilities,
+    snappy: bool,
+    // used to close connection, reader thread then notices it
+    stream: TcpStream,
+    writer: Mutex<(TcpStream, FrameWriter)>,
+    ping: Mutex<PingState>,
+}
+
+impl Session {
+    fn send(&self, message_id: u8, data: &[u8]) -> Result<(), RlpxError> {
+        let data = wire_payload(self.snappy, data)?;
+        let mut writer = self.writer.lock().unwrap();
+        let frame = writer.1.encode(message_id, &data)?;
+        writer.0.write_all(&frame)?;
+        Ok(())
+    }
+
+    fn close(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn disconnect(&self, reason: DisconnectReason) {
+        debug!("Disconnecting peer {}: {}", self.peer_id, reason);
+        let _ = self.send(p2p::DISCONNECT, &reason.encode());
+        self.close();
+    }
+
+    /// Pings quiet peer. Returns false if peer did not answer previous ping in time.
+    fn check_ping(&self) -> bool {
+        let mut ping = self.ping.lock().unwrap();
+        let elapsed = ping.last_sent.elapsed();
+        if ping.awaiting_pong {
+            return elapsed < PING_TIMEOUT;
+        }
+        if elapsed >= PING_INTERVAL {
+            ping.last_sent = Instant::now();
+            ping.awaiting_pong = true;
+            drop(ping);
+            let _ = self.send(p2p::PING, &rlp::EMPTY_LIST_RLP);
+        }
+        true
+    }
+}
+
+// Blocking reads that wake up periodically, so that session can be pinged and stopped.
+struct SessionReader<'a> {
+    stream: TcpStream,
+    session: &'a Session,
+    running: &'a AtomicBool,
+}
+
+impl Read for SessionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if !self.running.load(Ordering::Relaxed) {
+                        return Err(io::Error::other("transport stopped"));
+                    }
+                    if !self.session.check_ping() {
+                        self.session.disconnect(DisconnectReason::PingTimeout);
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "ping timeout"));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Stream used during handshake, its reads and writes fail once `deadline` passed, so that peer
+/// can not keep handshake going by sending few bytes at a time.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline<'_> {
+    fn remaining(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake took too long")),
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?.min(WRITE_TIMEOUT)))?;
+        (&mut &*self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&mut &*self.stream).flush()
+    }
+}
+
+struct Inner {
+    config: RlpxConfig,
+    node_id: NodeId,
+    capabilities: Vec<Capability>,
+    handler: Mutex<Option<Arc<dyn Devp2pInbound>>>,
+    sessions: Mutex<HashMap<PeerId, Arc<Session>>>,
+    next_peer_id: AtomicUsize,
+    // inbound connections that did not finish handshake yet
+    pending_handshakes: AtomicUsize,
+    running: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+    // nodes that we are connecting to, they are not in sessions yet
+    dialing: Mutex<HashSet<NodeId>>,
+    discovery: Option<Discovery>,
+}
+
+/// `Devp2pAdapter` that talks RLPx to other nodes. Clones share the same transport, so one
+/// can be given to scheduler and other kept to connect to nodes.
+#[derive(Clone)]
+pub struct Rlpx {
+    inner: Arc<Inner>,
+}
+
+impl Rlpx {
+    pub fn new(mut config: RlpxConfig) -> Self {
+        let discovery = if config.discovery {
+            let store = std::mem::replace(&mut config.node_store, Box::new(NoNodeStore));
+            Some(Discovery::new(config.secret.clone(), &config.bootnodes, store))
+        } else {
+            None
+        };
+        let node_id = ecies::node_id(&config.secret.public_key());
+        let capabilities = config
+            .capabilities
+            .iter()
+            .map(|(protocol, version)| Capability::new(*protocol, *version))
+            .collect();
+        Rlpx {
+            inner: Arc::new(Inner {
+                config,
+                node_id,
+                capabilities,
+                handler: Mutex::new(None),
+                sessions: Mutex::new(HashMap::new()),
+                next_peer_id: AtomicUsize::new(1),
+                pending_handshakes: AtomicUsize::new(0),
+                running: AtomicBool::new(false),
+                local_addr: Mutex::new(None),
+                dialing: Mutex::new(HashSet::new()),
+                discovery,
+            }),
+        }
+    }
+
+    pub fn local_id(&self) -> NodeId {
+        self.inner.node_id
+    }
+
+    /// Address that we listen on, known after start.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.inner.local_addr.lock().unwrap()
+    }
+
+    pub fn enode(&self) -> Option<Enode> {
+        Some(Enode { id: self.local_id(), address: self.local_addr()? })
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.inner.sessions.lock().unwrap().len()
+    }
+
+    /// Discovery service, if it is enabled.
+    pub fn discovery(&self) -> Option<&Discovery> {
+        self.inner.discovery.as_ref()
+    }
+
+    /// Dials node in background. Node that is already connected is skipped.
+    pub fn connect(&self, enode: &Enode) {
+        self.inner.connect(enode);
+    }
+}
+
+impl Inner {
+    fn connect(self: &Arc<Self>, enode: &Enode) {
+        if enode.id == self.node_id || self.is_connected(&enode.id) || !self.dialing.lock().unwrap().insert(enode.id) {
+            return;
+        }
+        let inner = self.clone();
+        let enode = *enode;
+        let spawned = thread::Builder::new().name("rlpx-dial".into()).spawn(move || {
+            let established = TcpStream::connect_timeout(&enode.address, CONNECT_TIMEOUT)
+                .map_err(RlpxError::from)
+                .and_then(|stream| inner.establish(stream, Some(enode.id)));
+            inner.dialing.lock().unwrap().remove(&enode.id);
+            match established {
+                Ok((session, reader, stream)) => inner.run_session(session, reader, stream),
+                Err(err) => debug!("Could not connect to {}: {}", enode, err),
+            }
+        });
+        if let Err(err) = spawned {
+            warn!("Could not spawn dial thread: {}", err);
+            self.dialing.lock().unwrap().remove(&enode.id);
+        }
+    }
+
+    fn handler(&self) -> Option<Arc<dyn Devp2pInbound>> {
+        self.handler.lock().unwrap().clone()
+    }
+
+    fn session(&self, peer: &PeerId) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(peer).cloned()
+    }
+
+    fn is_connected(&self, node_id: &NodeId) -> bool {
+        self.sessions.lock().unwrap().values().any(|session| session.remote_id == *node_id)
+    }
+
+    fn hello(&self) -> Hello {
+        Hello {
+            protocol_version: p2p::P2P_VERSION,
+            client_id: self.config.client_id.clone(),
+            capabilities: self.capabilities.clone(),
+            port: self.local_addr.lock().unwrap().map(|addr| addr.port()).unwrap_or(0),
+            id: self.node_id,
+        }
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        while self.running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    trace!("Incoming connection from {}", address);
+                    if self.pending_handshakes.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+                        self.pending_handshakes.fetch_sub(1, Ordering::SeqCst);
+                        debug!("Too many pending handshakes, closing connection from {}", address);
+                        continue;
+                    }
+                    let inner = self.clone();
+                    let spawned = thread::Builder::new()
+                        .name("rlpx-peer".into())
+                        .spawn(move || inner.run_connection(stream, None));
+                    if let Err(err) = spawned {
+                        self.pending_handshakes.fetch_sub(1, Ordering::SeqCst);
+                        warn!("Could not spawn peer thread: {}", err);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(err) => {
+                    warn!("Accepting connection failed: {}", err);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Runs connection until it is closed. `remote` is set if we are the one who dialed.
+    fn run_connection(&self, stream: TcpStream, remote: Option<NodeId>) {
+        let address = stream.peer_addr().ok();
+        let established = self.establish(stream, remote);
+        if remote.is_none() {
+            self.pending_handshakes.fetch_sub(1, Ordering::SeqCst);
+        }
+        match established {
+            Ok((session, reader, stream)) => self.run_session(session, reader, stream),
+            Err(err) => debug!("Connection with {:?} failed: {}", address, err),
+        }
+    }
+
+    fn establish(
+        &self,
+        stream: TcpStream,
+        remote: Option<NodeId>,
+    ) -> Result<(Arc<Session>, FrameReader, TcpStream), RlpxError> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        let mut timed = Deadline { stream: &stream, deadline: Instant::now() + HANDSHAKE_TIMEOUT };
+        let handshake = Handshake::new(self.config.secret.clone());
+        let secrets = match remote {
+            Some(remote) => {
+                let auth = handshake.auth(&remote)?;
+                timed.write_all(&auth)?;
+                let ack = read_packet(&mut timed)?;
+                handshake.finish(&remote, &auth, &ack)?
+            }
+            None => {
+                let auth = read_packet(&mut timed)?;
+                let (ack, secrets) = handshake.accept(&auth)?;
+                timed.write_all(&ack)?;
+                secrets
+            }
+        };
+        let remote_id = secrets.remote_id;
+        let (mut writer, mut reader) = frame_codec(secrets);
+        timed.write_all(&writer.encode(p2p::HELLO, &self.hello().encode())?)?;
+        let hello = match reader.read(&mut timed)? {
+            (p2p::HELLO, data) => Hello::decode(&data)?,
+            (p2p::DISCONNECT, data) => return Err(RlpxError::Disconnected(DisconnectReason::decode(&data))),
+            (id, _) => return Err(RlpxError::Protocol(format!("Expected Hello, got message {}", id))),
+        };
+        debug!("Hello from {}: {:?}", hello.client_id, hello.capabilities);
+        let shared = SharedCapabilities::new(&self.capabilities, &hello.capabilities);
+        let snappy = hello.protocol_version >= p2p::SNAPPY_VERSION;
+
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let session = Arc::new(Session {
+            peer_id: self.next_peer_id.fetch_add(1, Ordering::Relaxed),
+            remote_id,
+            shared,
+            snappy,
+            stream: stream.try_clone()?,
+            writer: Mutex::new((stream.try_clone()?, writer)),
+            ping: Mutex::new(PingState { last_sent: Instant::now(), awaiting_pong: false }),
+        });
+        let refuse = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let refuse = if hello.id != remote_id {
+                Some(DisconnectReason::UnexpectedIdentity)
+            } else if remote_id == self.node_id {
+                Some(DisconnectReason::ConnectedToSelf)
+            } else if hello.protocol_version < MIN_P2P_VERSION {
+                Some(DisconnectReason::IncompatibleVersion)
+            } else if session.shared.is_empty() {
+                Some(DisconnectReason::UselessPeer)
+            } else if sessions.values().any(|session| session.remote_id == remote_id) {
+                Some(DisconnectReason::AlreadyConnected)
+            } else if sessions.len() >= self.config.max_peers {
+                Some(DisconnectReason::TooManyPeers)
+            } else {
+                None
+            };
+            if refuse.is_none() {
+                sessions.insert(session.peer_id, session.clone());
+            }
+            refuse
+        };
+        // peer that is slow to read must not block other sessions, so it is told after lock is released
+        if let Some(reason) = refuse {
+            session.disconnect(reason);
+            return Err(RlpxError::Protocol(format!("Refused peer: {}", reason)));
+        }
+        Ok((session, reader, stream))
+    }
+
+    fn run_session(&self, session: Arc<Session>, mut reader: FrameReader, stream: TcpStream) {
+        let peer = session.peer_id;
+        info!("Rlpx session {} with node {:x} established", peer, session.remote_id);
+        if let Some(handler) = self.handler() {
+            handler.connected(&peer, &session.shared.peer_capability());
+        }
+        let mut stream = SessionReader { stream, session: &session, running: &self.running };
+        loop {
+            let (message_id, data) = match reader.read(&mut stream) {
+                Ok(message) => message,
+                Err(err) => {
+                    debug!("Session {} closed: {}", peer, err);
+                    break;
+                }
+            };
+            let data = if session.snappy {
+                match snappy::decompress(&data) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        debug!("Dropping message {} of peer {}: {}", message_id, peer, err);
+                        if let Some(handler) = self.handler() {
+                            handler.report_peer(&peer, PeerPenal::InvalidData, &err.to_string());
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                data
+            };
+            match message_id {
+                p2p::PING => {
+                    let _ = session.send(p2p::PONG, &rlp::EMPTY_LIST_RLP);
+                }
+                p2p::PONG => session.ping.lock().unwrap().awaiting_pong = false,
+                p2p::DISCONNECT => {
+                    debug!("Peer {} disconnected: {:?}", peer, DisconnectReason::decode(&data));
+                    break;
+                }
+                p2p::HELLO => {
+                    session.disconnect(DisconnectReason::BreachOfProtocol);
+                    break;
+                }
+                id => match session.shared.from_wire(id) {
+                    Some((protocol, message_id)) => {
+                        if let Some(handler) = self.handler() {
+                            handler.receive_message(&peer, protocol, message_id, &data);
+                        }
+                    }
+                    None => trace!("Peer {} sent message {} of unknown capability", peer, id),
+                },
+            }
+        }
+        session.close();
+        self.sessions.lock().unwrap().remove(&peer);
+        if let Some(handler) = self.handler() {
+            handler.disconnected(&peer);
+        }
+    }
+}
+
+impl Devp2pAdapter for Rlpx {
+    fn start(&self) {
+        if self.inner.running.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let listener = match TcpListener::bind(self.inner.config.listen) {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("Could not listen on {}: {}", self.inner.config.listen, err);
+                self.inner.running.store(false, Ordering::Relaxed);
+                return;
+            }
+        };
+        if let Err(err) = listener.set_nonblocking(true) {
+            warn!("Could not set listener to nonblocking: {}", err);
+        }
+        let address = listener.local_addr().ok();
+        *self.inner.local_addr.lock().unwrap() = address;
+        info!("Rlpx listening on {:?} as {:x}", address, self.inner.node_id);
+        let inner = self.inner.clone();
+        let spawned = thread::Builder::new()
+            .name("rlpx-accept".into())
+            .spawn(move || inner.accept_loop(listener));
+        if let Err(err) = spawned {
+            warn!("Could not spawn accept thread: {}", err);
+        }
+        let discovery = self.inner.discovery.as_ref().zip(address).and_then(|(discovery, address)| {
+            match discovery.start(address, address.port()) {
+                Ok(_) => Some(discovery.clone()),
+                Err(err) => {
+                    warn!("Could not start discovery: {}", err);
+                    None
+                }
+            }
+        });
+        let dialer = Dialer::new(self.inner.clone(), discovery);
+        if let Err(err) = thread::Builder::new().name("rlpx-dialer".into()).spawn(move || dialer.run()) {
+            warn!("Could not spawn dialer thread: {}", err);
+        }
+    }
+
+    fn stop(&self) {
+        self.inner.running.store(false, Ordering::Relaxed);
+        if let Some(discovery) = self.inner.discovery.as_ref() {
+            discovery.stop();
+        }
+        let sessions: Vec<Arc<Session>> = self.inner.sessions.lock().unwrap().values().cloned().collect();
+        for session in sessions {
+            session.disconnect(DisconnectReason::ClientQuitting);
+        }
+    }
+
+    fn register_handler(&self, handle: Arc<dyn Devp2pInbound>) {
+        *self.inner.handler.lock().unwrap() = Some(handle);
+    }
+
+    fn send_mesage(&self, protocol: ProtocolId, peer: &PeerId, mesage_id: u8, data: &[u8]) {
+        let session = match self.inner.session(peer) {
+            Some(session) => session,
+            None => {
+                debug!("Message {} to gone peer {} dropped", mesage_id, peer);
+                return;
+            }
+        };
+        let message_id = match session.shared.to_wire(protocol, mesage_id) {
+            Some(message_id) => message_id,
+            None => {
+                warn!("Peer {} does not support message {} of {:?}", peer, mesage_id, protocol);
+                return;
+            }
+        };
+        if let Err(err) = session.send(message_id, data) {
+            debug!("Sending to peer {} failed: {}", peer, err);
+            session.close();
+        }
+    }
+
+    fn penalize_peer(&self, peer: &PeerId, penal: PeerPenal) {
+        let reason = match penal {
+            PeerPenal::Kick | PeerPenal::IncompatibleChain => DisconnectReason::UselessPeer,
+            PeerPenal::Ban => DisconnectReason::BreachOfProtocol,
+            _ => return,
+        };
+        if let Some(session) = self.inner.session(peer) {
+            session.disconnect(reason);
+        }
+    }
+
+    fn node_id(&self, peer: &PeerId) -> Option<NodeId> {
+        self.inner.session(peer).map(|session| session.remote_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::peer_organizer::PeerCapability;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Connected(PeerId, PeerCapability),
+        Message(PeerId, ProtocolId, u8, Vec<u8>),
+        Disconnected(PeerId),
+        Reported(PeerId, PeerPenal),
+    }
+
+    struct Recorder(Mutex<Sender<Event>>);
+
+    impl Devp2pInbound for Recorder {
+        fn receive_message(&self, peer: &PeerId, protocol: ProtocolId, message_id: u8, data: &[u8]) {
+            let _ = self.0.lock().unwrap().send(Event::Message(*peer, protocol, message_id, data.to_vec()));
+        }
+
+        fn connected(&self, peer: &PeerId, capability: &PeerCapability) {
+            let _ = self.0.lock().unwrap().send(Event::Connected(*peer, capability.clone()));
+        }
+
+        fn disconnected(&self, peer: &PeerId) {
+            let _ = self.0.lock().unwrap().send(Event::Disconnected(*peer));
+        }
+
+        fn report_peer(&self, peer: &PeerId, penal: PeerPenal, _reason: &str) {
+            let _ = self.0.lock().unwrap().send(Event::Reported(*peer, penal));
+        }
+    }
+
+    fn config() -> RlpxConfig {
+        RlpxConfig::random("127.0.0.1:0".parse().unwrap())
+    }
+
+    fn node() -> (Rlpx, Receiver<Event>) {
+        start(config())
+    }
+
+    fn start(config: RlpxConfig) -> (Rlpx, Receiver<Event>) {
+        let rlpx = Rlpx::new(config);
+        let (sender, receiver) = channel();
+        rlpx.register_handler(Arc::new(Recorder(Mutex::new(sender))));
+        rlpx.start();
+        (rlpx, receiver)
+    }
+
+    fn next(events: &Receiver<Event>) -> Event {
+        events.recv_timeout(Duration::from_secs(5)).expect("Event in time")
+    }
+
+    // transport, its events and id of the other node as its peer
+    type Connected = (Rlpx, Receiver<Event>, PeerId);
+
+    fn connected_pair() -> (Connected, Connected) {
+        let (a, a_events) = node();
+        let (b, b_events) = node();
+        a.connect(&b.enode().unwrap());
+        let eth66 = PeerCapability::from([(ProtocolId::Eth, [66].iter().copied().collect())]);
+        let a_peer = match next(&a_events) {
+            Event::Connected(peer, capability) if capability == eth66 => peer,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        let b_peer = match next(&b_events) {
+            Event::Connected(peer, capability) if capability == eth66 => peer,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        ((a, a_events, a_peer), (b, b_events, b_peer))
+    }
+
+    #[test]
+    fn test_nodes_exchange_messages() {
+        let ((a, _a_events, a_peer), (b, b_events, b_peer)) = connected_pair();
+        assert_eq!(a.node_id(&a_peer), Some(b.local_id()));
+        assert_eq!(b.node_id(&b_peer), Some(a.local_id()));
+
+        a.send_mesage(ProtocolId::Eth, &a_peer, 0x04, &[0xc3, 1, 2, 3]);
+        a.send_mesage(ProtocolId::Eth, &a_peer, 0x10, &vec![0x55; 5000]);
+        // not negotiated
+        a.send_mesage(ProtocolId::Parity, &a_peer, 0x11, &[0xc0]);
+        assert_eq!(next(&b_events), Event::Message(b_peer, ProtocolId::Eth, 0x04, vec![0xc3, 1, 2, 3]));
+        assert_eq!(next(&b_events), Event::Message(b_peer, ProtocolId::Eth, 0x10, vec![0x55; 5000]));
+        a.stop();
+        b.stop();
+    }
+
+    #[test]
+    fn test_decompression_bomb_is_reported() {
+        let ((a, _a_events, a_peer), (b, b_events, b_peer)) = connected_pair();
+        let session = a.inner.session(&a_peer).unwrap();
+        assert!(session.snappy);
+        // written without compression, snappy header claims 32MB
+        let bomb = [0x80, 0x80, 0x80, 0x10, 0x00];
+        {
+            let mut writer = session.writer.lock().unwrap();
+            let frame = writer.1.encode(0x14, &bomb).unwrap();
+            writer.0.write_all(&frame).unwrap();
+        }
+        assert_eq!(next(&b_events), Event::Reported(b_peer, PeerPenal::InvalidData));
+        // message is dropped, session stays open
+        a.send_mesage(ProtocolId::Eth, &a_peer, 0x04, &[0xc0]);
+        assert_eq!(next(&b_events), Event::Message(b_peer, ProtocolId::Eth, 0x04, vec![0xc0]));
+        a.stop();
+        b.stop();
+    }
+
+    #[test]
+    fn test_kicked_peer_is_disconnected_on_both_sides() {
+        let ((a, a_events, a_peer), (b, b_events, b_peer)) = connected_pair();
+        a.penalize_peer(&a_peer, PeerPenal::Kick);
+        assert_eq!(next(&a_events), Event::Disconnected(a_peer));
+        assert_eq!(next(&b_events), Event::Disconnected(b_peer));
+        assert_eq!(a.peer_count(), 0);
+        assert_eq!(b.peer_count(), 0);
+        a.stop();
+        b.stop();
+    }
+
+    #[test]
+    fn test_dialer_connects_to_discovered_node() {
+        let (boot, boot_events) = node();
+        let mut config = config();
+        config.bootnodes = vec![boot.enode().unwrap()];
+        config.min_peers = 1;
+        let (rlpx, events) = start(config);
+        assert!(matches!(next(&events), Event::Connected(..)));
+        assert!(matches!(next(&boot_events), Event::Connected(..)));
+        let discovered = rlpx.discovery().unwrap().nodes();
+        assert_eq!(discovered.iter().map(|node| node.node_id).collect::<Vec<_>>(), vec![boot.local_id()]);
+        rlpx.stop();
+        boot.stop();
+    }
+
+    #[test]
+    fn test_bootnodes_are_dialed_without_discovery() {
+        let (boot, boot_events) = node();
+        let mut config = config();
+        config.bootnodes = vec![boot.enode().unwrap()];
+        config.discovery = false;
+        config.min_peers = 1;
+        let (rlpx, events) = start(config);
+        assert!(rlpx.discovery().is_none());
+        assert!(matches!(next(&events), Event::Connected(..)));
+        assert!(matches!(next(&boot_events), Event::Connected(..)));
+        rlpx.stop();
+        boot.stop();
+    }
+
+    #[test]
+    fn test_connection_is_closed_when_too_many_handshakes_pending() {
+        let (a, _a_events) = node();
+        a.inner.pending_handshakes.store(MAX_PENDING_HANDSHAKES, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(a.enode().unwrap().address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+        assert_eq!(a.inner.pending_handshakes.load(Ordering::SeqCst), MAX_PENDING_HANDSHAKES);
+        a.stop();
+    }
+
+    #[test]
+    fn test_second_connection_to_same_node_is_refused() {
+        let ((a, a_events, _), (b, _b_events, _)) = connected_pair();
+        a.connect(&b.enode().unwrap());
+        b.connect(&a.enode().unwrap());
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(a.peer_count(), 1);
+        assert_eq!(b.peer_count(), 1);
+        assert!(a_events.try_recv().is_err());
+        a.stop();
+        b.stop();
+    }
+}
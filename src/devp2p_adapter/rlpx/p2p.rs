@@ -0,0 +1,325 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Base devp2p protocol: `Hello`, `Disconnect`, `Ping` and `Pong`, capability negotiation and enode urls.
+
+use super::RlpxError;
+use crate::{
+    devp2p_adapter::NodeId,
+    scheduler::{
+        peer_organizer::PeerCapability,
+        protocol::{ProtocolId, ProtocolIdType},
+    },
+};
+use rlp::{DecoderError, Rlp, RlpStream};
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+};
+
+/// Version of base protocol that we announce in `Hello`.
+pub const P2P_VERSION: u64 = 5;
+/// Messages after `Hello` are snappy compressed from this version on.
+pub const SNAPPY_VERSION: u64 = 5;
+pub const HELLO: u8 = 0x00;
+pub const DISCONNECT: u8 = 0x01;
+pub const PING: u8 = 0x02;
+pub const PONG: u8 = 0x03;
+/// Message ids of capabilities start after ids reserved for base protocol.
+pub const BASE_PROTOCOL_LENGTH: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    DisconnectRequested = 0x00,
+    TcpError = 0x01,
+    BreachOfProtocol = 0x02,
+    UselessPeer = 0x03,
+    TooManyPeers = 0x04,
+    AlreadyConnected = 0x05,
+    IncompatibleVersion = 0x06,
+    InvalidIdentity = 0x07,
+    ClientQuitting = 0x08,
+    UnexpectedIdentity = 0x09,
+    ConnectedToSelf = 0x0a,
+    PingTimeout = 0x0b,
+    SubprotocolError = 0x10,
+}
+
+impl DisconnectReason {
+    pub fn from_u8(reason: u8) -> Option<DisconnectReason> {
+        use DisconnectReason::*;
+        let reason = match reason {
+            0x00 => DisconnectRequested,
+            0x01 => TcpError,
+            0x02 => BreachOfProtocol,
+            0x03 => UselessPeer,
+            0x04 => TooManyPeers,
+            0x05 => AlreadyConnected,
+            0x06 => IncompatibleVersion,
+            0x07 => InvalidIdentity,
+            0x08 => ClientQuitting,
+            0x09 => UnexpectedIdentity,
+            0x0a => ConnectedToSelf,
+            0x0b => PingTimeout,
+            0x10 => SubprotocolError,
+            _ => return None,
+        };
+        Some(reason)
+    }
+
+    pub fn encode(self) -> Vec<u8> {
+        rlp::encode_list(&[self as u8])
+    }
+
+    /// Some clients send reason without list around it.
+    pub fn decode(data: &[u8]) -> Option<DisconnectReason> {
+        let rlp = Rlp::new(data);
+        let reason: u8 = if rlp.is_list() { rlp.val_at(0).ok()? } else { rlp.as_val().ok()? };
+        Self::from_u8(reason)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Number of message ids that capability takes.
+pub fn message_count(protocol: ProtocolId) -> u8 {
+    match protocol {
+        ProtocolId::Eth => 0x11,
+        ProtocolId::Parity => 0x16,
+    }
+}
+
+fn protocol_from_name(name: &[u8]) -> Option<ProtocolId> {
+    [ProtocolId::Eth, ProtocolId::Parity]
+        .iter()
+        .copied()
+        .find(|protocol| protocol.to_protocol_type()[..] == *name)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capability {
+    pub name: Vec<u8>,
+    pub version: u8,
+}
+
+impl Capability {
+    pub fn new(protocol: ProtocolId, version: u8) -> Self {
+        let name: ProtocolIdType = protocol.to_protocol_type();
+        Capability { name: name.to_vec(), version }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u64,
+    pub client_id: String,
+    pub capabilities: Vec<Capability>,
+    pub port: u16,
+    pub id: NodeId,
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(5);
+        stream.append(&self.protocol_version);
+        stream.append(&self.client_id);
+        stream.begin_list(self.capabilities.len());
+        for capability in self.capabilities.iter() {
+            stream.begin_list(2);
+            stream.append(&capability.name);
+            stream.append(&capability.version);
+        }
+        stream.append(&self.port);
+        stream.append(&self.id);
+        stream.out()
+    }
+
+    /// Fields that newer versions add at the end are ignored.
+    pub fn decode(data: &[u8]) -> Result<Hello, DecoderError> {
+        let rlp = Rlp::new(data);
+        let capabilities = rlp
+            .at(2)?
+            .iter()
+            .map(|capability| {
+                Ok(Capability { name: capability.val_at(0)?, version: capability.val_at(1)? })
+            })
+            .collect::<Result<Vec<_>, DecoderError>>()?;
+        Ok(Hello {
+            protocol_version: rlp.val_at(0)?,
+            client_id: rlp.val_at(1)?,
+            capabilities,
+            port: rlp.val_at(3)?,
+            id: rlp.val_at(4)?,
+        })
+    }
+}
+
+/// Capabilities that both sides support, with highest common version of each. Ids of their
+/// messages follow base protocol in alphabetical order of capability names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedCapabilities {
+    // protocol, version and offset of its first message id
+    shared: Vec<(ProtocolId, u8, u8)>,
+}
+
+impl SharedCapabilities {
+    pub fn new(ours: &[Capability], theirs: &[Capability]) -> Self {
+        let mut shared: Vec<(Vec<u8>, ProtocolId, u8)> = Vec::new();
+        for capability in ours.iter().filter(|capability| theirs.contains(capability)) {
+            let protocol = match protocol_from_name(&capability.name) {
+                Some(protocol) => protocol,
+                None => continue,
+            };
+            match shared.iter_mut().find(|(_, shared, _)| *shared == protocol) {
+                Some((_, _, version)) => *version = std::cmp::max(*version, capability.version),
+                None => shared.push((capability.name.clone(), protocol, capability.version)),
+            }
+        }
+        shared.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut offset = BASE_PROTOCOL_LENGTH;
+        let shared = shared
+            .into_iter()
+            .map(|(_, protocol, version)| {
+                let capability = (protocol, version, offset);
+                offset += message_count(protocol);
+                capability
+            })
+            .collect();
+        SharedCapabilities { shared }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.is_empty()
+    }
+
+    /// Capability as scheduler expects it, with only negotiated version of every protocol.
+    pub fn peer_capability(&self) -> PeerCapability {
+        self.shared
+            .iter()
+            .map(|(protocol, version, _)| (*protocol, [*version].iter().copied().collect()))
+            .collect()
+    }
+
+    /// Id of message on wire.
+    pub fn to_wire(&self, protocol: ProtocolId, message_id: u8) -> Option<u8> {
+        let (_, _, offset) = self.shared.iter().find(|(shared, _, _)| *shared == protocol)?;
+        if message_id >= message_count(protocol) {
+            return None;
+        }
+        Some(offset + message_id)
+    }
+
+    /// Protocol and its message id of message received from wire.
+    pub fn from_wire(&self, message_id: u8) -> Option<(ProtocolId, u8)> {
+        self.shared.iter().find_map(|(protocol, _, offset)| {
+            if message_id >= *offset && message_id < offset + message_count(*protocol) {
+                Some((*protocol, message_id - offset))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Node address in `enode://<node id hex>@<host>:<port>` format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Enode {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+impl FromStr for Enode {
+    type Err = RlpxError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || RlpxError::Protocol(format!("Invalid enode url {}", url));
+        let rest = url.strip_prefix("enode://").ok_or_else(invalid)?;
+        let (id, address) = rest.split_once('@').ok_or_else(invalid)?;
+        // discovery port is not used by transport
+        let address = address.split('?').next().ok_or_else(invalid)?;
+        let id = NodeId::from_str(id).map_err(|_| invalid())?;
+        // host names are not resolved, lookup would block the caller
+        let address = SocketAddr::from_str(address).map_err(|_| invalid())?;
+        Ok(Enode { id, address })
+    }
+}
+
+impl fmt::Display for Enode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "enode://{:x}@{}", self.id, self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(list: &[(ProtocolId, u8)]) -> Vec<Capability> {
+        list.iter().map(|(protocol, version)| Capability::new(*protocol, *version)).collect()
+    }
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = Hello {
+            protocol_version: P2P_VERSION,
+            client_id: "test/1.0".into(),
+            capabilities: capabilities(&[(ProtocolId::Eth, 65), (ProtocolId::Eth, 66)]),
+            port: 30303,
+            id: NodeId::repeat_byte(7),
+        };
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_highest_shared_version_is_used() {
+        let ours = capabilities(&[(ProtocolId::Eth, 65), (ProtocolId::Eth, 66), (ProtocolId::Parity, 2)]);
+        let mut theirs = capabilities(&[(ProtocolId::Eth, 66), (ProtocolId::Eth, 65), (ProtocolId::Parity, 2)]);
+        theirs.push(Capability { name: b"snap".to_vec(), version: 1 });
+        let shared = SharedCapabilities::new(&ours, &theirs);
+
+        let capability = shared.peer_capability();
+        assert_eq!(capability[&ProtocolId::Eth].iter().copied().collect::<Vec<u8>>(), vec![66]);
+        assert!(capability[&ProtocolId::Parity].contains(&2));
+        // eth goes before par
+        assert_eq!(shared.to_wire(ProtocolId::Eth, 0x04), Some(0x14));
+        assert_eq!(shared.to_wire(ProtocolId::Parity, 0x13), Some(0x21 + 0x13));
+        assert_eq!(shared.from_wire(0x20), Some((ProtocolId::Eth, 0x10)));
+        assert_eq!(shared.from_wire(0x21 + 0x14), Some((ProtocolId::Parity, 0x14)));
+        assert_eq!(shared.from_wire(0x05), None);
+    }
+
+    #[test]
+    fn test_nothing_shared() {
+        let shared = SharedCapabilities::new(
+            &capabilities(&[(ProtocolId::Eth, 66)]),
+            &capabilities(&[(ProtocolId::Eth, 64)]),
+        );
+        assert!(shared.is_empty());
+        assert_eq!(shared.to_wire(ProtocolId::Eth, 0), None);
+    }
+
+    #[test]
+    fn test_enode_url() {
+        let url = "enode://d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666@18.138.108.67:30303?discport=30301";
+        let enode = Enode::from_str(url).unwrap();
+        assert_eq!(enode.address, "18.138.108.67:30303".parse().unwrap());
+        assert_eq!(enode.to_string(), url.split('?').next().unwrap());
+        assert!(Enode::from_str("enode://1234@127.0.0.1:30303").is_err());
+        assert!(Enode::from_str("127.0.0.1:30303").is_err());
+        let host = url.replace("18.138.108.67", "localhost");
+        assert!(Enode::from_str(&host).is_err());
+    }
+
+    #[test]
+    fn test_disconnect_reason() {
+        let reason = DisconnectReason::TooManyPeers;
+        assert_eq!(DisconnectReason::decode(&reason.encode()), Some(reason));
+        assert_eq!(DisconnectReason::decode(&rlp::encode(&4u8)), Some(reason));
+        assert_eq!(DisconnectReason::decode(&rlp::encode_list(&[0x20u8])), None);
+    }
+}
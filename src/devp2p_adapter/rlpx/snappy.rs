@@ -0,0 +1,59 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snappy compression of message payloads, used when both sides talk p2p v5.
+
+use super::RlpxError;
+
+/// Limit for decompressed message, the same as for frame. Size is checked before anything
+/// is decompressed, so small message can't make us allocate a lot of memory.
+pub const MAX_MESSAGE_SIZE: usize = 0xff_ffff;
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, RlpxError> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(RlpxError::Protocol(format!("Message of {} bytes is too big", data.len())));
+    }
+    snap::raw::Encoder::new()
+        .compress_vec(data)
+        .map_err(|err| RlpxError::Protocol(format!("Snappy compression failed: {}", err)))
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, RlpxError> {
+    let size = snap::raw::decompress_len(data)
+        .map_err(|err| RlpxError::Protocol(format!("Invalid snappy message: {}", err)))?;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(RlpxError::Protocol(format!("Decompressed message of {} bytes is too big", size)));
+    }
+    snap::raw::Decoder::new()
+        .decompress_vec(data)
+        .map_err(|err| RlpxError::Protocol(format!("Invalid snappy message: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = vec![0xc0; 1000];
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert!(decompress(&[0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_decompression_bomb_is_rejected() {
+        // header claims 32MB, nothing is allocated for it
+        let mut bomb = Vec::new();
+        let mut size = 32 * 1024 * 1024u32;
+        while size >= 0x80 {
+            bomb.push(size as u8 | 0x80);
+            size >>= 7;
+        }
+        bomb.push(size as u8);
+        bomb.extend_from_slice(&[0x00, 0x00]);
+        assert!(decompress(&bomb).is_err());
+        assert!(compress(&vec![0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+}
@@ -21,5 +21,7 @@ pub mod snapshot_manager;
 pub mod transaction_manager;
 pub mod client_adapter;
 pub mod common_types;
+#[cfg(test)]
+pub(crate) mod test_fixtures;
 
 pub use scheduler::Scheduler;
@@ -0,0 +1,286 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scheduler driven by tokio instead of dedicated thread. Main loop is a task woken by timer and
+//! by triggers, and outgoing messages are queued to a task that awaits `AsyncDevp2pAdapter`.
+//! Scheduler itself is synchronous: it takes `std::sync::Mutex` locks and calls client directly,
+//! so main loop and inbound handling run on tokio's blocking pool, never on async workers.
+
+use super::{
+    peer_organizer::{PeerCapability, PeerId, PeerOrganizer},
+    peer_store::{NoPeerStore, PeerStore},
+    protocol::ProtocolId,
+    scheduler::{LoopTrigger, LOOP_INTERVAL},
+    Scheduler,
+};
+use crate::{
+    client_adapter::{
+        client_info::{Client, Snapshot},
+        headers_in_memory::HeadersInMemory,
+        Blockchain, ChainSpec,
+    },
+    devp2p_adapter::{Devp2pAdapter, Devp2pInbound, NodeId, PeerPenal},
+};
+use std::{
+    future::Future,
+    panic,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Notify,
+    },
+    task::{self, JoinHandle},
+    time::{self, MissedTickBehavior},
+};
+
+/// Calls that can wait for adapter. When it is full, messages to peers are dropped.
+const OUTBOUND_CAPACITY: usize = 1024;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Network that is driven by async code. Calls are awaited one by one in order scheduler made them.
+pub trait AsyncDevp2pAdapter: Send + Sync + 'static {
+    fn start(&self) -> BoxFuture<'_, ()>;
+    fn stop(&self) -> BoxFuture<'_, ()>;
+    fn send_message(&self, protocol: ProtocolId, peer: PeerId, message_id: u8, data: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn penalize_peer(&self, peer: PeerId, penal: PeerPenal) -> BoxFuture<'_, ()>;
+    /// Public key of connected peer. Reputation and bans are persisted only for peers with known identity.
+    fn node_id(&self, _peer: &PeerId) -> Option<NodeId> {
+        None
+    }
+}
+
+enum Outbound {
+    Start,
+    Stop,
+    Send(ProtocolId, PeerId, u8, Vec<u8>),
+    Penalize(PeerId, PeerPenal),
+    // answered when everything queued before it is done
+    Flush(oneshot::Sender<()>),
+}
+
+/// Wakes main loop task. Triggers that come while loop is busy are coalesced into one.
+pub(crate) struct LoopWaker {
+    notify: Notify,
+    ended: AtomicBool,
+}
+
+impl LoopWaker {
+    pub(crate) fn trigger(&self) {
+        self.notify.notify_one();
+    }
+
+    pub(crate) fn end(&self) {
+        self.ended.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+}
+
+/// Adapter that scheduler sees, calls are queued for `AsyncDevp2pAdapter`.
+struct AdapterBridge {
+    adapter: Arc<dyn AsyncDevp2pAdapter>,
+    outbound: mpsc::Sender<Outbound>,
+    runtime: Handle,
+}
+
+impl Devp2pAdapter for AdapterBridge {
+    // adapter is started and stopped by `AsyncScheduler`, it waits for it
+    fn start(&self) {}
+
+    fn stop(&self) {}
+
+    // inbound calls come through `AsyncScheduler`
+    fn register_handler(&self, _handle: Arc<dyn Devp2pInbound>) {}
+
+    fn send_mesage(&self, protocol: ProtocolId, peer: &PeerId, mesage_id: u8, data: &[u8]) {
+        let call = Outbound::Send(protocol, *peer, mesage_id, data.to_vec());
+        if let Err(TrySendError::Full(_)) = self.outbound.try_send(call) {
+            debug!("Adapter queue is full, dropping message {} to peer {}", mesage_id, peer);
+        }
+    }
+
+    fn penalize_peer(&self, peer: &PeerId, penal: PeerPenal) {
+        // penalties are rare and must not be lost, full queue is waited for
+        if let Err(TrySendError::Full(call)) = self.outbound.try_send(Outbound::Penalize(*peer, penal)) {
+            let outbound = self.outbound.clone();
+            self.runtime.spawn(async move {
+                let _ = outbound.send(call).await;
+            });
+        }
+    }
+
+    fn node_id(&self, peer: &PeerId) -> Option<NodeId> {
+        self.adapter.node_id(peer)
+    }
+}
+
+async fn run_outbound(adapter: Arc<dyn AsyncDevp2pAdapter>, mut rx: mpsc::Receiver<Outbound>) {
+    while let Some(call) = rx.recv().await {
+        match call {
+            Outbound::Start => adapter.start().await,
+            Outbound::Stop => adapter.stop().await,
+            Outbound::Send(protocol, peer, message_id, data) => {
+                adapter.send_message(protocol, peer, message_id, data).await
+            }
+            Outbound::Penalize(peer, penal) => adapter.penalize_peer(peer, penal).await,
+            Outbound::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Runs synchronous scheduler call on blocking pool, panics are passed to the caller.
+async fn blocking<F>(scheduler: &Arc<Scheduler>, call: F)
+where
+    F: FnOnce(&Scheduler) + Send + 'static,
+{
+    let scheduler = scheduler.clone();
+    if let Err(err) = task::spawn_blocking(move || call(&scheduler)).await {
+        if err.is_panic() {
+            panic::resume_unwind(err.into_panic());
+        }
+    }
+}
+
+async fn run_main_loop(scheduler: Arc<Scheduler>, waker: Arc<LoopWaker>) {
+    let mut interval = time::interval(LOOP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = waker.notify.notified() => (),
+        }
+        if waker.ended.load(Ordering::Relaxed) {
+            break;
+        }
+        blocking(&scheduler, Scheduler::main_loop).await;
+    }
+}
+
+/// Scheduler that runs on tokio runtime it was created in, without its own thread.
+pub struct AsyncScheduler {
+    scheduler: Arc<Scheduler>,
+    outbound: mpsc::Sender<Outbound>,
+    waker: Arc<LoopWaker>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl AsyncScheduler {
+    /// Has to be called from within tokio runtime.
+    pub fn new(
+        devp2p: Arc<dyn AsyncDevp2pAdapter>,
+        client: Arc<dyn Client>,
+        snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+    ) -> Self {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        Self::with_chain(devp2p, client, snapshot, chain_spec, chain, Box::new(NoPeerStore))
+    }
+
+    /// Scheduler that syncs into and serves peers from given `chain`, reputation of nodes is kept
+    /// in `peer_store`. Has to be called from within tokio runtime.
+    pub fn with_chain(
+        devp2p: Arc<dyn AsyncDevp2pAdapter>,
+        client: Arc<dyn Client>,
+        snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+        chain: Arc<Mutex<dyn Blockchain + Send + Sync>>,
+        peer_store: Box<dyn PeerStore>,
+    ) -> Self {
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_CAPACITY);
+        let waker = Arc::new(LoopWaker { notify: Notify::new(), ended: AtomicBool::new(false) });
+        let bridge: Box<dyn Devp2pAdapter> =
+            Box::new(AdapterBridge { adapter: devp2p.clone(), outbound: outbound.clone(), runtime: Handle::current() });
+        let peer_organizer = PeerOrganizer::new(Arc::new(bridge), peer_store);
+        let scheduler = Scheduler::build(
+            peer_organizer,
+            client,
+            snapshot,
+            chain_spec,
+            chain,
+            LoopTrigger::Async(waker.clone()),
+        );
+        let tasks = vec![
+            tokio::spawn(run_outbound(devp2p, outbound_rx)),
+            tokio::spawn(run_main_loop(scheduler.clone(), waker.clone())),
+        ];
+        AsyncScheduler { scheduler, outbound, waker, tasks: Mutex::new(tasks) }
+    }
+
+    /// Scheduler itself, for state, bans, and submitting transactions and blocks.
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
+    /// Starts adapter and waits until it is started.
+    pub async fn start(&self) {
+        blocking(&self.scheduler, Scheduler::start).await;
+        let _ = self.outbound.send(Outbound::Start).await;
+        self.flush().await;
+    }
+
+    /// Ends main loop, stops adapter and waits for both. Messages queued before are still sent.
+    pub async fn stop(&self) {
+        blocking(&self.scheduler, Scheduler::stop).await;
+        let _ = self.outbound.send(Outbound::Stop).await;
+        self.flush().await;
+        // main loop is spawned last, outbound task lives as long as scheduler
+        let main_loop = self.tasks.lock().unwrap().pop();
+        if let Some(main_loop) = main_loop {
+            let _ = main_loop.await;
+        }
+    }
+
+    /// Waits until adapter handled all calls that scheduler made so far.
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.outbound.send(Outbound::Flush(done)).await.is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    /// Called when new network packet received. Answers are queued for adapter.
+    pub async fn receive_message(&self, peer: &PeerId, protocol: ProtocolId, message_id: u8, data: &[u8]) {
+        let (peer, data) = (*peer, data.to_vec());
+        blocking(&self.scheduler, move |scheduler| scheduler.receive_message(&peer, protocol, message_id, &data))
+            .await;
+        self.waker.trigger();
+    }
+
+    /// Called when new peer is connected. Only called when peer supports the same protocol.
+    pub async fn connected(&self, peer: &PeerId, capability: &PeerCapability) {
+        let (peer, capability) = (*peer, capability.clone());
+        blocking(&self.scheduler, move |scheduler| scheduler.connected(&peer, &capability)).await;
+        self.waker.trigger();
+    }
+
+    /// Called when a previously connected peer disconnects.
+    pub async fn disconnected(&self, peer: &PeerId) {
+        let peer = *peer;
+        blocking(&self.scheduler, move |scheduler| scheduler.disconnected(&peer)).await;
+        self.waker.trigger();
+    }
+
+    /// Called when adapter drops message because peer broke transport rules, e.g. sent too big message.
+    pub async fn report_peer(&self, peer: &PeerId, penal: PeerPenal, reason: &str) {
+        let (peer, reason) = (*peer, reason.to_string());
+        blocking(&self.scheduler, move |scheduler| scheduler.report_peer(&peer, penal, &reason)).await;
+    }
+}
+
+impl Drop for AsyncScheduler {
+    fn drop(&mut self) {
+        self.waker.end();
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time source of scheduler. Tests move it forward instead of sleeping until tasks time out.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// System time shifted forward by `advance`. Clones share the shift, so that all schedulers
+/// of mock network see the same time.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> Instant {
+        Instant::now() + *self.offset.lock().unwrap()
+    }
+
+    /// Wall clock time, used for bans that outlive the process.
+    pub fn system_now(&self) -> SystemTime {
+        SystemTime::now() + *self.offset.lock().unwrap()
+    }
+
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_are_advanced_together() {
+        let clock = Clock::new();
+        let other = clock.clone();
+        let start = other.now();
+        clock.advance(Duration::from_secs(60));
+        assert!(other.elapsed(start) >= Duration::from_secs(60));
+    }
+}
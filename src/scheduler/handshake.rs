@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
-    client_adapter::client_info::{ClientStatus, SnapshotManifestStatus},
+    client_adapter::{
+        client_info::{ClientStatus, SnapshotManifestStatus},
+        ChainSpec,
+    },
     devp2p_adapter::PeerPenal,
     snapshot_manager,
 };
@@ -12,18 +15,18 @@ use super::{
     protocol::{EthProtocolVersion, ParityProtocolVersion, ProtocolId},
 };
 use rlp::{DecoderError, Rlp, RlpStream};
-use std::{collections::HashMap, str::FromStr};
+use std::collections::HashMap;
 
-use ethereum_forkid::{ForkFilter, ForkId};
+use ethereum_forkid::{ForkFilter, ForkId, RejectReason};
 use primitive_types::{H256, U256};
 
 #[derive(Debug, Clone)]
 pub struct Handshake {
     pub peers: HashMap<PeerId, (TaskId, PeerCapability)>,
     // field bellow are needed for creating and verifying status msg
-    pub network_id: u64, //it is hard coded in start
+    pub network_id: u64,
     pub genesis_hash: H256,
-    //pub fork_filter: ForkFilter,
+    pub fork_filter: ForkFilter,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -39,19 +42,27 @@ pub struct HandshakeInfo {
 }
 
 impl Handshake {
-    pub fn new() -> Handshake {
+    /// `head` is our best block, fork id that we announce and accept depends on it.
+    pub fn new(chain_spec: &ChainSpec, head: u64) -> Handshake {
         Handshake {
             peers: HashMap::new(),
-            network_id: 1,
-            genesis_hash: H256::from_str(
-                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
-            )
-            .unwrap(),
-            //ForkFilter:: ForkFilter::fork_filter()
+            network_id: chain_spec.network_id,
+            genesis_hash: chain_spec.genesis_hash,
+            fork_filter: ForkFilter::new(
+                head,
+                chain_spec.genesis_hash,
+                chain_spec.forks.iter().copied(),
+            ),
         }
     }
 
+    /// Keeps fork filter in line with our best block.
+    pub fn set_head(&mut self, head: u64) {
+        self.fork_filter.set_head(head);
+    }
+
     fn encode_rlp_status_msg(
+        &self,
         status: &ClientStatus,
         protocol_version: u32,
         fork_ids: Option<ForkId>,
@@ -60,10 +71,10 @@ impl Handshake {
         let mut rlp = RlpStream::new();
         rlp.begin_unbounded_list();
         rlp.append(&protocol_version); //send protocol
-        rlp.append(&status.network_id); //network ID
+        rlp.append(&self.network_id); //network ID
         rlp.append(&status.total_difficulty);
         rlp.append(&status.highest_block.1);
-        rlp.append(&status.genesis_block_hash);
+        rlp.append(&self.genesis_hash);
 
         if let Some(fork_id) = fork_ids {
             //protocol
@@ -118,6 +129,19 @@ impl Handshake {
         })
     }
 
+    /// Highest eth version that both we and peer support.
+    fn negotiated_eth_version(capability: &PeerCapability) -> u8 {
+        let supported = [
+            EthProtocolVersion::VERSION_66.to_number(),
+            EthProtocolVersion::VERSION_65.to_number(),
+        ];
+        capability
+            .get(&ProtocolId::Eth)
+            .and_then(|versions| supported.iter().find(|ver| versions.contains(ver)))
+            .copied()
+            .unwrap_or_else(|| EthProtocolVersion::VERSION_64.to_number())
+    }
+
     pub fn connect_and_create_status_message(
         &mut self,
         peer: &PeerId,
@@ -134,7 +158,7 @@ impl Handshake {
                 .find(|&ver| *ver >= EthProtocolVersion::VERSION_64.to_number())
                 .is_some()
             {
-                fork_id = Some(status.fork);
+                fork_id = Some(self.fork_filter.current());
             }
         };
         let mut snap_manifest = None;
@@ -145,9 +169,9 @@ impl Handshake {
             }
         }
 
-        Self::encode_rlp_status_msg(
+        self.encode_rlp_status_msg(
             status,
-            EthProtocolVersion::VERSION_64.to_number() as u32,
+            Self::negotiated_eth_version(capability) as u32,
             fork_id,
             snap_manifest,
         )
@@ -158,12 +182,22 @@ impl Handshake {
             ErrorAct::new_kick("Unsupported Eth version".into())?
         }
         if hi.genesis_hash != self.genesis_hash {
-            ErrorAct::new_kick("Genesis hash is different".into())?
+            ErrorAct::new_incompatible("Genesis hash is different".into())?
         }
         if hi.network_id != self.network_id {
-            ErrorAct::new_kick("Network id is different".into())?
+            ErrorAct::new_incompatible("Network id is different".into())?
+        }
+        if let Some(fork_id) = hi.fork_id {
+            match self.fork_filter.is_compatible(fork_id) {
+                Ok(()) => (),
+                Err(RejectReason::RemoteStale) => {
+                    ErrorAct::new_incompatible("Incompatible fork id, peer is stale".into())?
+                }
+                Err(RejectReason::LocalIncompatibleOrStale) => ErrorAct::new_incompatible(
+                    "Incompatible fork id, peer is on other chain or we are stale".into(),
+                )?,
+            }
         }
-        //TODO self.fork_filter.is_compatible(hi.fork_id)?
 
         Ok(())
     }
@@ -174,6 +208,11 @@ impl Handshake {
                 Ok(mut hi) => {
                     hi.peer_id = *peer;
                     self.verify_status(&hi)?;
+                    // peer can't use newer version then the one we announced
+                    hi.eth_protocol_version = std::cmp::min(
+                        hi.eth_protocol_version,
+                        Self::negotiated_eth_version(&capability),
+                    );
                     return Ok(Task::InsertPeer(hi));
                 }
                 Err(err) => ErrorAct::new_kick(format!("Handshake error:{:?}", err))?,
@@ -188,3 +227,60 @@ impl Handshake {
             .and_then(|(task_id, _)| Some(task_id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_forkid::ForkHash;
+
+    fn status_from(handshake: &Handshake, fork_id: ForkId) -> HandshakeInfo {
+        HandshakeInfo {
+            peer_id: 1,
+            eth_protocol_version: EthProtocolVersion::VERSION_65.to_version_byte(),
+            genesis_hash: handshake.genesis_hash,
+            network_id: handshake.network_id,
+            latest_hash: H256::zero(),
+            total_difficulty: None,
+            fork_id: Some(fork_id),
+            snapshot: None,
+        }
+    }
+
+    #[test]
+    fn test_announces_mainnet_fork_id() {
+        let handshake = Handshake::new(&ChainSpec::mainnet(), 0);
+        assert_eq!(
+            handshake.fork_filter.current(),
+            ForkId { hash: ForkHash(0xfc64ec04), next: 1_150_000 }
+        );
+    }
+
+    #[test]
+    fn test_accepts_compatible_fork_id() {
+        let handshake = Handshake::new(&ChainSpec::mainnet(), 15_100_000);
+        let fork_id = handshake.fork_filter.current();
+        assert!(handshake.verify_status(&status_from(&handshake, fork_id)).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_incompatible_fork_id() {
+        let mut handshake = Handshake::new(&ChainSpec::mainnet(), 0);
+        let fork_id = ForkId { hash: ForkHash(0xdeadbeef), next: 0 };
+        let err = handshake.verify_status(&status_from(&handshake, fork_id)).unwrap_err();
+        assert_eq!(err.penal(), PeerPenal::IncompatibleChain);
+        // peer stuck before a fork that we already passed
+        handshake.set_head(15_100_000);
+        let stale = ForkId { hash: ForkHash(0xfc64ec04), next: 0 };
+        let err = handshake.verify_status(&status_from(&handshake, stale)).unwrap_err();
+        assert_eq!(err.penal(), PeerPenal::IncompatibleChain);
+    }
+
+    #[test]
+    fn test_other_genesis_is_incompatible() {
+        let handshake = Handshake::new(&ChainSpec::mainnet(), 0);
+        let mut status = status_from(&handshake, handshake.fork_filter.current());
+        status.genesis_hash = H256::repeat_byte(1);
+        let err = handshake.verify_status(&status).unwrap_err();
+        assert_eq!(err.penal(), PeerPenal::IncompatibleChain);
+    }
+}
@@ -1,10 +1,20 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(feature = "tokio")]
+pub mod async_runtime;
+pub mod clock;
 mod handshake;
 pub mod scheduler;
+pub mod msgrate;
 pub mod peer_organizer;
+pub mod peer_store;
+pub mod propagation;
 pub mod protocol;
+pub mod reputation;
+pub mod timeouts;
 
 pub use scheduler::Scheduler;
 pub use peer_organizer::PeerOrganizer;
+#[cfg(feature = "tokio")]
+pub use async_runtime::{AsyncDevp2pAdapter, AsyncScheduler};
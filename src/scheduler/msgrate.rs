@@ -0,0 +1,124 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::protocol::MessageId;
+use std::{collections::HashMap, time::Duration};
+
+/// Weight of new measurement in moving average of throughput.
+const MEASUREMENT_IMPACT: f64 = 0.1;
+/// Requests are sized so that peer can answer them in this time.
+pub const TARGET_RTT: Duration = Duration::from_secs(3);
+
+/// Throughput of peer in items per second for every request type, similar to geth's msgrate.
+/// It is measured from time between request and response and number of delivered items.
+#[derive(Debug, Clone, Default)]
+pub struct MsgRate {
+    throughput: HashMap<MessageId, f64>,
+}
+
+impl MsgRate {
+    /// Rate for new peer, it starts with mean throughput of `rates` so that it gets fair share of work.
+    pub fn mean<'a, I: Iterator<Item = &'a MsgRate>>(rates: I) -> MsgRate {
+        let mut sums: HashMap<MessageId, (f64, usize)> = HashMap::new();
+        for rate in rates {
+            for (message_id, throughput) in rate.throughput.iter() {
+                let sum = sums.entry(*message_id).or_insert((0.0, 0));
+                sum.0 += throughput;
+                sum.1 += 1;
+            }
+        }
+        MsgRate {
+            throughput: sums
+                .into_iter()
+                .map(|(message_id, (sum, count))| (message_id, sum / count as f64))
+                .collect(),
+        }
+    }
+
+    /// Adds measurement of request that was answered with `delivered` items after `elapsed`.
+    /// Timeouted requests are counted as zero items delivered.
+    pub fn update(&mut self, message_id: &MessageId, delivered: u64, elapsed: Duration) {
+        let elapsed = std::cmp::max(elapsed, Duration::from_millis(1));
+        let measured = delivered as f64 / elapsed.as_secs_f64();
+        let throughput = self
+            .throughput
+            .entry(*message_id)
+            .and_modify(|throughput| {
+                *throughput = (1.0 - MEASUREMENT_IMPACT) * *throughput + MEASUREMENT_IMPACT * measured
+            })
+            .or_insert(measured);
+        trace!("Throughput of {:?} is {:.1} items/s", message_id, throughput);
+    }
+
+    /// Items per second, None if request was not measured yet.
+    pub fn throughput(&self, message_id: &MessageId) -> Option<f64> {
+        self.throughput.get(message_id).copied()
+    }
+
+    /// Number of items that peer should be asked for in one request, between 1 and `max`.
+    /// Peer that was not measured yet gets `max`.
+    pub fn capacity(&self, message_id: &MessageId, max: u64) -> u64 {
+        match self.throughput(message_id) {
+            Some(throughput) => {
+                let capacity = 1.0 + throughput * TARGET_RTT.as_secs_f64();
+                std::cmp::min(capacity as u64, max).max(1)
+            }
+            None => max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::protocol::EthMessageId;
+
+    const HEADERS: MessageId = MessageId::Eth(EthMessageId::GetBlockHeaders);
+    const BODIES: MessageId = MessageId::Eth(EthMessageId::GetBlockBodies);
+
+    #[test]
+    fn test_unmeasured_peer_gets_max() {
+        let rate = MsgRate::default();
+        assert_eq!(rate.throughput(&HEADERS), None);
+        assert_eq!(rate.capacity(&HEADERS, 192), 192);
+    }
+
+    #[test]
+    fn test_capacity_follows_throughput() {
+        let mut rate = MsgRate::default();
+        rate.update(&BODIES, 10, Duration::from_secs(2));
+        assert_eq!(rate.throughput(&BODIES), Some(5.0));
+        assert_eq!(rate.capacity(&BODIES, 128), 16);
+        // other requests are measured separately
+        assert_eq!(rate.capacity(&HEADERS, 192), 192);
+
+        for _ in 0..100 {
+            rate.update(&BODIES, 128, Duration::from_millis(500));
+        }
+        assert_eq!(rate.capacity(&BODIES, 128), 128);
+    }
+
+    #[test]
+    fn test_timeouts_shrink_capacity() {
+        let mut rate = MsgRate::default();
+        rate.update(&HEADERS, 192, Duration::from_secs(1));
+        for _ in 0..100 {
+            rate.update(&HEADERS, 0, Duration::from_secs(5));
+        }
+        assert_eq!(rate.capacity(&HEADERS, 192), 1);
+    }
+
+    #[test]
+    fn test_new_peer_starts_with_mean() {
+        let mut fast = MsgRate::default();
+        fast.update(&HEADERS, 300, Duration::from_secs(1));
+        let mut slow = MsgRate::default();
+        slow.update(&HEADERS, 100, Duration::from_secs(1));
+        slow.update(&BODIES, 10, Duration::from_secs(1));
+
+        let rate = MsgRate::mean([fast, slow].iter());
+        assert_eq!(rate.throughput(&HEADERS), Some(200.0));
+        assert_eq!(rate.throughput(&BODIES), Some(10.0));
+        assert_eq!(MsgRate::mean(std::iter::empty()).throughput(&HEADERS), None);
+    }
+}
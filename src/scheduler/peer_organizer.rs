@@ -1,13 +1,18 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::protocol::{EthMessageId, MessageId, ProtocolId};
+use super::clock::Clock;
+use super::protocol::{encode_with_request_id, EthMessageId, EthProtocolVersion, MessageId, ProtocolId};
 use super::handshake::HandshakeInfo;
-use crate::devp2p_adapter::{adapter::Devp2pAdapter, PeerPenal};
+use super::msgrate::MsgRate;
+use super::peer_store::{PeerBook, PeerStore};
+use super::reputation::{Reputation, BAN_DURATION};
+use super::timeouts::{RttEstimator, DEFAULT_LATENCY};
+use crate::devp2p_adapter::{adapter::Devp2pAdapter, NodeId, PeerPenal};
 use std::{
     collections::{HashMap, HashSet},
     sync::{atomic::AtomicUsize, Arc, Mutex},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 #[derive(Debug)]
@@ -31,13 +36,21 @@ impl std::fmt::Display for CustomError {
 
 #[derive(Debug)]
 pub struct InitialRequest {
-    pub message_id: EthMessageId,
+    pub task_id: TaskId,
+    pub message_id: MessageId,
     pub data: MessageData,
+    /// Number of requested items, bigger requests get more time to be answered.
+    pub items: u64,
 }
 
 impl InitialRequest {
-    pub fn new(message_id:EthMessageId, data: MessageData) -> Self {
-        InitialRequest { message_id, data }
+    pub fn new(message_id: MessageId, data: MessageData) -> Self {
+        InitialRequest { task_id: Task::new_id(), message_id, data, items: 1 }
+    }
+
+    pub fn with_items(mut self, items: u64) -> Self {
+        self.items = items;
+        self
     }
 }
 
@@ -46,7 +59,7 @@ pub enum Task {
     InsertPeer(HandshakeInfo),
     PenalPeer(PeerId, PeerPenal, String), //last is reason
     WaitForStatus(PeerId, MessageData),
-    InitialRequest(PeerId, EthMessageId, MessageData),
+    InitialRequest(PeerId, MessageId, MessageData),
     Responde(PeerId, ProtocolId, MessageId, Vec<u8>),
     None,
 }
@@ -79,6 +92,32 @@ impl ErrorAct {
         })
     }
 
+    pub fn new_invalid(reason: String) -> Result<(), ErrorAct> {
+        Self::new_invalid_generic(reason)
+    }
+
+    /// Peer is on other network or chain, see `PeerPenal::IncompatibleChain`.
+    pub fn new_incompatible(reason: String) -> Result<(), ErrorAct> {
+        Err(ErrorAct {
+            penal: PeerPenal::IncompatibleChain,
+            reason,
+        })
+    }
+
+    pub fn new_invalid_generic<T>(reason: String) -> Result<T, ErrorAct> {
+        Err(ErrorAct {
+            penal: PeerPenal::InvalidData,
+            reason,
+        })
+    }
+
+    pub fn new_useless<T>(reason: String) -> Result<T, ErrorAct> {
+        Err(ErrorAct {
+            penal: PeerPenal::UselessResponse,
+            reason,
+        })
+    }
+
     pub fn penal(&self) -> PeerPenal {
         self.penal
     }
@@ -96,6 +135,11 @@ pub enum TaskType {
     None,
 }
 
+/// Max number of requests in flight to one eth/66 peer. Older peers can have only one.
+pub const MAX_REQUESTS_PER_PEER: usize = 4;
+/// How many times request is sent to other peer before it is reported as failed.
+pub const MAX_REQUEST_RETRIES: usize = 2;
+
 static GLOBAL_TASK_ID: AtomicUsize = AtomicUsize::new(1);
 
 impl Task {
@@ -130,7 +174,7 @@ impl Task {
             Self::InsertPeer(_) => 0,
             Self::PenalPeer(_, _, _) => 0,
             Self::WaitForStatus(_, _) => 0,
-            Self::InitialRequest(_, _, _) => 0,
+            Self::InitialRequest(_, _, _) => MAX_REQUEST_RETRIES,
             Self::Responde(_, _, _, _) => 0,
             Self::None => 0,
         }
@@ -140,7 +184,7 @@ impl Task {
         match self {
             Self::InsertPeer(_) => None,
             Self::PenalPeer(_, _, _) => None,
-            Self::InitialRequest(_, _, _) => None,
+            Self::InitialRequest(_, _, _) => Some(DEFAULT_LATENCY), // scheduled requests get timeout adapted to peer
             Self::Responde(_, _, _, _) => None,
             Self::WaitForStatus(_, _) => Some(Duration::from_millis(3000)), //timeout after not receiving status msg from peer
             Self::None => None,
@@ -151,20 +195,41 @@ impl Task {
         GLOBAL_TASK_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
 }
+/// Request that response answers. Throughput of peer is updated with `PeerOrganizer::delivered`,
+/// after response is processed and it is known how many of its items were accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Answered {
+    pub task_id: TaskId,
+    // request message and how long peer took to answer it
+    request: Option<(MessageId, Duration)>,
+}
+
 #[derive(Debug)]
 pub struct TaskWrapper {
     task: Task,
     retries: usize, // retrie by sending task to different peer
     timestamp: Instant,
+    // overrides timelimit of task
+    timelimit: Option<Duration>,
+    // original request, it is sent again when task is retried
+    request: Option<InitialRequest>,
+    // peers that task was sent to
+    tried: Vec<PeerId>,
+    // peer disconnected and task can't be answered anymore
+    expired: bool,
 }
 
 impl TaskWrapper {
-    pub fn new(task: Task) -> TaskWrapper {
+    pub fn new(task: Task, now: Instant) -> TaskWrapper {
         let max_retries = task.max_retries();
         TaskWrapper {
             task,
             retries: max_retries,
-            timestamp: Instant::now(),
+            timestamp: now,
+            timelimit: None,
+            request: None,
+            tried: Vec::new(),
+            expired: false,
         }
     }
 
@@ -178,7 +243,10 @@ impl TaskWrapper {
     }
 
     pub fn timeouted(&self, now: &Instant) -> bool {
-        match self.task.timelimit() {
+        if self.expired {
+            return true;
+        }
+        match self.timelimit.or_else(|| self.task.timelimit()) {
             Some(timelimit) => self.timestamp + timelimit < *now,
             None => false,
         }
@@ -197,12 +265,43 @@ pub struct Peer {
     peer_id: PeerId,
     info: PeerInfo,
     tasks: HashSet<TaskId>,
+    eth_protocol_version: u8,
+    rtt: RttEstimator,
+    rate: MsgRate,
+}
+
+impl Peer {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn eth_protocol_version(&self) -> u8 {
+        self.eth_protocol_version
+    }
+
+    pub fn has_request_id(&self) -> bool {
+        EthProtocolVersion::has_request_id(self.eth_protocol_version)
+    }
+
+    pub fn rate(&self) -> &MsgRate {
+        &self.rate
+    }
+
+    fn max_requests(&self) -> usize {
+        if self.has_request_id() {
+            MAX_REQUESTS_PER_PEER
+        } else {
+            1
+        }
+    }
 }
 
 // TODO expend this to cover all needed information fields
 // all field here should be one that are persistent.
 pub struct PeerInfo {
     network_id: u64,
+    /// Key under which peer reputation is persisted.
+    node_id: Option<NodeId>,
 }
 
 impl From<HandshakeInfo> for Peer {
@@ -210,8 +309,12 @@ impl From<HandshakeInfo> for Peer {
         Peer {
             peer_id: hi.peer_id,
             tasks: HashSet::new(),
+            eth_protocol_version: hi.eth_protocol_version,
+            rtt: RttEstimator::default(),
+            rate: MsgRate::default(),
             info: PeerInfo {
                 network_id: hi.network_id,
+                node_id: None,
             },
         }
     }
@@ -219,7 +322,10 @@ impl From<HandshakeInfo> for Peer {
 pub struct PeerOrganizer {
     peers: HashMap<PeerId, Peer>,
     pending_tasks: HashMap<TaskId, TaskWrapper>,
+    reputation: Reputation,
+    peer_book: PeerBook,
     devp2p: Arc<Box<dyn Devp2pAdapter>>,
+    clock: Clock,
 }
 
 impl PeerOrganizer {
@@ -227,36 +333,150 @@ impl PeerOrganizer {
         &self.peers
     }
 
-    fn free_peer(&self) -> Option<PeerId> {
-        for peer in self.peers.keys() {
-            let peer_tasks = self.peers.get(peer).unwrap().tasks.len();
-            if peer_tasks == 0 {
-                return Some(*peer);
-            }
+    /// Peers that can take another request, best scored first.
+    pub fn free_peers(&self) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self
+            .peers
+            .values()
+            .filter(|peer| peer.tasks.len() < peer.max_requests())
+            .map(|peer| peer.peer_id)
+            .collect();
+        peers.sort_by_key(|peer| (std::cmp::Reverse(self.reputation.score(peer)), *peer));
+        peers
+    }
+
+    /// Peers that can take another request, fastest in serving `message_id` first.
+    /// Peers with the same throughput are ordered by score.
+    pub fn free_peers_by_rate(&self, message_id: &MessageId) -> Vec<PeerId> {
+        let mut peers = self.free_peers();
+        let throughput = |peer: &PeerId| self.peers[peer].rate.throughput(message_id).unwrap_or(0.0);
+        // sort is stable, so order by score is kept for equal throughput
+        peers.sort_by(|a, b| throughput(b).total_cmp(&throughput(a)));
+        peers
+    }
+
+    /// Free peer that serves `message_id` the fastest.
+    pub fn best_peer(&self, message_id: &MessageId) -> Option<PeerId> {
+        self.free_peers_by_rate(message_id).into_iter().next()
+    }
+
+    /// Measured throughput of peer, None if peer is unknown.
+    pub fn rate(&self, peer_id: &PeerId) -> Option<&MsgRate> {
+        self.peers.get(peer_id).map(Peer::rate)
+    }
+
+    pub fn reputation(&self) -> &Reputation {
+        &self.reputation
+    }
+
+    /// Banned nodes with time when their ban expires.
+    pub fn bans(&self) -> Vec<(NodeId, SystemTime)> {
+        self.peer_book.bans()
+    }
+
+    /// Bans node for `duration`, it is disconnected if connected.
+    pub fn ban(&mut self, node_id: NodeId, duration: Duration) {
+        self.peer_book.ban(&node_id, self.clock.system_now() + duration);
+        let connected: Vec<PeerId> = self
+            .peers
+            .values()
+            .filter(|peer| peer.info.node_id == Some(node_id))
+            .map(|peer| peer.peer_id)
+            .collect();
+        for peer_id in connected {
+            info!("Peer {} banned manually", peer_id);
+            self.disconnect(&peer_id);
+            self.devp2p.penalize_peer(&peer_id, PeerPenal::Ban);
         }
-        None
     }
 
-    pub fn schedule_to_free_peer(&mut self, request: InitialRequest) {
-        if let Some(ref peer_id) = self.free_peer() {
-            let task = Task::InitialRequest(*peer_id, request.message_id, request.data);
-            let task_id = Task::new_id();
-            let peers_tasks = &mut self.peers.get_mut(peer_id).unwrap().tasks;
-            peers_tasks.insert(task_id);
-            self.push_task(task, Some(task_id));
-        } else {
-            info!("No free peer to schedule task {:?} to", &request);
+    /// Returns false if node was not banned.
+    pub fn unban(&mut self, node_id: &NodeId) -> bool {
+        self.peer_book.unban(node_id)
+    }
+
+    fn node_id(&self, peer_id: &PeerId) -> Option<NodeId> {
+        match self.peers.get(peer_id) {
+            Some(peer) => peer.info.node_id,
+            None => self.devp2p.node_id(peer_id),
         }
+    }
 
+    /// Refuses connection of banned peer. Returns true if peer was banned.
+    pub fn reject_if_banned(&self, peer_id: &PeerId) -> bool {
+        let banned_node = self
+            .node_id(peer_id)
+            .is_some_and(|node_id| self.peer_book.is_banned(&node_id, self.clock.system_now()));
+        if !banned_node && !self.reputation.is_banned(peer_id, self.node_id(peer_id), self.clock.now()) {
+            return false;
+        }
+        debug!("Banned peer {} tried to connect", peer_id);
+        self.devp2p.penalize_peer(peer_id, PeerPenal::Ban);
+        true
     }
 
-    pub fn random_peer(&self) -> Option<PeerId> {
-        match self.peers.keys().next() {
-            Some(peer) => Some(*peer),
-            None => None,
+    pub fn is_free(&self, peer_id: &PeerId) -> bool {
+        match self.peers.get(peer_id) {
+            Some(peer) => peer.tasks.len() < peer.max_requests(),
+            None => false,
         }
     }
 
+    /// Whether messages from peer are wrapped with eth/66 request id. None if peer is unknown.
+    pub fn has_request_id(&self, peer_id: &PeerId) -> Option<bool> {
+        self.peers.get(peer_id).map(Peer::has_request_id)
+    }
+
+    pub fn schedule_request(&mut self, peer_id: &PeerId, request: InitialRequest) {
+        if !self.peers.contains_key(peer_id) {
+            info!("Peer {} is gone, can't schedule task {:?} to it", peer_id, &request);
+            return;
+        }
+        let wrapper = TaskWrapper::new(Task::InitialRequest(*peer_id, request.message_id, Vec::new()), self.clock.now());
+        self.send_request(peer_id, request, wrapper);
+    }
+
+    /// Sends request to connected peer, wrapped with request id for eth/66 peers.
+    /// Original request is kept in `wrapper` so that it can be sent again to other peer.
+    fn send_request(&mut self, peer_id: &PeerId, request: InitialRequest, mut wrapper: TaskWrapper) {
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.tasks.insert(request.task_id);
+        let data = if peer.has_request_id() && request.message_id.protocol() == ProtocolId::Eth {
+            encode_with_request_id(request.task_id as u64, &request.data)
+        } else {
+            request.data.clone()
+        };
+        self.devp2p
+            .send_mesage(request.message_id.protocol(), peer_id, request.message_id.to_u8(), &data);
+        wrapper.task = Task::InitialRequest(*peer_id, request.message_id, Vec::new());
+        wrapper.timestamp = self.clock.now();
+        wrapper.timelimit = Some(peer.rtt.request_timeout(&request.message_id, request.items));
+        wrapper.expired = false;
+        wrapper.tried.push(*peer_id);
+        let task_id = request.task_id;
+        wrapper.request = Some(request);
+        self.pending_tasks.insert(task_id, wrapper);
+    }
+
+    /// Free peer that request was not sent to yet and that `accept` agrees can serve it.
+    fn retry_peer<F>(&self, task_id: &TaskId, wrapper: &mut TaskWrapper, accept: &mut F) -> Option<PeerId>
+    where
+        F: FnMut(&TaskId, &PeerId) -> bool,
+    {
+        let message_id = wrapper.request.as_ref()?.message_id;
+        if !wrapper.retry() {
+            return None;
+        }
+        let tried = &wrapper.tried;
+        self.free_peers_by_rate(&message_id)
+            .into_iter()
+            .filter(|peer| !tried.contains(peer))
+            .find(|peer| accept(task_id, peer))
+    }
+
     pub fn schedule(&mut self, task: Task) {
         let task_id = Task::new_id();
         let peer_id = &task.peer_id().unwrap();
@@ -267,16 +487,33 @@ impl PeerOrganizer {
         self.push_task(task, Some(task_id));
     }
 
-    pub fn new(devp2p: Arc<Box<dyn Devp2pAdapter>>) -> Arc<Mutex<PeerOrganizer>> {
+    /// Reputation and bans of nodes are loaded from `peer_store` and saved to it.
+    pub fn new(devp2p: Arc<Box<dyn Devp2pAdapter>>, peer_store: Box<dyn PeerStore>) -> Arc<Mutex<PeerOrganizer>> {
+        Self::with_clock(devp2p, peer_store, Clock::new())
+    }
+
+    /// Organizer whose timeouts and bans follow `clock`.
+    pub fn with_clock(
+        devp2p: Arc<Box<dyn Devp2pAdapter>>,
+        peer_store: Box<dyn PeerStore>,
+        clock: Clock,
+    ) -> Arc<Mutex<PeerOrganizer>> {
         let peer_org = Arc::new(Mutex::new(PeerOrganizer {
             peers: HashMap::new(),
             pending_tasks: HashMap::new(),
+            reputation: Reputation::new(),
+            peer_book: PeerBook::new(peer_store),
             devp2p,
+            clock,
         }));
 
         peer_org
     }
 
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
     pub fn start(&self) {
         self.devp2p.start();
     }
@@ -285,56 +522,113 @@ impl PeerOrganizer {
         self.devp2p.stop();
     }
 
-    pub fn tick(&mut self) -> Vec<Task> {
-        let now = Instant::now();
-        let mut timeouted_tasks = Vec::new();
-        let mut rem_ids = Vec::new();
-        for (id, task) in self.pending_tasks.iter_mut() {
-            //check timeout, and if timeouted call organizer to notify managers that request task was not successfull
-            if task.timeouted(&now) {
-                //disconnect particular peer, nulify all its pending tasks and retry all his pending tasks.
+    /// Handles tasks that timed out or whose peer disconnected. Requests are sent again to other
+    /// free peer that `accept` agrees on, owning manager should move the request to that peer
+    /// when it returns true. Tasks that could not be retried are returned as failed.
+    pub fn tick<F>(&mut self, mut accept: F) -> Vec<(TaskId, Task)>
+    where
+        F: FnMut(&TaskId, &PeerId) -> bool,
+    {
+        let now = self.clock.now();
+        self.reputation.tick(now);
+        self.peer_book.prune(self.clock.system_now());
+        let expired: Vec<TaskId> = self
+            .pending_tasks
+            .iter()
+            .filter(|(_, task)| task.timeouted(&now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut failed_tasks = Vec::new();
+        for task_id in expired {
+            let mut wrapper = match self.pending_tasks.remove(&task_id) {
+                Some(wrapper) => wrapper,
+                None => continue,
+            };
+            if let Task::InitialRequest(peer_id, message_id, _) = wrapper.task {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.tasks.remove(&task_id);
+                    // nothing delivered in time, peer gets smaller requests
+                    peer.rate.update(&message_id, 0, self.clock.elapsed(wrapper.timestamp));
+                    self.penalize(&peer_id, PeerPenal::Timeout, "Request timeouted");
+                }
+            }
+            match self.retry_peer(&task_id, &mut wrapper, &mut accept) {
+                Some(peer_id) => {
+                    debug!("Task {} is retried on peer {}", task_id, peer_id);
+                    let request = wrapper.request.take().expect("retried task has request");
+                    self.send_request(&peer_id, request, wrapper);
+                }
+                None => failed_tasks.push((task_id, wrapper.task)),
+            }
+        }
+        failed_tasks
+    }
 
-                // if we cant retry tasks send them to failed_task array.
-                timeouted_tasks.push(task.task.clone());
-                rem_ids.push(id.clone());
+    /// Checks if eth/66 response with `task_id` as request id is expected from peer.
+    /// Returns None if task is unknown, belongs to other peer or was asked with different message.
+    pub fn check_response_with_request_id(
+        &mut self,
+        peer: &PeerId,
+        message_id: EthMessageId,
+        task_id: &TaskId,
+    ) -> Option<Answered> {
+        let expected = match self.pending_tasks.get(task_id) {
+            Some(TaskWrapper { task: Task::InitialRequest(task_peer, request_id, _), .. }) => {
+                task_peer == peer && request_id.response_id() == Some(MessageId::Eth(message_id))
+            }
+            _ => false,
+        };
+        if !expected {
+            return None;
+        }
+        Some(self.response_received(peer, task_id))
+    }
+
+    /// Removes answered task and updates round trip time of peer.
+    fn response_received(&mut self, peer_id: &PeerId, task_id: &TaskId) -> Answered {
+        let wrapper = self.pending_tasks.remove(task_id);
+        let mut answered = Answered { task_id: *task_id, request: None };
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.tasks.remove(task_id);
+            if let Some(TaskWrapper { request: Some(request), timestamp, .. }) = wrapper {
+                let elapsed = self.clock.elapsed(timestamp);
+                peer.rtt.response(&request.message_id, request.items, elapsed);
+                answered.request = Some((request.message_id, elapsed));
             }
         }
+        trace!("peers:{} task_id:{} removed", peer_id, task_id);
+        answered
+    }
 
-        for rem_id in rem_ids {
-            self.pending_tasks.remove(&rem_id);
+    /// Measures throughput of peer by `delivered` items that were accepted from its response.
+    pub fn delivered(&mut self, peer_id: &PeerId, answered: &Answered, delivered: u64) {
+        if let (Some(peer), Some((message_id, elapsed))) = (self.peers.get_mut(peer_id), answered.request) {
+            peer.rate.update(&message_id, delivered, elapsed);
         }
-        timeouted_tasks
     }
 
-    // Checks if response is expected. This related to older <eth/65 protocols without requests_id,
-    // It is expected for peer to have only one pending task
-    pub fn check_response(&mut self, peer: &PeerId, _message_id: MessageId) -> bool {
-        let task_id = match self.peers.get_mut(peer) {
-            Some(peer) => {
-                // expects only one task for older protocol
-                if peer.tasks.len() != 1 {
-                    //disconnect
-                    return false;
-                }
-                peer.tasks.drain().next().unwrap()
-            }
-            None => {
-                return false;
-            }
-        };
+    /// Raises score of connected peer whose response passed validation.
+    pub fn reward(&mut self, peer_id: &PeerId) {
+        if self.peers.contains_key(peer_id) {
+            self.reputation.reward(peer_id);
+        }
+    }
 
-        trace!("peers:{} task_id:{} removed", peer, task_id);
-        match self.pending_tasks.remove(&task_id) {
-            Some(_) => {
-                // TODO check if task has same message_id that is expected
-                // with message_id
-                return true;
-            }
-            None => {
-                error!("Unexpected thing happened, peer tasks should be present in pending_tasks");
-                return false;
+    // Checks if response is expected. This related to older <eth/66 protocols and parity protocol without requests_id.
+    // Response answers pending request of the peer that expects this message id. Returns the request that response answers.
+    pub fn check_response(&mut self, peer: &PeerId, message_id: MessageId) -> Option<Answered> {
+        let peer_tasks = &mut self.peers.get_mut(peer)?.tasks;
+        let pending_tasks = &self.pending_tasks;
+        let task_id = peer_tasks.iter().copied().find(|task_id| {
+            match pending_tasks.get(task_id) {
+                Some(TaskWrapper { task: Task::InitialRequest(_, request_id, _), .. }) => {
+                    request_id.response_id() == Some(message_id)
+                }
+                _ => false,
             }
-        }
+        })?;
+        Some(self.response_received(peer, &task_id))
     }
 
     /// check if this message is expected response from peer.
@@ -381,13 +675,17 @@ impl PeerOrganizer {
         let task_id = match task {
             Task::InsertPeer(hi) => {
                 info!("Peer inserted: {:?}", task);
-                self.peers.insert(hi.peer_id, Peer::from(hi));
+                let mut peer = Peer::from(hi);
+                peer.rate = MsgRate::mean(self.peers.values().map(Peer::rate));
+                peer.info.node_id = self.devp2p.node_id(&hi.peer_id);
+                if let Some(score) = peer.info.node_id.and_then(|node_id| self.peer_book.score(&node_id)) {
+                    self.reputation.set_score(&hi.peer_id, score);
+                }
+                self.peers.insert(hi.peer_id, peer);
                 None
             }
-            Task::PenalPeer(peer, _penal, ref reason) => {
-                debug!("Peer penalized. Reason:{}", reason);
-                self.disconnect(&peer); // for now just disconnect all peers
-
+            Task::PenalPeer(peer, penal, ref reason) => {
+                self.penalize(&peer, penal, reason);
                 None
             }
             Task::WaitForStatus(ref peer, ref mut data) => {
@@ -401,7 +699,7 @@ impl PeerOrganizer {
             }
             Task::InitialRequest(ref peer, ref message_id, ref mut data) => {
                 self.devp2p
-                    .send_mesage(ProtocolId::Eth, peer, *message_id as u8, &data);
+                    .send_mesage(message_id.protocol(), peer, message_id.to_u8(), &data);
                 data.clear();
                 if task_id.is_none() {
                     panic!("Task id should be set for InitialRequest msg");
@@ -421,7 +719,7 @@ impl PeerOrganizer {
         };
         if let Some(task_id) = task_id {
             self.pending_tasks
-                .insert(task_id, TaskWrapper::new(task.clone()));
+                .insert(task_id, TaskWrapper::new(task.clone(), self.clock.now()));
         }
         task_id
     }
@@ -430,18 +728,266 @@ impl PeerOrganizer {
         self.pending_tasks.remove(task_id);
     }
 
-    //disconnect peer.
+    fn penalize(&mut self, peer_id: &PeerId, penal: PeerPenal, reason: &str) {
+        let soft = !matches!(penal, PeerPenal::Kick | PeerPenal::Ban | PeerPenal::IncompatibleChain);
+        if soft && !self.peers.contains_key(peer_id) {
+            debug!("Penalty {:?} for gone peer {} ignored. Reason:{}", penal, peer_id, reason);
+            return;
+        }
+        let node_id = self.node_id(peer_id);
+        match self.reputation.penalize(peer_id, node_id, penal, self.clock.now()) {
+            Some(action) => {
+                if let (PeerPenal::Ban, Some(node_id)) = (action, node_id) {
+                    self.peer_book.ban(&node_id, self.clock.system_now() + BAN_DURATION);
+                }
+                debug!("Peer {} penalized with {:?}, disconnecting with {:?}. Reason:{}", peer_id, penal, action, reason);
+                self.disconnect(peer_id);
+                self.devp2p.penalize_peer(peer_id, action);
+            }
+            None => debug!(
+                "Peer {} penalized with {:?}, score {}. Reason:{}",
+                peer_id,
+                penal,
+                self.reputation.score(peer_id),
+                reason
+            ),
+        }
+    }
+
+    /// Forgets peer and its tasks. Devp2p is not notified, use `PenalPeer` task to disconnect peer.
     pub fn disconnect(&mut self, peer_id: &PeerId) {
         if let Some(peer) = self.peers.remove(peer_id) {
+            if let Some(node_id) = peer.info.node_id {
+                self.peer_book.set_score(&node_id, self.reputation.score(peer_id));
+            }
+            // tasks are retried on other peers or reported as failed in next tick
             for task_id in peer.tasks {
-                // should we remove task, or do retrasmision. Best way is to naturally timeout it! TODO.
-                // For now lets remove it
                 if let Some(task) = self.pending_tasks.get_mut(&task_id) {
-                    // sub timestamp with arbitrary big number. It should be bigger then biggest message timeout. 1000 does a job.
-                    task.timestamp = task.timestamp - Duration::from_secs(1000);
+                    task.expired = true;
                 }
             }
         }
-        self.devp2p.penalize_peer(peer_id, PeerPenal::Kick);
+        self.reputation.peer_disconnected(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devp2p_adapter::mock::MockAdapter;
+    use crate::scheduler::peer_store::{NoPeerStore, PeerRecord};
+    use crate::scheduler::reputation::INITIAL_SCORE;
+    use crate::scheduler::protocol::decode_request_id;
+    use primitive_types::H256;
+
+    const HEADERS: MessageId = MessageId::Eth(EthMessageId::GetBlockHeaders);
+
+    fn organizer() -> (MockAdapter, Arc<Mutex<PeerOrganizer>>) {
+        let adapter = MockAdapter::new();
+        let org = PeerOrganizer::new(Arc::new(Box::new(adapter.clone())), Box::new(NoPeerStore));
+        (adapter, org)
+    }
+
+    fn insert_peer(org: &mut PeerOrganizer, peer_id: PeerId, eth_protocol_version: u8) {
+        org.push_task(
+            Task::InsertPeer(HandshakeInfo {
+                peer_id,
+                eth_protocol_version,
+                genesis_hash: H256::zero(),
+                network_id: 1,
+                latest_hash: H256::zero(),
+                total_difficulty: None,
+                fork_id: None,
+                snapshot: None,
+            }),
+            None,
+        );
+    }
+
+    fn request() -> InitialRequest {
+        InitialRequest::new(HEADERS, vec![0xc0]).with_items(10)
+    }
+
+    fn expire(org: &mut PeerOrganizer, task_id: &TaskId) {
+        org.pending_tasks.get_mut(task_id).unwrap().timelimit = Some(Duration::from_secs(0));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_request_is_wrapped_with_request_id_for_eth66() {
+        let (adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        insert_peer(&mut org, 1, 66);
+        insert_peer(&mut org, 2, 65);
+
+        let first = request();
+        let first_id = first.task_id;
+        org.schedule_request(&1, first);
+        org.schedule_request(&2, request());
+        let sent = adapter.take_sent();
+        assert_eq!(sent.len(), 2);
+        let (request_id, payload) = decode_request_id(&sent[0].data).unwrap();
+        assert_eq!((request_id, payload), (first_id as u64, &[0xc0][..]));
+        assert_eq!(sent[1].data, vec![0xc0]);
+
+        // older peer can have only one request in flight
+        assert!(org.is_free(&1));
+        assert!(!org.is_free(&2));
+        assert_eq!(org.free_peers(), vec![1]);
+    }
+
+    #[test]
+    fn test_response_must_match_peer_and_message() {
+        let (_adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        insert_peer(&mut org, 1, 66);
+        insert_peer(&mut org, 2, 66);
+        let request = request();
+        let task_id = request.task_id;
+        org.schedule_request(&1, request);
+
+        assert!(org.check_response_with_request_id(&2, EthMessageId::BlockHeaders, &task_id).is_none());
+        assert!(org.check_response_with_request_id(&1, EthMessageId::BlockBodies, &task_id).is_none());
+        let answered = org.check_response_with_request_id(&1, EthMessageId::BlockHeaders, &task_id).unwrap();
+        assert!(org.check_response_with_request_id(&1, EthMessageId::BlockHeaders, &task_id).is_none());
+        // throughput is measured only after response is processed
+        assert!(org.rate(&1).unwrap().throughput(&HEADERS).is_none());
+        org.delivered(&1, &answered, 10);
+        assert!(org.rate(&1).unwrap().throughput(&HEADERS).is_some());
+        // response is rewarded only after it is validated
+        assert_eq!(org.reputation().score(&1), org.reputation().score(&2));
+        org.reward(&1);
+        assert!(org.reputation().score(&1) > org.reputation().score(&2));
+    }
+
+    #[test]
+    fn test_timeouted_request_is_retried_on_other_peer() {
+        let (adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        insert_peer(&mut org, 1, 66);
+        insert_peer(&mut org, 2, 66);
+        let request = request();
+        let task_id = request.task_id;
+        org.schedule_request(&1, request);
+        adapter.take_sent();
+
+        expire(&mut org, &task_id);
+        assert!(org.tick(|_, _| true).is_empty());
+        let sent = adapter.take_sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].peer, 2);
+        assert!(org.reputation().score(&1) < org.reputation().score(&2));
+        // timeout counts as nothing delivered
+        assert_eq!(org.rate(&1).unwrap().throughput(&HEADERS), Some(0.0));
+
+        // both peers were tried
+        expire(&mut org, &task_id);
+        let failed = org.tick(|_, _| true);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, task_id);
+        assert!(adapter.take_sent().is_empty());
+    }
+
+    #[test]
+    fn test_request_of_disconnected_peer_fails_if_not_moved() {
+        let (adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        insert_peer(&mut org, 1, 66);
+        insert_peer(&mut org, 2, 66);
+        let request = request();
+        let task_id = request.task_id;
+        org.schedule_request(&1, request);
+        adapter.take_sent();
+
+        org.disconnect(&1);
+        // manager refuses to move request
+        assert_eq!(org.tick(|_, _| false).len(), 1);
+        assert!(adapter.take_sent().is_empty());
+        assert!(!org.pending_tasks.contains_key(&task_id));
+        assert!(adapter.penalties().is_empty());
+    }
+
+    #[test]
+    fn test_peer_is_kicked_when_score_drops() {
+        let (adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        insert_peer(&mut org, 1, 66);
+        org.push_task(Task::PenalPeer(1, PeerPenal::InvalidData, "bad".into()), None);
+        assert!(org.peers().contains_key(&1));
+        org.push_task(Task::PenalPeer(1, PeerPenal::InvalidData, "bad".into()), None);
+        assert!(!org.peers().contains_key(&1));
+        assert_eq!(adapter.penalties(), vec![(1, PeerPenal::Kick)]);
+        // soft penalty of gone peer is ignored
+        org.push_task(Task::PenalPeer(1, PeerPenal::Timeout, "late".into()), None);
+        assert_eq!(adapter.penalties().len(), 1);
+    }
+
+    // store that test can look into
+    #[derive(Clone, Default)]
+    struct SharedStore(Arc<Mutex<Vec<PeerRecord>>>);
+
+    impl PeerStore for SharedStore {
+        fn load(&self) -> Vec<PeerRecord> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn save(&mut self, records: &[PeerRecord]) {
+            *self.0.lock().unwrap() = records.to_vec();
+        }
+    }
+
+    #[test]
+    fn test_kicked_node_keeps_low_score_under_new_peer_id() {
+        let adapter = MockAdapter::new();
+        let store = SharedStore::default();
+        let org = PeerOrganizer::new(Arc::new(Box::new(adapter.clone())), Box::new(store.clone()));
+        let mut org = org.lock().unwrap();
+        let node_id = NodeId::from_low_u64_be(7);
+        adapter.set_node_id(1, node_id);
+        insert_peer(&mut org, 1, 66);
+        org.push_task(Task::PenalPeer(1, PeerPenal::Kick, "bad".into()), None);
+        assert_eq!(adapter.penalties(), vec![(1, PeerPenal::Kick)]);
+        let saved = store.0.lock().unwrap().clone();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].score < INITIAL_SCORE);
+
+        adapter.set_node_id(2, node_id);
+        insert_peer(&mut org, 2, 66);
+        assert_eq!(org.reputation().score(&2), saved[0].score);
+    }
+
+    #[test]
+    fn test_banned_node_is_rejected_under_new_peer_id() {
+        let (adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        let node_id = NodeId::from_low_u64_be(7);
+        adapter.set_node_id(1, node_id);
+        insert_peer(&mut org, 1, 66);
+
+        org.ban(node_id, Duration::from_secs(60));
+        assert!(org.peers().is_empty());
+        assert_eq!(adapter.penalties(), vec![(1, PeerPenal::Ban)]);
+
+        adapter.set_node_id(2, node_id);
+        assert!(org.reject_if_banned(&2));
+        assert!(org.unban(&node_id));
+        assert!(!org.reject_if_banned(&2));
+    }
+
+    #[test]
+    fn test_fastest_peer_is_chosen_first() {
+        let (_adapter, org) = organizer();
+        let mut org = org.lock().unwrap();
+        for peer in 1..=3 {
+            insert_peer(&mut org, peer, 66);
+        }
+        org.peers.get_mut(&1).unwrap().rate.update(&HEADERS, 10, Duration::from_secs(1));
+        org.peers.get_mut(&3).unwrap().rate.update(&HEADERS, 100, Duration::from_secs(1));
+        assert_eq!(org.free_peers_by_rate(&HEADERS), vec![3, 1, 2]);
+        assert_eq!(org.best_peer(&HEADERS), Some(3));
+
+        // new peer starts as average one
+        insert_peer(&mut org, 4, 66);
+        assert_eq!(org.rate(&4).unwrap().throughput(&HEADERS), Some(55.0));
     }
 }
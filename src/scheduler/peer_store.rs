@@ -0,0 +1,255 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::reputation::INITIAL_SCORE;
+use crate::devp2p_adapter::NodeId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What we remember about node between restarts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerRecord {
+    pub node_id: NodeId,
+    pub score: i32,
+    /// Unix time in seconds when ban of node expires.
+    #[serde(default)]
+    pub banned_until: Option<u64>,
+}
+
+/// Storage of peer records. Errors are not propagated, store is only best effort.
+pub trait PeerStore: Send {
+    fn load(&self) -> Vec<PeerRecord>;
+    fn save(&mut self, records: &[PeerRecord]);
+}
+
+/// Store that keeps nothing, used when client does not provide one.
+#[derive(Debug, Default)]
+pub struct NoPeerStore;
+
+impl PeerStore for NoPeerStore {
+    fn load(&self) -> Vec<PeerRecord> {
+        Vec::new()
+    }
+
+    fn save(&mut self, _records: &[PeerRecord]) {}
+}
+
+/// Keeps records as json list in a file.
+#[derive(Debug)]
+pub struct FilePeerStore {
+    path: PathBuf,
+}
+
+impl FilePeerStore {
+    pub fn new(path: PathBuf) -> Self {
+        FilePeerStore { path }
+    }
+
+    fn read(&self) -> Result<Vec<PeerRecord>, io::Error> {
+        let json = fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    fn write(&self, records: &[PeerRecord]) -> Result<(), io::Error> {
+        // write to temporary file first, so that crash does not leave half written store
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl PeerStore for FilePeerStore {
+    fn load(&self) -> Vec<PeerRecord> {
+        match self.read() {
+            Ok(records) => records,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                warn!("Could not load peer store {}: {}", self.path.display(), err);
+                Vec::new()
+            }
+        }
+    }
+
+    fn save(&mut self, records: &[PeerRecord]) {
+        if let Err(err) = self.write(records) {
+            warn!("Could not save peer store {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Records of known nodes, backed by `PeerStore`. Every change is saved right away.
+pub struct PeerBook {
+    store: Box<dyn PeerStore>,
+    records: HashMap<NodeId, PeerRecord>,
+}
+
+impl PeerBook {
+    pub fn new(store: Box<dyn PeerStore>) -> Self {
+        let records = store.load().into_iter().map(|record| (record.node_id, record)).collect();
+        PeerBook { store, records }
+    }
+
+    fn save(&mut self) {
+        let mut records: Vec<PeerRecord> = self.records.values().cloned().collect();
+        records.sort_by_key(|record| record.node_id);
+        self.store.save(&records);
+    }
+
+    fn record(&mut self, node_id: &NodeId) -> &mut PeerRecord {
+        self.records.entry(*node_id).or_insert_with(|| PeerRecord {
+            node_id: *node_id,
+            score: INITIAL_SCORE,
+            banned_until: None,
+        })
+    }
+
+    /// Record that does not differ from unknown node is not worth keeping.
+    fn forget_if_empty(&mut self, node_id: &NodeId) {
+        if let Some(record) = self.records.get(node_id) {
+            if record.score == INITIAL_SCORE && record.banned_until.is_none() {
+                self.records.remove(node_id);
+            }
+        }
+    }
+
+    pub fn score(&self, node_id: &NodeId) -> Option<i32> {
+        self.records.get(node_id).map(|record| record.score)
+    }
+
+    pub fn set_score(&mut self, node_id: &NodeId, score: i32) {
+        if self.score(node_id).unwrap_or(INITIAL_SCORE) == score {
+            return;
+        }
+        self.record(node_id).score = score;
+        self.forget_if_empty(node_id);
+        self.save();
+    }
+
+    pub fn ban(&mut self, node_id: &NodeId, until: SystemTime) {
+        self.record(node_id).banned_until = Some(unix_secs(until));
+        self.save();
+    }
+
+    /// Returns false if node was not banned.
+    pub fn unban(&mut self, node_id: &NodeId) -> bool {
+        let banned = match self.records.get_mut(node_id) {
+            Some(record) => record.banned_until.take().is_some(),
+            None => false,
+        };
+        if banned {
+            self.forget_if_empty(node_id);
+            self.save();
+        }
+        banned
+    }
+
+    pub fn is_banned(&self, node_id: &NodeId, now: SystemTime) -> bool {
+        self.records
+            .get(node_id)
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| until > unix_secs(now))
+    }
+
+    /// Banned nodes with time when their ban expires.
+    pub fn bans(&self) -> Vec<(NodeId, SystemTime)> {
+        let mut bans: Vec<(NodeId, SystemTime)> = self
+            .records
+            .values()
+            .filter_map(|record| {
+                let until = record.banned_until?;
+                Some((record.node_id, UNIX_EPOCH + Duration::from_secs(until)))
+            })
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Lifts expired bans.
+    pub fn prune(&mut self, now: SystemTime) {
+        let now = unix_secs(now);
+        let expired: Vec<NodeId> = self
+            .records
+            .values()
+            .filter(|record| record.banned_until.is_some_and(|until| until <= now))
+            .map(|record| record.node_id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for node_id in expired.iter() {
+            self.record(node_id).banned_until = None;
+            self.forget_if_empty(node_id);
+        }
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(n: u64) -> NodeId {
+        NodeId::from_low_u64_be(n)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("reth-scheduler-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_bans_expire() {
+        let mut book = PeerBook::new(Box::new(NoPeerStore));
+        let now = SystemTime::now();
+        book.ban(&node(1), now + Duration::from_secs(60));
+        assert!(book.is_banned(&node(1), now));
+        assert!(!book.is_banned(&node(2), now));
+        assert_eq!(book.bans().len(), 1);
+
+        book.prune(now + Duration::from_secs(61));
+        assert!(book.bans().is_empty());
+        assert!(book.records.is_empty());
+    }
+
+    #[test]
+    fn test_unban_keeps_score() {
+        let mut book = PeerBook::new(Box::new(NoPeerStore));
+        book.set_score(&node(1), -40);
+        book.ban(&node(1), SystemTime::now() + Duration::from_secs(60));
+        assert!(book.unban(&node(1)));
+        assert!(!book.unban(&node(1)));
+        assert_eq!(book.score(&node(1)), Some(-40));
+        book.set_score(&node(1), INITIAL_SCORE);
+        assert_eq!(book.score(&node(1)), None);
+    }
+
+    #[test]
+    fn test_file_store_survives_restart() {
+        let path = temp_path("peers");
+        let until = SystemTime::now() + Duration::from_secs(600);
+        {
+            let mut book = PeerBook::new(Box::new(FilePeerStore::new(path.clone())));
+            book.ban(&node(1), until);
+            book.set_score(&node(2), 35);
+        }
+        let book = PeerBook::new(Box::new(FilePeerStore::new(path.clone())));
+        assert!(book.is_banned(&node(1), SystemTime::now()));
+        assert_eq!(book.score(&node(2)), Some(35));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file_is_empty_store() {
+        let store = FilePeerStore::new(temp_path("missing"));
+        assert!(store.load().is_empty());
+    }
+}
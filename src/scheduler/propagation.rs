@@ -0,0 +1,84 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by block and transaction propagation.
+
+use std::collections::{HashMap, HashSet};
+use super::peer_organizer::PeerId;
+use primitive_types::H256;
+
+/// Remembers which hashes every peer has, so that item is never sent to peer that
+/// announced it to us or already got it from us. Set of a peer is cleared when it reaches `limit`.
+pub struct KnownHashes {
+    limit: usize,
+    known: HashMap<PeerId, HashSet<H256>>,
+}
+
+impl KnownHashes {
+    pub fn new(limit: usize) -> Self {
+        KnownHashes { limit, known: HashMap::new() }
+    }
+
+    pub fn mark_known(&mut self, peer: &PeerId, hash: H256) {
+        let known = self.known.entry(*peer).or_default();
+        if known.len() >= self.limit {
+            known.clear();
+        }
+        known.insert(hash);
+    }
+
+    pub fn is_known(&self, peer: &PeerId, hash: &H256) -> bool {
+        self.known.get(peer).is_some_and(|known| known.contains(hash))
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.known.remove(peer);
+    }
+}
+
+/// Goes over `peers` and tells for each one if it should get the full item with `hash`. Square root
+/// of peers gets full item, others get only its hash. Iteration starts from different peer for
+/// every hash, so that full items are spread over peers.
+pub fn split_propagation<'a, P>(hash: &H256, peers: &'a [P]) -> impl Iterator<Item = (&'a P, bool)> {
+    let direct = (peers.len() as f64).sqrt().ceil() as usize;
+    let start = match peers.len() {
+        0 => 0,
+        len => hash[0] as usize % len,
+    };
+    peers
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(peers.len())
+        .enumerate()
+        .map(move |(index, peer)| (peer, index < direct))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_square_root_of_peers_gets_full_item() {
+        let peers: Vec<PeerId> = (1..=10).collect();
+        let split: Vec<(PeerId, bool)> = split_propagation(&H256::repeat_byte(3), &peers)
+            .map(|(peer, full)| (*peer, full))
+            .collect();
+        assert_eq!(split.len(), 10);
+        assert_eq!(split[0], (4, true));
+        assert_eq!(split.iter().filter(|(_, full)| *full).count(), 4);
+        assert!(split_propagation(&H256::zero(), &Vec::<PeerId>::new()).next().is_none());
+    }
+
+    #[test]
+    fn test_known_hashes_are_cleared_at_limit() {
+        let mut known = KnownHashes::new(2);
+        known.mark_known(&1, H256::repeat_byte(1));
+        known.mark_known(&1, H256::repeat_byte(2));
+        known.mark_known(&1, H256::repeat_byte(3));
+        assert!(!known.is_known(&1, &H256::repeat_byte(1)));
+        assert!(known.is_known(&1, &H256::repeat_byte(3)));
+        known.peer_disconnected(&1);
+        assert!(!known.is_known(&1, &H256::repeat_byte(3)));
+    }
+}
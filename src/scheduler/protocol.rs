@@ -1,6 +1,8 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
+use rlp::{DecoderError, Rlp, RlpStream};
+
 pub type ProtocolIdType = [u8; 3];
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
@@ -23,6 +25,8 @@ impl ProtocolId {
 pub enum EthProtocolVersion {
     VERSION_63,
     VERSION_64,
+    VERSION_65,
+    VERSION_66,
     HIGHER_VERSION(u8),
 }
 
@@ -31,6 +35,8 @@ impl EthProtocolVersion {
         match self {
             Self::VERSION_63 => 0x11,
             Self::VERSION_64 => 0x11,
+            Self::VERSION_65 => 65,
+            Self::VERSION_66 => 66,
             Self::HIGHER_VERSION(ver) => ver,
         }
     }
@@ -39,6 +45,8 @@ impl EthProtocolVersion {
         match self {
             Self::VERSION_63 => 63,
             Self::VERSION_64 => 64,
+            Self::VERSION_65 => 65,
+            Self::VERSION_66 => 66,
             Self::HIGHER_VERSION(ver) => ver,
         }
     }
@@ -46,10 +54,17 @@ impl EthProtocolVersion {
     pub fn from_version_byte(byte: u8) -> Option<EthProtocolVersion> {
         match byte {
             0x11 => Some(Self::VERSION_64),
+            65 => Some(Self::VERSION_65),
+            66 => Some(Self::VERSION_66),
             byte if byte > 0x11 => Some(Self::HIGHER_VERSION(byte)),
             _ => None,
         }
     }
+
+    /// Since eth/66 requests and responses are wrapped together with request id.
+    pub fn has_request_id(version: u8) -> bool {
+        version >= Self::VERSION_66.to_number()
+    }
 }
 
 /// Parity protocol version related protocol
@@ -82,7 +97,7 @@ impl ParityProtocolVersion {
     }
 }
 
-#[derive(FromPrimitive,Debug,Copy,Clone)]
+#[derive(FromPrimitive,Debug,Copy,Clone,PartialEq,Eq,Hash)]
 pub enum EthMessageId {
     Status = 0x00,
     NewBlockHashes = 0x01,
@@ -92,9 +107,9 @@ pub enum EthMessageId {
     GetBlockBodies = 0x05,
     BlockBodies = 0x06,
     NewBlock = 0x07,
-    // NewPooledTransactionHashes = 0x08, // eth/65 protocol
-    // GetPooledTransactions = 0x09, // eth/65 protocol
-    // PooledTransactions  = 0x0a, // eth/65 protocol
+    NewPooledTransactionHashes = 0x08, // eth/65 protocol
+    GetPooledTransactions = 0x09, // eth/65 protocol
+    PooledTransactions  = 0x0a, // eth/65 protocol
     //GetNodeData = 0x0d, // ommited it can overburder client.
     //NodeData = 0x0e,    // ommited it can overburder client
     GetReceipts = 0x0f,
@@ -104,13 +119,31 @@ pub enum EthMessageId {
 impl EthMessageId {
     pub fn is_response(&self) -> bool {
         match self {
-            Self::BlockHeaders | Self::BlockBodies | Self::Receipts => true,
+            Self::BlockHeaders | Self::BlockBodies | Self::Receipts | Self::PooledTransactions => true,
             _ => false
         }
     }
+
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Self::GetBlockHeaders | Self::GetBlockBodies | Self::GetReceipts | Self::GetPooledTransactions
+        )
+    }
+
+    /// Message id that peer is expected to answer request with.
+    pub fn response_id(&self) -> Option<EthMessageId> {
+        match self {
+            Self::GetBlockHeaders => Some(Self::BlockHeaders),
+            Self::GetBlockBodies => Some(Self::BlockBodies),
+            Self::GetReceipts => Some(Self::Receipts),
+            Self::GetPooledTransactions => Some(Self::PooledTransactions),
+            _ => None
+        }
+    }
 }
 
-#[derive(FromPrimitive,Debug, Copy,Clone)]
+#[derive(FromPrimitive,Debug, Copy,Clone,PartialEq,Eq,Hash)]
 pub enum ParityMessageId {
     // Snapshot related id/s
     GetSnapshotManifest = 0x11,
@@ -128,9 +161,18 @@ impl ParityMessageId {
             _ => false
         }
     }
+
+    /// Message id that peer is expected to answer request with.
+    pub fn response_id(&self) -> Option<ParityMessageId> {
+        match self {
+            Self::GetSnapshotManifest => Some(Self::SnapshotManifest),
+            Self::GetSnapshotData => Some(Self::SnapshotData),
+            _ => None
+        }
+    }
 }
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash)]
 pub enum MessageId {
     Eth(EthMessageId),
     Parity(ParityMessageId),
@@ -143,4 +185,63 @@ impl MessageId {
             Self::Parity(msg_id) => *msg_id as u8,
         }
     }
-}
\ No newline at end of file
+
+    pub fn protocol(&self) -> ProtocolId {
+        match self {
+            Self::Eth(_) => ProtocolId::Eth,
+            Self::Parity(_) => ProtocolId::Parity,
+        }
+    }
+
+    pub fn response_id(&self) -> Option<MessageId> {
+        match self {
+            Self::Eth(msg_id) => msg_id.response_id().map(Self::Eth),
+            Self::Parity(msg_id) => msg_id.response_id().map(Self::Parity),
+        }
+    }
+}
+
+/// Wraps already encoded request or response into eth/66 `[request_id, payload]` list.
+pub fn encode_with_request_id(request_id: u64, data: &[u8]) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&request_id);
+    stream.append_raw(data, 1);
+    stream.out()
+}
+
+/// Splits eth/66 message into request id and payload that regular codecs can decode.
+pub fn decode_request_id(data: &[u8]) -> Result<(u64, &[u8]), DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? != 2 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+    Ok((rlp.val_at(0)?, rlp.at(1)?.as_raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id_roundtrip() {
+        let payload = rlp::encode_list::<u64, u64>(&[1, 2, 3]);
+        let encoded = encode_with_request_id(1111, &payload);
+        let (request_id, decoded) = decode_request_id(&encoded).unwrap();
+        assert_eq!(request_id, 1111);
+        assert_eq!(decoded, &payload[..]);
+    }
+
+    #[test]
+    fn test_request_id_missing() {
+        let payload = rlp::encode_list::<u64, u64>(&[1, 2, 3]);
+        assert!(decode_request_id(&payload).is_err());
+        assert!(decode_request_id(&rlp::encode(&5u64)).is_err());
+    }
+
+    #[test]
+    fn test_request_id_version() {
+        assert!(!EthProtocolVersion::has_request_id(64));
+        assert!(!EthProtocolVersion::has_request_id(65));
+        assert!(EthProtocolVersion::has_request_id(66));
+    }
+}
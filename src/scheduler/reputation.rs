@@ -0,0 +1,211 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::peer_organizer::PeerId;
+use crate::devp2p_adapter::{NodeId, PeerPenal};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Score of peer that we know nothing about.
+pub const INITIAL_SCORE: i32 = 0;
+pub const MAX_SCORE: i32 = 100;
+/// Peer is kicked when its score drops to this value.
+pub const KICK_THRESHOLD: i32 = -100;
+/// Score gained for every response that we asked for.
+pub const USEFUL_RESPONSE_REWARD: i32 = 5;
+/// Peer that was kicked this many times in `OFFENCE_WINDOW` is banned.
+pub const MAX_OFFENCES: usize = 3;
+pub const OFFENCE_WINDOW: Duration = Duration::from_secs(60 * 60);
+pub const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Score lost for penalties that don't disconnect peer on their own.
+fn score_change(penal: PeerPenal) -> i32 {
+    match penal {
+        PeerPenal::UselessResponse => -10,
+        PeerPenal::Timeout => -20,
+        PeerPenal::InvalidData => -50,
+        PeerPenal::Kick | PeerPenal::Ban | PeerPenal::IncompatibleChain => KICK_THRESHOLD,
+    }
+}
+
+/// Identity that offences and bans are kept under. Peer id changes with every session,
+/// so it is used only for peers whose node id is not known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Offender {
+    Node(NodeId),
+    Session(PeerId),
+}
+
+impl Offender {
+    fn new(peer: &PeerId, node_id: Option<NodeId>) -> Self {
+        match node_id {
+            Some(node_id) => Offender::Node(node_id),
+            None => Offender::Session(*peer),
+        }
+    }
+}
+
+/// Tracks how well peers behave. Score is kept while peer is connected, offences
+/// and bans are kept after disconnect so that repeat offenders can be banned.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, i32>,
+    offences: HashMap<Offender, Vec<Instant>>,
+    banned: HashMap<Offender, Instant>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn score(&self, peer: &PeerId) -> i32 {
+        self.scores.get(peer).copied().unwrap_or(INITIAL_SCORE)
+    }
+
+    /// Restores score that peer had in previous session.
+    pub fn set_score(&mut self, peer: &PeerId, score: i32) {
+        self.scores.insert(*peer, score.clamp(KICK_THRESHOLD + 1, MAX_SCORE));
+    }
+
+    pub fn reward(&mut self, peer: &PeerId) {
+        let score = self.scores.entry(*peer).or_insert(INITIAL_SCORE);
+        *score = (*score + USEFUL_RESPONSE_REWARD).min(MAX_SCORE);
+    }
+
+    /// Lowers score of peer and returns `Kick` or `Ban` if peer needs to be disconnected.
+    /// Peer on incompatible chain is always disconnected, without counting it as offence.
+    /// Offences are counted for `node_id` of peer when it is known.
+    pub fn penalize(
+        &mut self,
+        peer: &PeerId,
+        node_id: Option<NodeId>,
+        penal: PeerPenal,
+        now: Instant,
+    ) -> Option<PeerPenal> {
+        let offender = Offender::new(peer, node_id);
+        match penal {
+            PeerPenal::Ban => return Some(self.ban(peer, offender, now)),
+            PeerPenal::Kick => (),
+            PeerPenal::IncompatibleChain => {
+                self.scores.remove(peer);
+                return Some(PeerPenal::IncompatibleChain);
+            }
+            _ => {
+                let score = self.scores.entry(*peer).or_insert(INITIAL_SCORE);
+                *score += score_change(penal);
+                if *score > KICK_THRESHOLD {
+                    return None;
+                }
+            }
+        }
+        // kicked peer keeps the lowest score, so that it is remembered for its node
+        self.scores.insert(*peer, KICK_THRESHOLD + 1);
+        let offences = self.offences.entry(offender).or_default();
+        offences.retain(|at| now.duration_since(*at) < OFFENCE_WINDOW);
+        offences.push(now);
+        if offences.len() >= MAX_OFFENCES {
+            Some(self.ban(peer, offender, now))
+        } else {
+            Some(PeerPenal::Kick)
+        }
+    }
+
+    fn ban(&mut self, peer: &PeerId, offender: Offender, now: Instant) -> PeerPenal {
+        self.scores.insert(*peer, KICK_THRESHOLD + 1);
+        self.offences.remove(&offender);
+        self.banned.insert(offender, now + BAN_DURATION);
+        PeerPenal::Ban
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, node_id: Option<NodeId>, now: Instant) -> bool {
+        self.banned
+            .get(&Offender::new(peer, node_id))
+            .is_some_and(|until| *until > now)
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.scores.remove(peer);
+    }
+
+    /// Forgets expired bans and old offences.
+    pub fn tick(&mut self, now: Instant) {
+        self.banned.retain(|_, until| *until > now);
+        self.offences
+            .retain(|_, offences| offences.iter().any(|at| now.duration_since(*at) < OFFENCE_WINDOW));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_penalties_kick_below_threshold() {
+        let mut reputation = Reputation::new();
+        let now = Instant::now();
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::InvalidData, now), None);
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::Timeout, now), None);
+        assert_eq!(reputation.score(&1), -70);
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::Timeout, now), None);
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::UselessResponse, now), Some(PeerPenal::Kick));
+        assert_eq!(reputation.score(&1), KICK_THRESHOLD + 1);
+        reputation.peer_disconnected(&1);
+        assert_eq!(reputation.score(&1), INITIAL_SCORE);
+    }
+
+    #[test]
+    fn test_rewards_are_capped() {
+        let mut reputation = Reputation::new();
+        for _ in 0..100 {
+            reputation.reward(&1);
+        }
+        assert_eq!(reputation.score(&1), MAX_SCORE);
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::InvalidData, Instant::now()), None);
+    }
+
+    #[test]
+    fn test_repeat_offender_is_banned_for_a_while() {
+        let mut reputation = Reputation::new();
+        let now = Instant::now();
+        for _ in 1..MAX_OFFENCES {
+            assert_eq!(reputation.penalize(&1, None, PeerPenal::Kick, now), Some(PeerPenal::Kick));
+        }
+        assert!(!reputation.is_banned(&1, None, now));
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::Kick, now), Some(PeerPenal::Ban));
+        assert!(reputation.is_banned(&1, None, now));
+
+        let later = now + BAN_DURATION + Duration::from_secs(1);
+        assert!(!reputation.is_banned(&1, None, later));
+        reputation.tick(later);
+        assert!(reputation.banned.is_empty());
+    }
+
+    #[test]
+    fn test_old_offences_are_forgotten() {
+        let mut reputation = Reputation::new();
+        let now = Instant::now();
+        for _ in 1..MAX_OFFENCES {
+            reputation.penalize(&1, None, PeerPenal::Kick, now);
+        }
+        let later = now + OFFENCE_WINDOW;
+        assert_eq!(reputation.penalize(&1, None, PeerPenal::Kick, later), Some(PeerPenal::Kick));
+    }
+
+    #[test]
+    fn test_offences_follow_node_across_sessions() {
+        let mut reputation = Reputation::new();
+        let now = Instant::now();
+        let node_id = Some(NodeId::from_low_u64_be(7));
+        // every kick is under new peer id
+        for peer in 1..MAX_OFFENCES {
+            assert_eq!(reputation.penalize(&peer, node_id, PeerPenal::Kick, now), Some(PeerPenal::Kick));
+        }
+        let peer = MAX_OFFENCES;
+        assert_eq!(reputation.penalize(&peer, node_id, PeerPenal::Kick, now), Some(PeerPenal::Ban));
+        assert!(reputation.is_banned(&(peer + 1), node_id, now));
+        assert!(!reputation.is_banned(&peer, None, now));
+    }
+}
@@ -2,21 +2,28 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::{
+    clock::Clock,
     handshake::Handshake,
-    peer_organizer::{ErrorAct, PeerCapability, PeerId, PeerOrganizer, Task, TaskType},
-    protocol::{EthMessageId, MessageId, ParityMessageId, ProtocolId},
+    peer_organizer::{Answered, ErrorAct, PeerCapability, PeerId, PeerOrganizer, Task, TaskId, TaskType},
+    peer_store::{NoPeerStore, PeerStore},
+    protocol::{decode_request_id, encode_with_request_id, EthMessageId, MessageId, ParityMessageId, ProtocolId},
 };
 use crate::{
     block_manager::BlockManager,
     client_adapter::{
         Blockchain,
+        ChainSpec,
         client_info::{Client, Snapshot},
         headers_in_memory::HeadersInMemory,
+        transaction_pool::TransactionsInMemory,
     },
+    common_types::{BlockNumber, BlockTransaction, NewBlock},
     devp2p_adapter::{
         adapter::{Devp2pAdapter, Devp2pInbound},
         PeerPenal,
     },
+    snapshot_manager::SnapshotManager,
+    transaction_manager::TransactionManager,
 };
 use log::*;
 use std::{
@@ -25,9 +32,19 @@ use std::{
         Arc, Condvar, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/// Number of peers we wait for before starting sync.
+pub const MIN_PEERS_TO_START: usize = 3;
+/// If there are less then `MIN_PEERS_TO_START` peers, sync starts with ones that connected in this time.
+pub const PEERS_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Main loop runs at least this often, even if nothing triggered it.
+pub const LOOP_INTERVAL: Duration = Duration::from_secs(1);
+/// Snapshot is used only if it is this much ahead of our best block.
+pub const MIN_WARP_DISTANCE: BlockNumber = 30000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SchedulerState {
     WaitingPeer,
     Warping,
@@ -35,15 +52,30 @@ pub enum SchedulerState {
     PassiveSync,
 }
 
+/// Events that are reported to client with `Client::on_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchedulerEvent {
+    StateChanged {
+        from: SchedulerState,
+        to: SchedulerState,
+    },
+}
+
 pub struct Scheduler {
     handshake: Mutex<Handshake>,
     state: Mutex<SchedulerState>,
+    // peers are waited for since `start`
+    started: Mutex<Instant>,
+    clock: Clock,
 
     peer_organizer: Arc<Mutex<PeerOrganizer>>,
     client: Arc<dyn Client>,
     snapshot: Arc<dyn Snapshot>,
+    chain_spec: ChainSpec,
 
     block_manager: Arc<Mutex<BlockManager>>,
+    transaction_manager: Arc<Mutex<TransactionManager>>,
+    snapshot_manager: Arc<Mutex<SnapshotManager>>,
     //pending_packages: u32,
     /*
     block_manager,
@@ -54,7 +86,7 @@ pub struct Scheduler {
     */
     // peer org thread,
     // organizer thread.
-    main_loop_trigger: Mutex<Sender<LoopMsg>>,
+    main_loop_trigger: Mutex<LoopTrigger>,
     thread_handle: Mutex<Option<thread::JoinHandle<()>>>,
 }
 
@@ -63,38 +95,71 @@ pub enum LoopMsg {
     EndLoop,
 }
 
+/// Wakes whichever runtime drives `Scheduler::main_loop`.
+pub(crate) enum LoopTrigger {
+    Thread(Sender<LoopMsg>),
+    #[cfg(feature = "tokio")]
+    Async(Arc<super::async_runtime::LoopWaker>),
+    /// Caller runs main loop on its own, like `MockNetwork` does.
+    #[cfg(any(test, feature = "mock"))]
+    Manual,
+}
+
+impl LoopTrigger {
+    /// Message is lost if loop has already ended.
+    fn send(&self, msg: LoopMsg) {
+        match self {
+            LoopTrigger::Thread(tx) => {
+                let _ = tx.send(msg);
+            }
+            #[cfg(feature = "tokio")]
+            LoopTrigger::Async(waker) => match msg {
+                LoopMsg::TrigerLoop => waker.trigger(),
+                LoopMsg::EndLoop => waker.end(),
+            },
+            #[cfg(any(test, feature = "mock"))]
+            LoopTrigger::Manual => (),
+        }
+    }
+}
+
 impl Scheduler {
     pub fn new(
         devp2p: Box<dyn Devp2pAdapter>,
         client: Arc<dyn Client>,
         snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+    ) -> Arc<Scheduler> {
+        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
+        Self::with_chain(devp2p, client, snapshot, chain_spec, chain, Box::new(NoPeerStore))
+    }
+
+    /// Scheduler that syncs into and serves peers from given `chain`. Reputation and bans
+    /// of nodes are kept in `peer_store`.
+    pub fn with_chain(
+        devp2p: Box<dyn Devp2pAdapter>,
+        client: Arc<dyn Client>,
+        snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+        chain: Arc<Mutex<dyn Blockchain + Send + Sync>>,
+        peer_store: Box<dyn PeerStore>,
     ) -> Arc<Scheduler> {
         let devp2p = Arc::new(devp2p);
         let (tx, rx) = channel::<LoopMsg>();
-        let chain = Arc::new(Mutex::new(HeadersInMemory::new()));
-        let peer_organizer = PeerOrganizer::new(devp2p.clone());
-        let block_manager = BlockManager::new(chain);
-        let org = Arc::new(Scheduler {
-            peer_organizer: peer_organizer,
-            state: Mutex::new(SchedulerState::WaitingPeer),
-            handshake: Mutex::new(Handshake::new()),
-            block_manager: block_manager,
-            main_loop_trigger: Mutex::new(tx),
-            thread_handle: Mutex::new(None),
-            client,
-            snapshot,
-        });
+        let trigger = LoopTrigger::Thread(tx);
+        let peer_organizer = PeerOrganizer::new(devp2p.clone(), peer_store);
+        let org = Self::build(peer_organizer, client, snapshot, chain_spec, chain, trigger);
         let org_exec = org.clone();
         *(org.thread_handle.lock().unwrap()) = Some(
             thread::Builder::new()
                 .name("Scheduler".to_string())
                 .spawn(move || loop {
                     {
-                        match rx.recv_timeout(Duration::from_secs(1)) {
-                            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => (),
+                        match rx.recv_timeout(LOOP_INTERVAL) {
+                            Err(RecvTimeoutError::Timeout) => (),
                             Ok(LoopMsg::TrigerLoop) => (),
                             Ok(LoopMsg::EndLoop) => break,
-                            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                            Err(RecvTimeoutError::Disconnected) => break,
                         }
                         org_exec.main_loop();
                     }
@@ -106,17 +171,47 @@ impl Scheduler {
         org
     }
 
+    /// Scheduler without runtime, caller runs `main_loop` whenever `trigger` asks for it.
+    /// Time of scheduler is taken from clock of `peer_organizer`.
+    pub(crate) fn build(
+        peer_organizer: Arc<Mutex<PeerOrganizer>>,
+        client: Arc<dyn Client>,
+        snapshot: Arc<dyn Snapshot>,
+        chain_spec: ChainSpec,
+        chain: Arc<Mutex<dyn Blockchain + Send + Sync>>,
+        trigger: LoopTrigger,
+    ) -> Arc<Scheduler> {
+        let head = client.status().highest_block.0;
+        let clock = peer_organizer.lock().unwrap().clock().clone();
+        let block_manager = BlockManager::new(chain);
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let transaction_manager = TransactionManager::new(pool);
+        let snapshot_manager = SnapshotManager::new(snapshot.clone());
+        Arc::new(Scheduler {
+            peer_organizer: peer_organizer,
+            state: Mutex::new(SchedulerState::WaitingPeer),
+            started: Mutex::new(clock.now()),
+            clock,
+            handshake: Mutex::new(Handshake::new(&chain_spec, head)),
+            block_manager: block_manager,
+            transaction_manager,
+            snapshot_manager,
+            main_loop_trigger: Mutex::new(trigger),
+            thread_handle: Mutex::new(None),
+            client,
+            snapshot,
+            chain_spec,
+        })
+    }
+
     pub fn start(&self) {
+        *self.started.lock().unwrap() = self.clock.now();
         self.peer_organizer.lock().unwrap().start();
     }
 
     pub fn stop(&self) {
         let handle = {
-            self.main_loop_trigger
-                .lock()
-                .unwrap()
-                .send(LoopMsg::EndLoop)
-                .unwrap();
+            self.main_loop_trigger.lock().unwrap().send(LoopMsg::EndLoop);
             self.thread_handle.lock().unwrap().take()
         };
         self.peer_organizer.lock().unwrap().stop();
@@ -126,17 +221,71 @@ impl Scheduler {
         //TODO clean all states
     }
 
-    pub fn main_loop(&self) {
+    /// Transactions created by client, they are propagated to peers in next loop.
+    pub fn submit_transactions(&self, transactions: Vec<BlockTransaction>) {
+        self.transaction_manager.lock().unwrap().submit_transactions(transactions);
+        self.main_loop_trigger.lock().unwrap().send(LoopMsg::TrigerLoop);
+    }
+
+    /// Block that client sealed or imported, it is announced to peers that don't have it.
+    /// `block.score` is total difficulty of the chain with this block.
+    pub fn broadcast_block(&self, block: NewBlock) {
+        let tasks = self.block_manager.lock().unwrap().broadcast_block(&block);
         let mut org = self.peer_organizer.lock().unwrap();
-        let block_mgr = self.block_manager.lock().unwrap();
-        if let Some(task) = block_mgr.next_sync_task() {
-            org.schedule_to_free_peer(task);
+        for task in tasks {
+            org.push_task(task, None);
         }
-        let failed_tasks = org.tick();
+    }
+
+    /// Organizer of connected peers, it exposes management of bans.
+    pub fn peer_organizer(&self) -> &Arc<Mutex<PeerOrganizer>> {
+        &self.peer_organizer
+    }
+
+    pub fn state(&self) -> SchedulerState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Network that scheduler syncs with, bootnodes are taken from here.
+    pub fn chain_spec(&self) -> &ChainSpec {
+        &self.chain_spec
+    }
+
+    pub fn main_loop(&self) {
+        // client is notified after all locks are released, so that it can call back into scheduler
+        for event in self.update() {
+            self.client.on_event(event);
+        }
+    }
+
+    fn update(&self) -> Vec<SchedulerEvent> {
+        // handshake is locked before other parts, so it is updated separately
+        let best = self.block_manager.lock().unwrap().best_block();
+        if let Some(best) = best {
+            self.handshake.lock().unwrap().set_head(best);
+        }
+
+        let mut events = Vec::new();
+        let mut set_state = |state: &mut SchedulerState, to: SchedulerState| {
+            info!("Scheduler state {:?} -> {:?}", state, to);
+            events.push(SchedulerEvent::StateChanged { from: *state, to });
+            *state = to;
+        };
+        let mut org = self.peer_organizer.lock().unwrap();
+        let mut block_mgr = self.block_manager.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let mut tx_mgr = self.transaction_manager.lock().unwrap();
+        let mut snapshot_mgr = self.snapshot_manager.lock().unwrap();
+
+        let failed_tasks = org.tick(|task_id, peer| {
+            block_mgr.reassign_task(task_id, peer)
+                || tx_mgr.reassign_task(task_id, peer)
+                || snapshot_mgr.reassign_task(task_id, peer)
+        });
         if failed_tasks.len() != 0 {
             info!("Failed tasks: {:?}", failed_tasks);
         }
-        for fail_task in failed_tasks.iter() {
+        for (task_id, fail_task) in failed_tasks.iter() {
             match fail_task {
                 Task::WaitForStatus(peer, _) => {
                     org.push_task(
@@ -144,30 +293,102 @@ impl Scheduler {
                         None,
                     );
                 }
+                Task::InitialRequest(_, _, _) => {
+                    block_mgr.task_failed(task_id);
+                    tx_mgr.task_failed(task_id);
+                    snapshot_mgr.task_failed(task_id);
+                }
                 _ => (),
             }
         }
+        for penalty in block_mgr.take_penalties() {
+            org.push_task(penalty, None);
+        }
         if org.peers().len() != 0 {
             info!("Current peer number:{}", org.peers().len());
         }
-        //wait for n number of peer
-        /*
-            match self.state {
-            WaitingPeer => //check timeout or N number of peers,
-            Warping => self.snapshot_manager.i_want(),
-            ActiveSync => self.block_manager.i_want(),
-            PassiveSync => {
-                self.block_manager.i_want()
-                self.transaction_manager.i_want()
+
+        match *state {
+            //wait for n number of peer
+            SchedulerState::WaitingPeer => {
+                let peers = org.peers().len();
+                let waited = self.clock.elapsed(*self.started.lock().unwrap()) > PEERS_WAIT_TIMEOUT;
+                if peers >= MIN_PEERS_TO_START || (waited && peers > 0) {
+                    let best = block_mgr.best_block().unwrap_or(0);
+                    match snapshot_mgr.choose_snapshot() {
+                        Some(number) if number >= best + MIN_WARP_DISTANCE => {
+                            set_state(&mut state, SchedulerState::Warping)
+                        }
+                        _ => set_state(&mut state, SchedulerState::ActiveSync),
+                    }
+                }
+            }
+            SchedulerState::Warping => {
+                for peer in org.free_peers_by_rate(&MessageId::Parity(ParityMessageId::GetSnapshotData)) {
+                    if let Some(request) = snapshot_mgr.next_sync_task(&peer) {
+                        org.schedule_request(&peer, request);
+                    }
+                }
+                if snapshot_mgr.is_complete() {
+                    info!("Warp sync finished, restored {:?} chunks", snapshot_mgr.progress());
+                    if let Some(number) = snapshot_mgr.snapshot_block() {
+                        block_mgr.warped_to(number);
+                    }
+                    set_state(&mut state, SchedulerState::ActiveSync);
+                } else if snapshot_mgr.is_stalled() {
+                    warn!("No peer can serve chosen snapshot, continuing with active sync");
+                    snapshot_mgr.abort();
+                    set_state(&mut state, SchedulerState::ActiveSync);
+                }
+            }
+            // in passive sync same pipeline fetches blocks that peers announce
+            SchedulerState::ActiveSync | SchedulerState::PassiveSync => {
+                // fastest peers get the lowest ranges, as import waits for them
+                for peer in org.free_peers_by_rate(&MessageId::Eth(EthMessageId::GetBlockHeaders)) {
+                    // eth/66 peers can take several requests at once
+                    while org.is_free(&peer) {
+                        let request = match org.rate(&peer) {
+                            Some(rate) => block_mgr.next_sync_task(&peer, rate),
+                            None => None,
+                        };
+                        match request {
+                            Some(request) => org.schedule_request(&peer, request),
+                            None => break,
+                        }
+                    }
+                }
+                if *state == SchedulerState::ActiveSync && !block_mgr.is_syncing() {
+                    info!("Active sync finished at #{:?}", block_mgr.sync_target());
+                    set_state(&mut state, SchedulerState::PassiveSync);
+                }
+            }
+        }
+
+        // peers that are left free can fetch transactions they announced
+        for peer in org.free_peers() {
+            while org.is_free(&peer) {
+                match tx_mgr.next_fetch_task(&peer) {
+                    Some(request) => org.schedule_request(&peer, request),
+                    None => break,
+                }
             }
         }
-        */
+        let peers: Vec<_> = org
+            .peers()
+            .values()
+            .map(|peer| (peer.peer_id(), peer.eth_protocol_version()))
+            .collect();
+        for task in tx_mgr.propagate(&peers) {
+            org.push_task(task, None);
+        }
+        events
     }
 
     fn process_eth_message(
         &self,
         id: EthMessageId,
         peer: &PeerId,
+        task_id: Option<TaskId>,
         data: &[u8],
     ) -> Result<Task, ErrorAct> {
         match id {
@@ -179,52 +400,155 @@ impl Scheduler {
                     // this should be only place where we interlock handshake and peer_organizer
                     let mut org = self.peer_organizer.lock().unwrap();
                     if org.check_response_with_task_id(peer, TaskType::StatusMsg, task_id) {
-                        org.push_task(
-                            handshake
-                                .handle_status_message(peer, data)
-                                .unwrap_or_else(|act| {
-                                    Task::PenalPeer(*peer, act.penal(), act.reason())
-                                }),
-                            None,
-                        );
+                        let task = handshake
+                            .handle_status_message(peer, data)
+                            .unwrap_or_else(|act| {
+                                Task::PenalPeer(*peer, act.penal(), act.reason())
+                            });
+                        if let Task::InsertPeer(ref hi) = task {
+                            self.block_manager.lock().unwrap().new_peer(
+                                peer,
+                                hi.latest_hash,
+                                hi.total_difficulty,
+                            );
+                            self.snapshot_manager.lock().unwrap().new_peer(peer, hi.snapshot);
+                        }
+                        org.push_task(task, None);
                     };
                 };
             }
             EthMessageId::NewBlockHashes => {
                 info!("Got NewBlockHashes message from {}", peer);
-                self.block_manager.lock().unwrap().api_new_block_hashes(peer, data);
+                return self.block_manager.lock().unwrap().api_new_block_hashes(peer, data);
+            }
+            EthMessageId::Transactions => {
+                info!("Got Transactions message from {} with {} bytes", peer, data.len());
+                return self.transaction_manager.lock().unwrap().api_transactions(peer, data);
             }
-            EthMessageId::Transactions => {}
             EthMessageId::GetBlockHeaders => {
-                info!("Responding peer {} with dummy BlockHeaders message", peer);
+                debug!("Serving block headers to peer {}", peer);
                 return self.block_manager.lock().unwrap().api_get_block_headers(peer, &data);
             }
             EthMessageId::BlockHeaders => {
                 info!("Got BlockHeaders message from {}", peer);
-                self.block_manager.lock().unwrap().process_block_headers(&data);
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .block_manager
+                        .lock()
+                        .unwrap()
+                        .process_block_headers(peer, task_id, &data);
+                }
             }
             EthMessageId::GetBlockBodies => {
-                info!("Responding peer {} with dummy BlockBodies message", peer);
+                debug!("Serving block bodies to peer {}", peer);
                 return self.block_manager.lock().unwrap().api_get_block_bodies(peer, &data);
             }
             EthMessageId::BlockBodies => {
                 info!("Got BlockBodies message from {} with {} bytes", peer, data.len());
-                self.block_manager.lock().unwrap().process_block_bodies(&data);
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .block_manager
+                        .lock()
+                        .unwrap()
+                        .process_block_bodies(peer, task_id, &data);
+                }
             }
             EthMessageId::NewBlock => {
                 info!("Got NewBlock message from {} with {} bytes", peer, data.len());
-                self.block_manager.lock().unwrap().api_new_block_hashes(peer, data);
+                return self.block_manager.lock().unwrap().api_new_block(peer, data);
+            }
+            EthMessageId::NewPooledTransactionHashes => {
+                info!("Got NewPooledTransactionHashes message from {}", peer);
+                return self
+                    .transaction_manager
+                    .lock()
+                    .unwrap()
+                    .api_new_pooled_transaction_hashes(peer, data);
+            }
+            EthMessageId::GetPooledTransactions => {
+                debug!("Serving pooled transactions to peer {}", peer);
+                return self.transaction_manager.lock().unwrap().api_get_pooled_transactions(peer, data);
+            }
+            EthMessageId::PooledTransactions => {
+                info!("Got PooledTransactions message from {} with {} bytes", peer, data.len());
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .transaction_manager
+                        .lock()
+                        .unwrap()
+                        .process_pooled_transactions(peer, task_id, data);
+                }
             }
-            // NewPooledTransactionHashes = 0x08, // eth/65 protocol
-            // GetPooledTransactions = 0x09, // eth/65 protocol
-            // PooledTransactions  = 0x0a, // eth/65 protocol
             //EthMessageId::GetNodeData => {} // ommited it can overburder client.
             //EthMessageId::NodeData => {}    // ommited it can overburder client
-            EthMessageId::GetReceipts => {}
-            EthMessageId::Receipts => {}
+            EthMessageId::GetReceipts => {
+                debug!("Serving receipts to peer {}", peer);
+                return self.block_manager.lock().unwrap().api_get_receipts(peer, data);
+            }
+            EthMessageId::Receipts => {
+                info!("Got Receipts message from {} with {} bytes", peer, data.len());
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .block_manager
+                        .lock()
+                        .unwrap()
+                        .process_receipts(peer, task_id, data);
+                }
+            }
+        }
+        Ok(Task::None)
+    }
+
+    fn process_parity_message(
+        &self,
+        id: ParityMessageId,
+        peer: &PeerId,
+        task_id: Option<TaskId>,
+        data: &[u8],
+    ) -> Result<Task, ErrorAct> {
+        match id {
+            ParityMessageId::GetSnapshotManifest => {
+                info!("Responding peer {} with SnapshotManifest message", peer);
+                return self.snapshot_manager.lock().unwrap().api_get_snapshot_manifest(peer);
+            }
+            ParityMessageId::SnapshotManifest => {
+                info!("Got SnapshotManifest message from {}", peer);
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .snapshot_manager
+                        .lock()
+                        .unwrap()
+                        .process_snapshot_manifest(peer, task_id, data);
+                }
+            }
+            ParityMessageId::GetSnapshotData => {
+                info!("Responding peer {} with SnapshotData message", peer);
+                return self.snapshot_manager.lock().unwrap().api_get_snapshot_data(peer, data);
+            }
+            ParityMessageId::SnapshotData => {
+                info!("Got SnapshotData message from {} with {} bytes", peer, data.len());
+                if let Some(ref task_id) = task_id {
+                    return self
+                        .snapshot_manager
+                        .lock()
+                        .unwrap()
+                        .process_snapshot_data(peer, task_id, data);
+                }
+            }
+            ParityMessageId::ConsensusData => {}
         }
         Ok(Task::None)
     }
+
+    /// Measures throughput of peer by number of items `accepted` from its response, None if response
+    /// was rejected. Only accepted response is rewarded.
+    fn response_processed(&self, peer: &PeerId, answered: &Answered, accepted: Option<u64>) {
+        let mut org = self.peer_organizer.lock().unwrap();
+        org.delivered(peer, answered, accepted.unwrap_or(0));
+        if accepted.is_some() {
+            org.reward(peer);
+        }
+    }
 }
 
 impl Devp2pInbound for Scheduler {
@@ -243,22 +567,54 @@ impl Devp2pInbound for Scheduler {
                     None => return, //TODO disconnect peer. but for now just ignore it.
                 };
 
+                let has_request_id = self
+                    .peer_organizer
+                    .lock()
+                    .unwrap()
+                    .has_request_id(peer)
+                    .unwrap_or(false);
+                let (request_id, data) =
+                    if has_request_id && (message_id.is_request() || message_id.is_response()) {
+                        match decode_request_id(data) {
+                            Ok((request_id, payload)) => (Some(request_id), payload),
+                            Err(err) => {
+                                let task = Task::new_kick(peer, format!("Invalid eth/66 message: {}", err));
+                                self.peer_organizer.lock().unwrap().push_task(task, None);
+                                return;
+                            }
+                        }
+                    } else {
+                        (None, data)
+                    };
+
+                let mut answered = None;
                 if message_id.is_response() {
-                    if !self
-                        .peer_organizer
-                        .lock()
-                        .unwrap()
-                        .check_response(peer, MessageId::Eth(message_id))
-                    {
+                    let mut org = self.peer_organizer.lock().unwrap();
+                    answered = match request_id {
+                        Some(request_id) => {
+                            org.check_response_with_request_id(peer, message_id, &(request_id as TaskId))
+                        }
+                        None => org.check_response(peer, MessageId::Eth(message_id)),
+                    };
+                    if answered.is_none() {
                         return;
                     }
                 }
 
-                let tasks = self.process_eth_message(message_id, peer, data);
-                let mut peer_org = self.peer_organizer.lock().unwrap();
-                for task in tasks {
-                    peer_org.push_task(task, None);
+                let task_id = answered.map(|answered| answered.task_id);
+                let result = self.process_eth_message(message_id, peer, task_id, data);
+                if let Some(answered) = answered {
+                    // every eth response is list of requested items
+                    let items = rlp::Rlp::new(data).item_count().unwrap_or(0) as u64;
+                    let accepted = result.as_ref().ok().map(|_| items);
+                    self.response_processed(peer, &answered, accepted);
                 }
+                let mut task = result.unwrap_or_else(|act| Task::PenalPeer(*peer, act.penal(), act.reason()));
+                // eth/66 response is answered with request id it was asked with
+                if let (Some(request_id), Task::Responde(_, _, _, response)) = (request_id, &mut task) {
+                    *response = encode_with_request_id(request_id, response);
+                }
+                self.peer_organizer.lock().unwrap().push_task(task, None);
             }
             ProtocolId::Parity => {
                 // transform message id
@@ -268,29 +624,33 @@ impl Devp2pInbound for Scheduler {
                     None => return, //TODO disconnect peer. but for now just ignore it.
                 };
 
+                let mut answered = None;
                 if message_id.is_response() {
-                    if !self
+                    answered = self
                         .peer_organizer
                         .lock()
                         .unwrap()
-                        .check_response(peer, MessageId::Parity(message_id))
-                    {
+                        .check_response(peer, MessageId::Parity(message_id));
+                    if answered.is_none() {
                         return;
                     }
                 }
 
-                match message_id {
-                    ParityMessageId::GetSnapshotManifest => {}
-                    ParityMessageId::SnapshotManifest => {}
-                    ParityMessageId::GetSnapshotData => {}
-                    ParityMessageId::SnapshotData => {}
-                    ParityMessageId::ConsensusData => {}
+                let task_id = answered.map(|answered| answered.task_id);
+                let result = self.process_parity_message(message_id, peer, task_id, data);
+                if let Some(answered) = answered {
+                    self.response_processed(peer, &answered, result.as_ref().ok().map(|_| 1));
                 }
+                let task = result.unwrap_or_else(|act| Task::PenalPeer(*peer, act.penal(), act.reason()));
+                self.peer_organizer.lock().unwrap().push_task(task, None);
             }
         }
     }
     /// Called when new peer is connected. Only called when peer supports the same protocol.
     fn connected(&self, peer: &PeerId, capability: &PeerCapability) {
+        if self.peer_organizer.lock().unwrap().reject_if_banned(peer) {
+            return;
+        }
         let client_status = self.client.status();
         let snapshot_manifest_status = self.snapshot.manifest_status();
         let task_id = Task::new_id();
@@ -322,5 +682,395 @@ impl Devp2pInbound for Scheduler {
             Some(task_id) => peer_org.remove_task(&task_id),
             None => peer_org.disconnect(peer),
         }
+        self.block_manager.lock().unwrap().peer_disconnected(peer);
+        self.transaction_manager.lock().unwrap().peer_disconnected(peer);
+        self.snapshot_manager.lock().unwrap().peer_disconnected(peer);
+    }
+
+    /// Transport penalties lower reputation the same as ones that scheduler gives.
+    fn report_peer(&self, peer: &PeerId, penal: PeerPenal, reason: &str) {
+        let task = Task::PenalPeer(*peer, penal, reason.to_string());
+        self.peer_organizer.lock().unwrap().push_task(task, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_adapter::client_info::ClientStatus;
+    use crate::common_types::BlockHeader;
+    use crate::scheduler::timeouts::MAX_REQUEST_TIMEOUT;
+    use crate::test_fixtures;
+    use crate::devp2p_adapter::{
+        mock::{MockAdapter, MockNetwork, NodeIndex, SentMessage},
+        NodeId,
+    };
+    use primitive_types::{H256, U256};
+
+    struct TestClient {
+        head: (BlockNumber, H256),
+        events: Mutex<Vec<SchedulerEvent>>,
+    }
+
+    impl Client for TestClient {
+        fn status(&self) -> ClientStatus {
+            ClientStatus { total_difficulty: U256::from(self.head.0 + 1), highest_block: self.head }
+        }
+
+        fn on_event(&self, event: SchedulerEvent) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    struct NoSnapshot;
+
+    impl Snapshot for NoSnapshot {}
+
+    fn chain_spec(network_id: u64, genesis: &BlockHeader) -> ChainSpec {
+        ChainSpec {
+            name: "test".into(),
+            network_id,
+            genesis_hash: genesis.hash(),
+            forks: vec![],
+            bootnodes: vec![],
+        }
+    }
+
+    struct Node {
+        index: NodeIndex,
+        chain: Arc<Mutex<HeadersInMemory>>,
+        client: Arc<TestClient>,
+    }
+
+    impl Node {
+        fn best_block(&self) -> Option<BlockNumber> {
+            self.chain.lock().unwrap().best_block_header().copied()
+        }
+    }
+
+    fn add_node(net: &mut MockNetwork, spec: ChainSpec, headers: &[BlockHeader]) -> Node {
+        let mut chain = HeadersInMemory::new();
+        for header in headers {
+            chain.import_block_header(header);
+        }
+        let chain = Arc::new(Mutex::new(chain));
+        let best = headers.last().unwrap();
+        let client = Arc::new(TestClient {
+            head: (best.number, best.hash()),
+            events: Mutex::new(Vec::new()),
+        });
+        let index = net.add_node(client.clone(), Arc::new(NoSnapshot), spec, chain.clone());
+        Node { index, chain, client }
+    }
+
+    fn peer_count(net: &MockNetwork, node: &Node) -> usize {
+        net.scheduler(node.index).peer_organizer().lock().unwrap().peers().len()
+    }
+
+    // fresh node connected to `seeds` nodes that have chain of `len` headers
+    fn sync_network(seeds: usize, len: u64) -> (MockNetwork, Vec<Node>, Node) {
+        let chain = test_fixtures::chain(len);
+        let spec = chain_spec(1337, &chain[0]);
+        let mut net = MockNetwork::new();
+        let seeds: Vec<Node> = (0..seeds).map(|_| add_node(&mut net, spec.clone(), &chain)).collect();
+        let fresh = add_node(&mut net, spec, &chain[..1]);
+        for seed in seeds.iter() {
+            net.connect(fresh.index, seed.index);
+        }
+        (net, seeds, fresh)
+    }
+
+    #[test]
+    fn test_fresh_node_syncs_from_seeds() {
+        let (mut net, seeds, fresh) = sync_network(MIN_PEERS_TO_START, 500);
+        assert!(net.run_until(100, |_| fresh.best_block() == Some(499)));
+        assert_eq!(peer_count(&net, &fresh), seeds.len());
+        assert!(net.run_until(10, |net| net.scheduler(fresh.index).state() == SchedulerState::PassiveSync));
+        let events = fresh.client.events.lock().unwrap();
+        assert_eq!(
+            events[0],
+            SchedulerEvent::StateChanged { from: SchedulerState::WaitingPeer, to: SchedulerState::ActiveSync }
+        );
+        // nobody misbehaved
+        assert!(net.adapter(fresh.index).penalties().is_empty());
+    }
+
+    #[test]
+    fn test_sync_survives_disconnected_seed() {
+        let (mut net, seeds, fresh) = sync_network(MIN_PEERS_TO_START, 1000);
+        // handshake and first requests
+        net.step();
+        net.step();
+        net.disconnect(fresh.index, seeds[0].index);
+        assert!(net.run_until(200, |_| fresh.best_block() == Some(999)));
+        assert_eq!(peer_count(&net, &fresh), seeds.len() - 1);
+    }
+
+    // eth/66 request for range of headers, not for head of peer
+    fn is_range_request(message: &SentMessage) -> bool {
+        let amount = rlp::Rlp::new(&message.data).at(1).and_then(|request| request.val_at::<u64>(1));
+        message.message_id == EthMessageId::GetBlockHeaders as u8 && amount.is_ok_and(|amount| amount > 1)
+    }
+
+    #[test]
+    fn test_timed_out_request_is_sent_to_other_peer() {
+        let (mut net, seeds, fresh) = sync_network(MIN_PEERS_TO_START, 500);
+        net.drop_next(fresh.index, is_range_request);
+        assert!(net.run_until(10, |net| net.lost().len() == 1));
+        // sync stalls on request that nobody answers
+        for _ in 0..200 {
+            if net.step() == 0 {
+                break;
+            }
+        }
+        assert_ne!(fresh.best_block(), Some(499));
+        let (_, lost) = net.lost()[0].clone();
+        let request = lost.data.clone();
+        net.hold(fresh.index, move |message: &SentMessage| message.data == request);
+
+        net.advance(MAX_REQUEST_TIMEOUT + Duration::from_secs(1));
+        net.step();
+        net.deliver();
+        let held: Vec<&SentMessage> = net.held().iter().map(|(_, message)| message).collect();
+        assert_eq!(held.len(), 1);
+        assert_ne!(held[0].peer, lost.peer);
+
+        net.release();
+        assert!(net.run_until(200, |_| fresh.best_block() == Some(499)));
+        assert_eq!(peer_count(&net, &fresh), seeds.len());
+    }
+
+    #[test]
+    fn test_peer_on_other_network_is_dropped() {
+        let chain = test_fixtures::chain(10);
+        let mut net = MockNetwork::new();
+        let ours = add_node(&mut net, chain_spec(1337, &chain[0]), &chain);
+        let other = add_node(&mut net, chain_spec(1338, &chain[0]), &chain);
+        net.connect(ours.index, other.index);
+        let peer = net.peer_id(ours.index, other.index).unwrap();
+        net.step();
+        net.step();
+        assert_eq!(net.adapter(ours.index).penalties(), vec![(peer, PeerPenal::IncompatibleChain)]);
+        assert_eq!(net.peer_id(ours.index, other.index), None);
+        assert_eq!(peer_count(&net, &ours), 0);
+        assert_eq!(peer_count(&net, &other), 0);
+    }
+
+    #[test]
+    fn test_malformed_response_kicks_peer() {
+        let (mut net, seeds, fresh) = sync_network(1, 10);
+        net.step();
+        assert_eq!(peer_count(&net, &fresh), 1);
+        let peer = net.peer_id(fresh.index, seeds[0].index).unwrap();
+        // eth/66 response without request id
+        net.adapter(fresh.index)
+            .receive(&peer, ProtocolId::Eth, EthMessageId::BlockHeaders as u8, &[0xc0]);
+        net.deliver();
+        assert_eq!(net.adapter(fresh.index).penalties(), vec![(peer, PeerPenal::Kick)]);
+        assert_eq!(peer_count(&net, &fresh), 0);
+        assert_eq!(peer_count(&net, &seeds[0]), 0);
+    }
+
+    #[test]
+    fn test_transport_reports_lower_reputation() {
+        let (mut net, seeds, fresh) = sync_network(1, 10);
+        net.step();
+        let peer = net.peer_id(fresh.index, seeds[0].index).unwrap();
+        let scheduler = net.scheduler(fresh.index);
+        scheduler.report_peer(&peer, PeerPenal::InvalidData, "oversized message");
+        assert!(net.adapter(fresh.index).penalties().is_empty());
+        scheduler.report_peer(&peer, PeerPenal::InvalidData, "oversized message");
+        assert_eq!(net.adapter(fresh.index).penalties(), vec![(peer, PeerPenal::Kick)]);
+    }
+
+    #[test]
+    fn test_banned_node_cannot_connect() {
+        let chain = test_fixtures::chain(1);
+        let mut net = MockNetwork::new();
+        let node = add_node(&mut net, chain_spec(1337, &chain[0]), &chain);
+        let node_id = NodeId::from_low_u64_be(1);
+        let adapter = net.adapter(node.index).clone();
+        adapter.set_node_id(1, node_id);
+        net.scheduler(node.index).peer_organizer().lock().unwrap().ban(node_id, Duration::from_secs(60));
+
+        adapter.connect(&1, &MockAdapter::eth66_capability());
+        assert_eq!(adapter.penalties(), vec![(1, PeerPenal::Ban)]);
+        // no status message is sent to banned peer
+        assert!(adapter.take_sent().is_empty());
+    }
+
+    #[cfg(feature = "rlpx")]
+    #[test]
+    fn test_schedulers_handshake_over_rlpx() {
+        use crate::devp2p_adapter::rlpx::{Rlpx, RlpxConfig};
+        let chain = test_fixtures::chain(10);
+        let spec = chain_spec(1337, &chain[0]);
+        let nodes: Vec<(Rlpx, Arc<Scheduler>)> = (0..2)
+            .map(|_| {
+                let rlpx = Rlpx::new(RlpxConfig::random("127.0.0.1:0".parse().unwrap()));
+                let mut headers = HeadersInMemory::new();
+                for header in chain.iter() {
+                    headers.import_block_header(header);
+                }
+                let client = Arc::new(TestClient { head: (9, chain[9].hash()), events: Mutex::new(Vec::new()) });
+                let scheduler = Scheduler::with_chain(
+                    Box::new(rlpx.clone()),
+                    client,
+                    Arc::new(NoSnapshot),
+                    spec.clone(),
+                    Arc::new(Mutex::new(headers)),
+                    Box::new(NoPeerStore),
+                );
+                scheduler.start();
+                (rlpx, scheduler)
+            })
+            .collect();
+        nodes[0].0.connect(&nodes[1].0.enode().unwrap());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while nodes.iter().any(|(_, scheduler)| scheduler.peer_organizer().lock().unwrap().peers().len() != 1) {
+            assert!(Instant::now() < deadline, "Status exchanged in time");
+            thread::sleep(Duration::from_millis(50));
+        }
+        for (_, scheduler) in nodes {
+            scheduler.stop();
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    mod async_runtime {
+        use super::*;
+        use crate::scheduler::async_runtime::{AsyncDevp2pAdapter, AsyncScheduler, BoxFuture};
+        use tokio::{sync::mpsc, time};
+
+        // sender, receiver, protocol, message id and data
+        type Packet = (PeerId, PeerId, ProtocolId, u8, Vec<u8>);
+
+        // every node sees other node as peer with id of its index
+        struct Wire {
+            index: PeerId,
+            tx: mpsc::UnboundedSender<Packet>,
+            calls: Mutex<Vec<&'static str>>,
+        }
+
+        impl AsyncDevp2pAdapter for Wire {
+            fn start(&self) -> BoxFuture<'_, ()> {
+                Box::pin(async move { self.calls.lock().unwrap().push("start") })
+            }
+
+            fn stop(&self) -> BoxFuture<'_, ()> {
+                Box::pin(async move { self.calls.lock().unwrap().push("stop") })
+            }
+
+            fn send_message(
+                &self,
+                protocol: ProtocolId,
+                peer: PeerId,
+                message_id: u8,
+                data: Vec<u8>,
+            ) -> BoxFuture<'_, ()> {
+                Box::pin(async move {
+                    let _ = self.tx.send((self.index, peer, protocol, message_id, data));
+                })
+            }
+
+            fn penalize_peer(&self, _peer: PeerId, _penal: PeerPenal) -> BoxFuture<'_, ()> {
+                Box::pin(async move { self.calls.lock().unwrap().push("penalize") })
+            }
+        }
+
+        struct AsyncNode {
+            wire: Arc<Wire>,
+            chain: Arc<Mutex<HeadersInMemory>>,
+            scheduler: AsyncScheduler,
+        }
+
+        fn add_node(
+            nodes: &mut Vec<AsyncNode>,
+            tx: &mpsc::UnboundedSender<Packet>,
+            spec: ChainSpec,
+            headers: &[BlockHeader],
+        ) {
+            let mut chain = HeadersInMemory::new();
+            for header in headers {
+                chain.import_block_header(header);
+            }
+            let chain = Arc::new(Mutex::new(chain));
+            let best = headers.last().unwrap();
+            let client = Arc::new(TestClient { head: (best.number, best.hash()), events: Mutex::new(Vec::new()) });
+            let wire = Arc::new(Wire { index: nodes.len(), tx: tx.clone(), calls: Mutex::new(Vec::new()) });
+            let store = Box::new(NoPeerStore);
+            let scheduler =
+                AsyncScheduler::with_chain(wire.clone(), client, Arc::new(NoSnapshot), spec, chain.clone(), store);
+            nodes.push(AsyncNode { wire, chain, scheduler });
+        }
+
+        async fn connect(nodes: &[AsyncNode], a: PeerId, b: PeerId) {
+            let capability = MockAdapter::eth66_capability();
+            nodes[a].scheduler.connected(&b, &capability).await;
+            nodes[b].scheduler.connected(&a, &capability).await;
+        }
+
+        // delivers packets until `done`, main loops run while router waits for them
+        async fn route<F>(nodes: &[AsyncNode], rx: &mut mpsc::UnboundedReceiver<Packet>, done: F)
+        where
+            F: Fn(&[AsyncNode]) -> bool,
+        {
+            while !done(nodes) {
+                let packet = time::timeout(Duration::from_secs(10), rx.recv()).await;
+                let (from, to, protocol, message_id, data) = packet.expect("Network is not stuck").unwrap();
+                nodes[to].scheduler.receive_message(&from, protocol, message_id, &data).await;
+            }
+        }
+
+        fn async_peer_count(node: &AsyncNode) -> usize {
+            node.scheduler.scheduler().peer_organizer().lock().unwrap().peers().len()
+        }
+
+        #[tokio::test]
+        async fn test_schedulers_handshake_without_thread() {
+            let chain = test_fixtures::chain(10);
+            let spec = chain_spec(1337, &chain[0]);
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let mut nodes = Vec::new();
+            for _ in 0..2 {
+                add_node(&mut nodes, &tx, spec.clone(), &chain);
+            }
+            for node in nodes.iter() {
+                node.scheduler.start().await;
+            }
+            connect(&nodes, 0, 1).await;
+            route(&nodes, &mut rx, |nodes| nodes.iter().all(|node| async_peer_count(node) == 1)).await;
+            for node in nodes.iter() {
+                node.scheduler.stop().await;
+                assert_eq!(*node.wire.calls.lock().unwrap(), vec!["start", "stop"]);
+            }
+        }
+
+        #[tokio::test]
+        async fn test_fresh_node_syncs_without_thread() {
+            let chain = test_fixtures::chain(300);
+            let spec = chain_spec(1337, &chain[0]);
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let mut nodes = Vec::new();
+            for _ in 0..MIN_PEERS_TO_START {
+                add_node(&mut nodes, &tx, spec.clone(), &chain);
+            }
+            add_node(&mut nodes, &tx, spec, &chain[..1]);
+            for node in nodes.iter() {
+                node.scheduler.start().await;
+            }
+            let fresh = MIN_PEERS_TO_START;
+            for seed in 0..MIN_PEERS_TO_START {
+                connect(&nodes, fresh, seed).await;
+            }
+            let synced = |nodes: &[AsyncNode]| nodes[fresh].chain.lock().unwrap().best_block_header() == Some(&299);
+            route(&nodes, &mut rx, synced).await;
+            for node in nodes.iter() {
+                node.scheduler.stop().await;
+            }
+            // nobody was penalized
+            assert_eq!(*nodes[fresh].wire.calls.lock().unwrap(), vec!["start", "stop"]);
+        }
     }
 }
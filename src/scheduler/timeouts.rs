@@ -0,0 +1,106 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::protocol::{EthMessageId, MessageId, ParityMessageId};
+use std::time::Duration;
+
+/// Latency that we expect from peer that did not answer any request yet.
+pub const DEFAULT_LATENCY: Duration = Duration::from_secs(5);
+pub const MIN_LATENCY: Duration = Duration::from_millis(500);
+pub const MAX_LATENCY: Duration = Duration::from_secs(15);
+/// No request waits longer than this, no matter how big it is.
+pub const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time that peer needs to serve one item of request, on top of its latency.
+fn item_cost(message_id: &MessageId) -> Duration {
+    match message_id {
+        MessageId::Eth(EthMessageId::GetBlockHeaders) => Duration::from_millis(10),
+        MessageId::Eth(EthMessageId::GetBlockBodies) => Duration::from_millis(50),
+        MessageId::Eth(EthMessageId::GetReceipts) => Duration::from_millis(50),
+        MessageId::Eth(EthMessageId::GetPooledTransactions) => Duration::from_millis(10),
+        MessageId::Parity(ParityMessageId::GetSnapshotManifest) => Duration::from_secs(2),
+        MessageId::Parity(ParityMessageId::GetSnapshotData) => Duration::from_secs(15),
+        _ => Duration::from_secs(0),
+    }
+}
+
+/// Smoothed round trip time of peer, estimated as in RFC 6298.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Adds measured response time of request with `items` items.
+    pub fn response(&mut self, message_id: &MessageId, items: u64, elapsed: Duration) {
+        let sample = elapsed.saturating_sub(item_cost(message_id) * items as u32);
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+
+    /// Time in which peer should start answering, None before first response.
+    pub fn latency(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
+    }
+
+    /// How long we wait for response to request with `items` items.
+    pub fn request_timeout(&self, message_id: &MessageId, items: u64) -> Duration {
+        let latency = self
+            .latency()
+            .unwrap_or(DEFAULT_LATENCY)
+            .clamp(MIN_LATENCY, MAX_LATENCY);
+        std::cmp::min(latency + item_cost(message_id) * items as u32, MAX_REQUEST_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADERS: MessageId = MessageId::Eth(EthMessageId::GetBlockHeaders);
+    const BODIES: MessageId = MessageId::Eth(EthMessageId::GetBlockBodies);
+
+    #[test]
+    fn test_unknown_peer_gets_default_latency() {
+        let rtt = RttEstimator::default();
+        assert_eq!(rtt.request_timeout(&HEADERS, 0), DEFAULT_LATENCY);
+        assert_eq!(rtt.request_timeout(&HEADERS, 192), DEFAULT_LATENCY + Duration::from_millis(1920));
+        assert!(rtt.request_timeout(&BODIES, 128) > rtt.request_timeout(&HEADERS, 128));
+    }
+
+    #[test]
+    fn test_timeout_follows_peer_latency() {
+        let mut rtt = RttEstimator::default();
+        for _ in 0..20 {
+            rtt.response(&HEADERS, 100, Duration::from_millis(1300));
+        }
+        // item cost is not counted in latency
+        let latency = rtt.latency().unwrap();
+        assert!(latency > Duration::from_millis(300) && latency < Duration::from_millis(600));
+        assert_eq!(rtt.request_timeout(&HEADERS, 0), MIN_LATENCY.max(latency));
+
+        for _ in 0..20 {
+            rtt.response(&HEADERS, 1, Duration::from_secs(30));
+        }
+        assert_eq!(rtt.request_timeout(&HEADERS, 0), MAX_LATENCY);
+    }
+
+    #[test]
+    fn test_timeout_is_capped() {
+        let rtt = RttEstimator::default();
+        assert_eq!(
+            rtt.request_timeout(&MessageId::Parity(ParityMessageId::GetSnapshotData), 10),
+            MAX_REQUEST_TIMEOUT
+        );
+    }
+}
@@ -1,4 +1,7 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
-mod snapshot_manager;
+pub mod snapshot_manager;
+mod rlp_en_de;
+
+pub use snapshot_manager::SnapshotManager;
@@ -0,0 +1,127 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common_types::{keccak, ManifestData};
+use primitive_types::H256;
+use rlp::{DecoderError, Rlp, RlpStream};
+
+pub fn encode_get_snapshot_manifest() -> Vec<u8> {
+    RlpStream::new_list(0).out()
+}
+
+pub fn encode_snapshot_manifest(manifest: Option<&ManifestData>) -> Vec<u8> {
+    match manifest {
+        Some(manifest) => {
+            let mut stream = RlpStream::new_list(1);
+            stream.append(manifest);
+            stream.out()
+        }
+        None => RlpStream::new_list(0).out(),
+    }
+}
+
+// manifest of version 1 is missing version field.
+fn decode_manifest(manifest: &Rlp) -> Result<ManifestData, DecoderError> {
+    let (start, version) = match manifest.item_count()? {
+        5 => (0, 1),
+        6 => (1, manifest.val_at(0)?),
+        _ => return Err(DecoderError::RlpIncorrectListLen),
+    };
+    Ok(ManifestData {
+        version,
+        state_hashes: manifest.list_at(start)?,
+        block_hashes: manifest.list_at(start + 1)?,
+        state_root: manifest.val_at(start + 2)?,
+        block_number: manifest.val_at(start + 3)?,
+        block_hash: manifest.val_at(start + 4)?,
+    })
+}
+
+/// Returns manifest with its hash, or None if peer does not have snapshot.
+pub fn decode_snapshot_manifest(data: &[u8]) -> Result<Option<(H256, ManifestData)>, DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? == 0 {
+        return Ok(None);
+    }
+    let manifest = rlp.at(0)?;
+    Ok(Some((keccak(manifest.as_raw()), decode_manifest(&manifest)?)))
+}
+
+pub fn encode_get_snapshot_data(hash: &H256) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(1);
+    stream.append(hash);
+    stream.out()
+}
+
+pub fn decode_get_snapshot_data(data: &[u8]) -> Result<H256, DecoderError> {
+    Rlp::new(data).val_at(0)
+}
+
+pub fn encode_snapshot_data(chunk: Option<&[u8]>) -> Vec<u8> {
+    match chunk {
+        Some(chunk) => {
+            let mut stream = RlpStream::new_list(1);
+            stream.append(&chunk);
+            stream.out()
+        }
+        None => RlpStream::new_list(0).out(),
+    }
+}
+
+/// Returns compressed chunk, or None if peer does not have it.
+pub fn decode_snapshot_data(data: &[u8]) -> Result<Option<Vec<u8>>, DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(rlp.val_at(0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> ManifestData {
+        ManifestData {
+            version: 2,
+            state_hashes: vec![H256::repeat_byte(1), H256::repeat_byte(2)],
+            block_hashes: vec![H256::repeat_byte(3)],
+            state_root: H256::repeat_byte(4),
+            block_number: 12345,
+            block_hash: H256::repeat_byte(5),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_manifest_roundtrip() {
+        let encoded = encode_snapshot_manifest(Some(&manifest()));
+        let (hash, decoded) = decode_snapshot_manifest(&encoded).unwrap().unwrap();
+        assert_eq!(decoded, manifest());
+        assert_eq!(hash, keccak(&rlp::encode(&manifest())));
+        assert_eq!(decode_snapshot_manifest(&encode_snapshot_manifest(None)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_manifest_version_1() {
+        let manifest = manifest();
+        let mut stream = RlpStream::new_list(1);
+        stream
+            .begin_list(5)
+            .append_list(&manifest.state_hashes)
+            .append_list(&manifest.block_hashes)
+            .append(&manifest.state_root)
+            .append(&manifest.block_number)
+            .append(&manifest.block_hash);
+        let (_, decoded) = decode_snapshot_manifest(&stream.out()).unwrap().unwrap();
+        assert_eq!(decoded, ManifestData { version: 1, ..manifest });
+    }
+
+    #[test]
+    fn test_snapshot_data_roundtrip() {
+        let hash = H256::repeat_byte(7);
+        assert_eq!(decode_get_snapshot_data(&encode_get_snapshot_data(&hash)).unwrap(), hash);
+        let chunk = vec![1u8, 2, 3];
+        assert_eq!(decode_snapshot_data(&encode_snapshot_data(Some(&chunk))).unwrap(), Some(chunk));
+        assert_eq!(decode_snapshot_data(&encode_snapshot_data(None)).unwrap(), None);
+    }
+}
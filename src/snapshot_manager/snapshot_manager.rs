@@ -1,2 +1,404 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+use super::rlp_en_de::{
+    decode_get_snapshot_data,
+    decode_snapshot_data,
+    decode_snapshot_manifest,
+    encode_get_snapshot_data,
+    encode_get_snapshot_manifest,
+    encode_snapshot_data,
+    encode_snapshot_manifest,
+};
+use crate::{
+    client_adapter::client_info::Snapshot,
+    common_types::{keccak, BlockNumber, ManifestData},
+    scheduler::peer_organizer::{ErrorAct, InitialRequest, PeerId, Task, TaskId},
+    scheduler::protocol::{MessageId, ParityMessageId, ProtocolId},
+};
+use primitive_types::{H256, U256};
+
+pub struct SnapshotManager {
+    snapshot: Arc<dyn Snapshot>,
+    // snapshots (manifest hash, block number) that peers advertised in status message.
+    peers: HashMap<PeerId, (H256, BlockNumber)>,
+    target: Option<(H256, BlockNumber)>,
+    manifest: Option<ManifestData>,
+    manifest_request: Option<(TaskId, PeerId)>,
+    state_chunks: HashSet<H256>,
+    // chunks that are not yet requested
+    chunks: VecDeque<H256>,
+    requests: HashMap<TaskId, (PeerId, H256)>,
+    restored: usize,
+}
+
+impl SnapshotManager {
+    pub fn new(snapshot: Arc<dyn Snapshot>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(SnapshotManager {
+            snapshot,
+            peers: HashMap::new(),
+            target: None,
+            manifest: None,
+            manifest_request: None,
+            state_chunks: HashSet::new(),
+            chunks: VecDeque::new(),
+            requests: HashMap::new(),
+            restored: 0,
+        }))
+    }
+
+    pub fn new_peer(&mut self, peer: &PeerId, snapshot: Option<(H256, U256)>) {
+        if let Some((hash, number)) = snapshot {
+            if !hash.is_zero() && !number.is_zero() {
+                self.peers.insert(*peer, (hash, number.low_u64()));
+            }
+        }
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Request `task_id` is going to be sent again to `peer`. It is accepted only if peer has
+    /// chosen snapshot and is not busy. Returns false if request is not ours.
+    pub fn reassign_task(&mut self, task_id: &TaskId, peer: &PeerId) -> bool {
+        if self.target.is_none() || self.peers.get(peer) != self.target.as_ref() || self.is_busy(peer) {
+            return false;
+        }
+        if let Some((request_id, request_peer)) = self.manifest_request.as_mut() {
+            if *request_id == *task_id {
+                *request_peer = *peer;
+                return true;
+            }
+        }
+        match self.requests.get_mut(task_id) {
+            Some((request_peer, _)) => {
+                *request_peer = *peer;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Picks snapshot that is advertised by most peers, and highest one between them.
+    /// Returns its block number, or None if no peer has snapshot.
+    pub fn choose_snapshot(&mut self) -> Option<BlockNumber> {
+        if self.target.is_none() {
+            let mut votes: HashMap<(H256, BlockNumber), usize> = HashMap::new();
+            for snapshot in self.peers.values() {
+                *votes.entry(*snapshot).or_default() += 1;
+            }
+            self.target = votes
+                .into_iter()
+                .max_by_key(|((_, number), votes)| (*votes, *number))
+                .map(|(snapshot, _)| snapshot);
+            if let Some((hash, number)) = self.target {
+                info!("Warping to snapshot #{} with manifest {}", number, hash);
+            }
+        }
+        self.target.map(|(_, number)| number)
+    }
+
+    /// Warp can't progress if none of connected peers has chosen snapshot.
+    pub fn is_stalled(&self) -> bool {
+        match self.target {
+            Some(target) => !self.is_complete() && !self.peers.values().any(|snapshot| *snapshot == target),
+            None => true,
+        }
+    }
+
+    /// Gives up chosen snapshot, client is asked to drop restore if it was begun.
+    /// Answers to requests that are in flight are ignored.
+    pub fn abort(&mut self) {
+        if self.manifest.take().is_some() {
+            self.snapshot.abort_restore();
+        }
+        self.target = None;
+        self.manifest_request = None;
+        self.state_chunks.clear();
+        self.chunks.clear();
+        self.requests.clear();
+        self.restored = 0;
+    }
+
+    /// Block that snapshot is taken at, known after manifest is downloaded.
+    pub fn snapshot_block(&self) -> Option<BlockNumber> {
+        self.manifest.as_ref().map(|manifest| manifest.block_number)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.manifest.is_some() && self.chunks.is_empty() && self.requests.is_empty()
+    }
+
+    /// Number of restored and all chunks of snapshot.
+    pub fn progress(&self) -> (usize, usize) {
+        let total = match self.manifest {
+            Some(ref manifest) => manifest.state_hashes.len() + manifest.block_hashes.len(),
+            None => 0,
+        };
+        (self.restored, total)
+    }
+
+    fn is_busy(&self, peer: &PeerId) -> bool {
+        matches!(self.manifest_request, Some((_, request_peer)) if request_peer == *peer)
+            || self.requests.values().any(|(request_peer, _)| request_peer == peer)
+    }
+
+    /// Request that should be sent to free `peer` to progress warp sync. Only peers that advertised
+    /// chosen snapshot are asked and every one of them has at most one request in flight.
+    pub fn next_sync_task(&mut self, peer: &PeerId) -> Option<InitialRequest> {
+        let target = self.target?;
+        if self.peers.get(peer) != Some(&target) || self.is_busy(peer) {
+            return None;
+        }
+        if self.manifest.is_none() {
+            if self.manifest_request.is_some() {
+                return None;
+            }
+            debug!("Requesting snapshot manifest from peer {}", peer);
+            let request = InitialRequest::new(
+                MessageId::Parity(ParityMessageId::GetSnapshotManifest),
+                encode_get_snapshot_manifest(),
+            );
+            self.manifest_request = Some((request.task_id, *peer));
+            return Some(request);
+        }
+        let hash = self.chunks.pop_front()?;
+        debug!("Requesting snapshot chunk {} from peer {}", hash, peer);
+        let request = InitialRequest::new(
+            MessageId::Parity(ParityMessageId::GetSnapshotData),
+            encode_get_snapshot_data(&hash),
+        );
+        self.requests.insert(request.task_id, (*peer, hash));
+        Some(request)
+    }
+
+    pub fn process_snapshot_manifest(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        match self.manifest_request {
+            Some((request_id, _)) if request_id == *task_id => self.manifest_request = None,
+            _ => return Ok(Task::None),
+        }
+        let (hash, manifest) = match decode_snapshot_manifest(data) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => {
+                info!("Peer {} does not have snapshot it advertised", peer);
+                self.peers.remove(peer);
+                return Ok(Task::None);
+            }
+            Err(err) => return ErrorAct::new_invalid_generic(format!("Invalid SnapshotManifest: {}", err)),
+        };
+        if Some(hash) != self.target.map(|(hash, _)| hash) {
+            self.peers.remove(peer);
+            return ErrorAct::new_invalid_generic("Peer sent manifest of different snapshot".into());
+        }
+        info!(
+            "Got manifest of snapshot #{} with {} state and {} block chunks",
+            manifest.block_number,
+            manifest.state_hashes.len(),
+            manifest.block_hashes.len()
+        );
+        self.state_chunks = manifest.state_hashes.iter().copied().collect();
+        self.chunks = manifest.state_hashes.iter().chain(manifest.block_hashes.iter()).copied().collect();
+        self.snapshot.begin_restore(&manifest);
+        self.manifest = Some(manifest);
+        Ok(Task::None)
+    }
+
+    pub fn process_snapshot_data(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let hash = match self.requests.remove(task_id) {
+            Some((_, hash)) => hash,
+            None => return Ok(Task::None),
+        };
+        let chunk = match decode_snapshot_data(data) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                info!("Peer {} does not have snapshot chunk {}", peer, hash);
+                self.peers.remove(peer);
+                self.chunks.push_back(hash);
+                return Ok(Task::None);
+            }
+            Err(err) => {
+                self.chunks.push_back(hash);
+                return ErrorAct::new_invalid_generic(format!("Invalid SnapshotData: {}", err));
+            }
+        };
+        if keccak(&chunk) != hash {
+            self.chunks.push_back(hash);
+            return ErrorAct::new_invalid_generic(format!("Snapshot chunk {} has wrong hash", hash));
+        }
+        if self.state_chunks.contains(&hash) {
+            self.snapshot.feed_state_chunk(&hash, &chunk);
+        } else {
+            self.snapshot.feed_block_chunk(&hash, &chunk);
+        }
+        self.restored += 1;
+        Ok(Task::None)
+    }
+
+    /// Request sent with `task_id` was not answered in time.
+    pub fn task_failed(&mut self, task_id: &TaskId) {
+        if let Some((_, hash)) = self.requests.remove(task_id) {
+            self.chunks.push_back(hash);
+        }
+        if matches!(self.manifest_request, Some((request_id, _)) if request_id == *task_id) {
+            self.manifest_request = None;
+        }
+    }
+
+    pub fn api_get_snapshot_manifest(&self, peer: &PeerId) -> Result<Task, ErrorAct> {
+        Ok(Task::Responde(
+            *peer,
+            ProtocolId::Parity,
+            MessageId::Parity(ParityMessageId::SnapshotManifest),
+            encode_snapshot_manifest(self.snapshot.manifest().as_ref()),
+        ))
+    }
+
+    pub fn api_get_snapshot_data(&self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        match decode_get_snapshot_data(data) {
+            Ok(ref hash) => Ok(Task::Responde(
+                *peer,
+                ProtocolId::Parity,
+                MessageId::Parity(ParityMessageId::SnapshotData),
+                encode_snapshot_data(self.snapshot.chunk(hash).as_deref()),
+            )),
+            Err(err) => ErrorAct::new_invalid_generic(format!("Invalid GetSnapshotData request: {}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestSnapshot {
+        state: Mutex<Vec<H256>>,
+        blocks: Mutex<Vec<H256>>,
+        aborted: Mutex<bool>,
+    }
+
+    impl Snapshot for TestSnapshot {
+        fn abort_restore(&self) {
+            *self.aborted.lock().unwrap() = true;
+        }
+
+        fn feed_state_chunk(&self, hash: &H256, _chunk: &[u8]) {
+            self.state.lock().unwrap().push(*hash);
+        }
+
+        fn feed_block_chunk(&self, hash: &H256, _chunk: &[u8]) {
+            self.blocks.lock().unwrap().push(*hash);
+        }
+    }
+
+    fn chunk(byte: u8) -> Vec<u8> {
+        vec![byte; 10]
+    }
+
+    fn manifest() -> ManifestData {
+        ManifestData {
+            version: 2,
+            state_hashes: vec![keccak(&chunk(1)), keccak(&chunk(2))],
+            block_hashes: vec![keccak(&chunk(3))],
+            state_root: H256::repeat_byte(4),
+            block_number: 1000,
+            block_hash: H256::repeat_byte(5),
+        }
+    }
+
+    fn answer(manager: &mut SnapshotManager, peer: &PeerId, request: &InitialRequest) -> Result<Task, ErrorAct> {
+        let hash = decode_get_snapshot_data(&request.data).unwrap();
+        let byte = (1..=3).find(|byte| keccak(&chunk(*byte)) == hash).unwrap();
+        manager.process_snapshot_data(peer, &request.task_id, &encode_snapshot_data(Some(&chunk(byte))))
+    }
+
+    #[test]
+    fn test_snapshot_is_downloaded() {
+        let snapshot = Arc::new(TestSnapshot::default());
+        let manager = SnapshotManager::new(snapshot.clone());
+        let mut manager = manager.lock().unwrap();
+        let manifest_hash = keccak(&rlp::encode(&manifest()));
+        manager.new_peer(&1, Some((manifest_hash, U256::from(1000))));
+        manager.new_peer(&2, Some((manifest_hash, U256::from(1000))));
+        manager.new_peer(&3, Some((H256::repeat_byte(9), U256::from(2000))));
+        assert_eq!(manager.choose_snapshot(), Some(1000));
+
+        // peer with other snapshot is not used
+        assert!(manager.next_sync_task(&3).is_none());
+        let request = manager.next_sync_task(&1).unwrap();
+        assert!(manager.next_sync_task(&2).is_none());
+        let data = encode_snapshot_manifest(Some(&manifest()));
+        manager.process_snapshot_manifest(&1, &request.task_id, &data).unwrap();
+
+        let first = manager.next_sync_task(&1).unwrap();
+        let second = manager.next_sync_task(&2).unwrap();
+        assert!(manager.next_sync_task(&1).is_none());
+        answer(&mut manager, &1, &first).unwrap();
+        manager.task_failed(&second.task_id);
+        while !manager.is_complete() {
+            let request = manager.next_sync_task(&1).unwrap();
+            answer(&mut manager, &1, &request).unwrap();
+        }
+        assert_eq!(manager.progress(), (3, 3));
+        assert_eq!(snapshot.state.lock().unwrap().len(), 2);
+        assert_eq!(*snapshot.blocks.lock().unwrap(), vec![keccak(&chunk(3))]);
+    }
+
+    #[test]
+    fn test_wrong_chunk_is_rejected() {
+        let snapshot = Arc::new(TestSnapshot::default());
+        let manager = SnapshotManager::new(snapshot);
+        let mut manager = manager.lock().unwrap();
+        let manifest_hash = keccak(&rlp::encode(&manifest()));
+        manager.new_peer(&1, Some((manifest_hash, U256::from(1000))));
+        manager.choose_snapshot();
+        let request = manager.next_sync_task(&1).unwrap();
+        let data = encode_snapshot_manifest(Some(&manifest()));
+        manager.process_snapshot_manifest(&1, &request.task_id, &data).unwrap();
+
+        let request = manager.next_sync_task(&1).unwrap();
+        let data = encode_snapshot_data(Some(&chunk(42)));
+        assert!(manager.process_snapshot_data(&1, &request.task_id, &data).is_err());
+        assert_eq!(manager.progress(), (0, 3));
+        assert!(!manager.is_complete());
+    }
+
+    #[test]
+    fn test_aborted_restore_is_dropped() {
+        let snapshot = Arc::new(TestSnapshot::default());
+        let manager = SnapshotManager::new(snapshot.clone());
+        let mut manager = manager.lock().unwrap();
+        let manifest_hash = keccak(&rlp::encode(&manifest()));
+        manager.new_peer(&1, Some((manifest_hash, U256::from(1000))));
+        manager.choose_snapshot();
+        let request = manager.next_sync_task(&1).unwrap();
+        let data = encode_snapshot_manifest(Some(&manifest()));
+        manager.process_snapshot_manifest(&1, &request.task_id, &data).unwrap();
+        let request = manager.next_sync_task(&1).unwrap();
+
+        manager.abort();
+        assert!(*snapshot.aborted.lock().unwrap());
+        assert_eq!(manager.snapshot_block(), None);
+        assert!(manager.next_sync_task(&1).is_none());
+        // late answer is not fed to client
+        answer(&mut manager, &1, &request).unwrap();
+        assert!(snapshot.state.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_manifest_of_other_snapshot_is_rejected() {
+        let snapshot = Arc::new(TestSnapshot::default());
+        let manager = SnapshotManager::new(snapshot);
+        let mut manager = manager.lock().unwrap();
+        manager.new_peer(&1, Some((H256::repeat_byte(1), U256::from(1000))));
+        manager.choose_snapshot();
+        let request = manager.next_sync_task(&1).unwrap();
+        let data = encode_snapshot_manifest(Some(&manifest()));
+        assert!(manager.process_snapshot_manifest(&1, &request.task_id, &data).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Blocks shared by unit tests.
+
+use crate::common_types::{BlockBody, BlockHeader, BlockNumber, BlockTransaction};
+use primitive_types::{H160, H256, U256};
+
+pub fn empty_body() -> BlockBody {
+    BlockBody { transactions: vec![], ommers: vec![] }
+}
+
+/// Body with one transaction, bodies with different `nonce` have different roots.
+pub fn body(nonce: u64) -> BlockBody {
+    let tx = BlockTransaction {
+        nonce: U256::from(nonce),
+        gas_price: U256::from(1),
+        gas_limit: U256::from(21000),
+        to: Some(H160::repeat_byte(1)),
+        value: U256::from(0),
+        input_data: vec![],
+        v: 27,
+        r: U256::from(1),
+        s: U256::from(1),
+    };
+    BlockBody { transactions: vec![tx], ommers: vec![] }
+}
+
+/// Header of block with `body` and without receipts. It has no parent.
+pub fn header(number: BlockNumber, body: &BlockBody) -> BlockHeader {
+    BlockHeader {
+        parent_hash: H256::zero(),
+        ommers_hash: body.ommers_hash(),
+        beneficiary_address: H160::zero(),
+        state_root: H256::zero(),
+        transactions_root: body.transactions_root(),
+        receipts_root: empty_body().transactions_root(),
+        logs_bloom: vec![0; 256],
+        difficulty: 1,
+        number,
+        gas_limit: 5000,
+        gas_used: 0,
+        timestamp: number,
+        extra_data: vec![],
+        mix_hash: H256::zero(),
+        nonce: 0,
+    }
+}
+
+/// Header of block with `body` on top of `parent`.
+pub fn child(parent: &BlockHeader, body: &BlockBody) -> BlockHeader {
+    let mut header = parent.clone();
+    header.parent_hash = parent.hash();
+    header.number += 1;
+    header.timestamp += 15;
+    header.transactions_root = body.transactions_root();
+    header.ommers_hash = body.ommers_hash();
+    header
+}
+
+/// Chain of `len` empty blocks starting from genesis, so that only headers need to be synced.
+pub fn chain(len: u64) -> Vec<BlockHeader> {
+    let mut headers = vec![header(0, &empty_body())];
+    while (headers.len() as u64) < len {
+        let next = child(headers.last().unwrap(), &empty_body());
+        headers.push(next);
+    }
+    headers.truncate(len as usize);
+    headers
+}
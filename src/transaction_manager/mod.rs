@@ -1,4 +1,7 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
 
-mod transaction_manager;
+pub mod transaction_manager;
+mod rlp_en_de;
+
+pub use transaction_manager::TransactionManager;
@@ -0,0 +1,97 @@
+// Copyright 2020 Gnosis Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::block_manager::rlp_en_de::decode_block_transaction;
+use crate::common_types::BlockTransaction;
+use primitive_types::H256;
+use rlp::{DecoderError, Rlp, RlpStream};
+
+fn encode_hashes(hashes: &[H256]) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(hashes.len());
+    for hash in hashes {
+        stream.append(hash);
+    }
+    stream.out()
+}
+
+fn decode_hashes(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
+    let rlp = Rlp::new(data);
+    let mut hashes = vec![];
+    for item in rlp.iter() {
+        hashes.push(item.as_val()?);
+    }
+    Ok(hashes)
+}
+
+pub fn encode_new_pooled_transaction_hashes(hashes: &[H256]) -> Vec<u8> {
+    encode_hashes(hashes)
+}
+
+pub fn decode_new_pooled_transaction_hashes(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
+    decode_hashes(data)
+}
+
+pub fn encode_get_pooled_transactions(hashes: &[H256]) -> Vec<u8> {
+    encode_hashes(hashes)
+}
+
+pub fn decode_get_pooled_transactions(data: &[u8]) -> Result<Vec<H256>, DecoderError> {
+    decode_hashes(data)
+}
+
+pub fn encode_transactions(transactions: &[BlockTransaction]) -> Vec<u8> {
+    rlp::encode_list(transactions)
+}
+
+pub fn decode_transactions(data: &[u8]) -> Result<Vec<BlockTransaction>, DecoderError> {
+    let rlp = Rlp::new(data);
+    let mut transactions = vec![];
+    for ref transaction in rlp.iter() {
+        transactions.push(decode_block_transaction(transaction)?);
+    }
+    Ok(transactions)
+}
+
+pub fn encode_pooled_transactions(transactions: &[BlockTransaction]) -> Vec<u8> {
+    encode_transactions(transactions)
+}
+
+pub fn decode_pooled_transactions(data: &[u8]) -> Result<Vec<BlockTransaction>, DecoderError> {
+    decode_transactions(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitive_types::{H160, U256};
+
+    fn transaction(nonce: u64) -> BlockTransaction {
+        BlockTransaction {
+            nonce: U256::from(nonce),
+            gas_price: U256::from(1_000_000_000u64),
+            gas_limit: U256::from(21000),
+            to: Some(H160::repeat_byte(3)),
+            value: U256::from(10),
+            input_data: vec![],
+            v: 37,
+            r: U256::from(1),
+            s: U256::from(2),
+        }
+    }
+
+    #[test]
+    fn test_pooled_transaction_hashes_roundtrip() {
+        let hashes = vec![H256::repeat_byte(1), H256::repeat_byte(2)];
+        let encoded = encode_new_pooled_transaction_hashes(&hashes);
+        assert_eq!(decode_new_pooled_transaction_hashes(&encoded).unwrap(), hashes);
+        let encoded = encode_get_pooled_transactions(&hashes);
+        assert_eq!(decode_get_pooled_transactions(&encoded).unwrap(), hashes);
+    }
+
+    #[test]
+    fn test_pooled_transactions_roundtrip() {
+        let transactions = vec![transaction(0), transaction(1)];
+        let encoded = encode_pooled_transactions(&transactions);
+        assert_eq!(decode_pooled_transactions(&encoded).unwrap(), transactions);
+    }
+}
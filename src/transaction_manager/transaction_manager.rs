@@ -1,2 +1,449 @@
 // Copyright 2020 Gnosis Ltd.
 // SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+use super::rlp_en_de::{
+    decode_get_pooled_transactions,
+    decode_new_pooled_transaction_hashes,
+    decode_pooled_transactions,
+    decode_transactions,
+    encode_get_pooled_transactions,
+    encode_new_pooled_transaction_hashes,
+    encode_pooled_transactions,
+    encode_transactions,
+};
+use crate::{
+    client_adapter::TransactionPool,
+    common_types::BlockTransaction,
+    scheduler::peer_organizer::{ErrorAct, InitialRequest, PeerId, Task, TaskId},
+    scheduler::propagation::{split_propagation, KnownHashes},
+    scheduler::protocol::{EthMessageId, EthProtocolVersion, MessageId, ProtocolId},
+};
+use primitive_types::H256;
+
+/// Max number of transactions asked for in one GetPooledTransactions request, or sent in answer to one.
+pub const MAX_TRANSACTIONS_PER_REQUEST: usize = 256;
+/// Max number of announced hashes that wait to be fetched from one peer, further announcements are dropped.
+pub const MAX_ANNOUNCED_TRANSACTIONS: usize = 4096;
+/// Max number of transaction hashes remembered per peer, set is cleared when it grows over it.
+pub const MAX_KNOWN_TRANSACTIONS: usize = 32768;
+
+pub struct TransactionManager {
+    pool: Arc<Mutex<dyn TransactionPool + Send + Sync>>,
+    // transactions that peer sent, announced or got from us. They are never sent back to it.
+    known: KnownHashes,
+    // new transactions that are not yet propagated.
+    pending: Vec<BlockTransaction>,
+    // hashes announced by peer that we didn't fetch yet. They are fetched from the announcer,
+    // hashes that are in flight are kept in case request to other peer fails.
+    announced: HashMap<PeerId, Announced>,
+    // hashes that are in flight, so that same transaction is not fetched from several peers.
+    requested: HashSet<H256>,
+    requests: HashMap<TaskId, (PeerId, Vec<H256>)>,
+}
+
+/// Hashes announced by one peer, oldest first and without duplicates.
+#[derive(Default)]
+struct Announced {
+    order: VecDeque<H256>,
+    hashes: HashSet<H256>,
+}
+
+impl Announced {
+    fn insert(&mut self, hash: H256) {
+        if self.hashes.len() < MAX_ANNOUNCED_TRANSACTIONS && self.hashes.insert(hash) {
+            self.order.push_back(hash);
+        }
+    }
+
+    fn retain<F: FnMut(&H256) -> bool>(&mut self, mut keep: F) {
+        let hashes = &mut self.hashes;
+        self.order.retain(|hash| keep(hash) || !hashes.remove(hash));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl TransactionManager {
+    pub fn new(pool: Arc<Mutex<dyn TransactionPool + Send + Sync>>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(TransactionManager {
+            pool,
+            known: KnownHashes::new(MAX_KNOWN_TRANSACTIONS),
+            pending: Vec::new(),
+            announced: HashMap::new(),
+            requested: HashSet::new(),
+            requests: HashMap::new(),
+        }))
+    }
+
+    pub fn api_new_pooled_transaction_hashes(&mut self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let hashes = match decode_new_pooled_transaction_hashes(data) {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                return ErrorAct::new_invalid_generic(format!("Invalid NewPooledTransactionHashes: {}", err))
+            }
+        };
+        self.mark_known(peer, hashes.iter().copied());
+        let pool = self.pool.lock().unwrap();
+        let announced = self.announced.entry(*peer).or_default();
+        for hash in hashes.into_iter().filter(|hash| !pool.contains(hash)) {
+            announced.insert(hash);
+        }
+        Ok(Task::None)
+    }
+
+    /// Request for transactions that `peer` announced and we still don't have.
+    pub fn next_fetch_task(&mut self, peer: &PeerId) -> Option<InitialRequest> {
+        let announced = self.announced.get_mut(peer)?;
+        let mut hashes = vec![];
+        {
+            let pool = self.pool.lock().unwrap();
+            let requested = &mut self.requested;
+            announced.retain(|hash| {
+                if pool.contains(hash) {
+                    return false;
+                }
+                if hashes.len() < MAX_TRANSACTIONS_PER_REQUEST && requested.insert(*hash) {
+                    hashes.push(*hash);
+                    return false;
+                }
+                true
+            });
+        }
+        if announced.is_empty() {
+            self.announced.remove(peer);
+        }
+        if hashes.is_empty() {
+            return None;
+        }
+        debug!("Requesting {} pooled transactions from peer {}", hashes.len(), peer);
+        let request = InitialRequest::new(
+            MessageId::Eth(EthMessageId::GetPooledTransactions),
+            encode_get_pooled_transactions(&hashes),
+        )
+        .with_items(hashes.len() as u64);
+        self.requests.insert(request.task_id, (*peer, hashes));
+        Some(request)
+    }
+
+    pub fn api_get_pooled_transactions(&self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        match decode_get_pooled_transactions(data) {
+            Ok(hashes) => {
+                let pool = self.pool.lock().unwrap();
+                let transactions: Vec<_> = hashes
+                    .iter()
+                    .take(MAX_TRANSACTIONS_PER_REQUEST)
+                    .filter_map(|hash| pool.transaction(hash))
+                    .collect();
+                Ok(Task::Responde(
+                    *peer,
+                    ProtocolId::Eth,
+                    MessageId::Eth(EthMessageId::PooledTransactions),
+                    encode_pooled_transactions(&transactions),
+                ))
+            }
+            Err(err) => {
+                ErrorAct::new_invalid_generic(format!("Invalid GetPooledTransactions request: {}", err))
+            }
+        }
+    }
+
+    pub fn process_pooled_transactions(&mut self, peer: &PeerId, task_id: &TaskId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let hashes = match self.requests.remove(task_id) {
+            Some((_, hashes)) => hashes,
+            None => return Ok(Task::None),
+        };
+        for hash in hashes.iter() {
+            self.requested.remove(hash);
+        }
+        let transactions = match decode_pooled_transactions(data) {
+            Ok(transactions) => transactions,
+            Err(err) => {
+                return ErrorAct::new_invalid_generic(format!("Could not decode pooled transactions: {}", err))
+            }
+        };
+        debug!("Got {} pooled transactions from peer {}", transactions.len(), peer);
+        // peer can skip transactions it doesn't have anymore, but must not send ones we didn't ask for.
+        if transactions.iter().any(|transaction| !hashes.contains(&transaction.hash())) {
+            return ErrorAct::new_invalid_generic("Peer sent unrequested pooled transaction".into());
+        }
+        self.mark_known(peer, transactions.iter().map(BlockTransaction::hash));
+        self.import_new(transactions);
+        Ok(Task::None)
+    }
+
+    pub fn api_transactions(&mut self, peer: &PeerId, data: &[u8]) -> Result<Task, ErrorAct> {
+        let transactions = match decode_transactions(data) {
+            Ok(transactions) => transactions,
+            Err(err) => return ErrorAct::new_invalid_generic(format!("Invalid Transactions message: {}", err)),
+        };
+        debug!("Got {} transactions from peer {}", transactions.len(), peer);
+        self.mark_known(peer, transactions.iter().map(BlockTransaction::hash));
+        self.import_new(transactions);
+        Ok(Task::None)
+    }
+
+    /// Transactions created by our client. They are added to pool and propagated to peers.
+    pub fn submit_transactions(&mut self, transactions: Vec<BlockTransaction>) {
+        self.import_new(transactions);
+    }
+
+    // Imports transactions that pool doesn't have and queues them for propagation.
+    fn import_new(&mut self, transactions: Vec<BlockTransaction>) {
+        let mut pool = self.pool.lock().unwrap();
+        let new: Vec<_> = transactions
+            .into_iter()
+            .filter(|transaction| !pool.contains(&transaction.hash()))
+            .collect();
+        if new.is_empty() {
+            return;
+        }
+        pool.import_transactions(new.clone());
+        self.pending.extend(new);
+    }
+
+    fn mark_known<I: Iterator<Item = H256>>(&mut self, peer: &PeerId, hashes: I) {
+        for hash in hashes {
+            self.known.mark_known(peer, hash);
+        }
+    }
+
+    /// Sends pending transactions to peers that don't know them. Square root of those peers gets
+    /// full transactions and the rest gets only hashes, peers older than eth/65 always get full transactions.
+    /// `peers` contains connected peers with their eth version.
+    pub fn propagate(&mut self, peers: &[(PeerId, u8)]) -> Vec<Task> {
+        if peers.is_empty() || self.pending.is_empty() {
+            return Vec::new();
+        }
+        let mut full: HashMap<PeerId, Vec<BlockTransaction>> = HashMap::new();
+        let mut announce: HashMap<PeerId, Vec<H256>> = HashMap::new();
+        for transaction in std::mem::take(&mut self.pending) {
+            let hash = transaction.hash();
+            let peers: Vec<(PeerId, u8)> = peers
+                .iter()
+                .filter(|(peer, _)| !self.known.is_known(peer, &hash))
+                .copied()
+                .collect();
+            for ((peer, version), direct) in split_propagation(&hash, &peers) {
+                if direct || *version < EthProtocolVersion::VERSION_65.to_number() {
+                    full.entry(*peer).or_default().push(transaction.clone());
+                } else {
+                    announce.entry(*peer).or_default().push(hash);
+                }
+                self.known.mark_known(peer, hash);
+            }
+        }
+        let full = full.into_iter().map(|(peer, transactions)| {
+            Task::Responde(
+                peer,
+                ProtocolId::Eth,
+                MessageId::Eth(EthMessageId::Transactions),
+                encode_transactions(&transactions),
+            )
+        });
+        let announce = announce.into_iter().map(|(peer, hashes)| {
+            Task::Responde(
+                peer,
+                ProtocolId::Eth,
+                MessageId::Eth(EthMessageId::NewPooledTransactionHashes),
+                encode_new_pooled_transaction_hashes(&hashes),
+            )
+        });
+        full.chain(announce).collect()
+    }
+
+    /// Request sent with `task_id` was not answered in time.
+    pub fn task_failed(&mut self, task_id: &TaskId) {
+        if let Some((_, hashes)) = self.requests.remove(task_id) {
+            for hash in hashes.iter() {
+                self.requested.remove(hash);
+            }
+        }
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.known.peer_disconnected(peer);
+        self.announced.remove(peer);
+    }
+
+    /// Request `task_id` is going to be sent again to `peer`. It is accepted only if peer
+    /// announced all requested transactions. Returns false if request is not ours.
+    pub fn reassign_task(&mut self, task_id: &TaskId, peer: &PeerId) -> bool {
+        let known = &self.known;
+        match self.requests.get_mut(task_id) {
+            Some((request_peer, hashes)) if hashes.iter().all(|hash| known.is_known(peer, hash)) => {
+                *request_peer = *peer;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_adapter::transaction_pool::TransactionsInMemory;
+    use crate::common_types::BlockTransaction;
+    use primitive_types::{H160, U256};
+
+    fn transaction(nonce: u64) -> BlockTransaction {
+        BlockTransaction {
+            nonce: U256::from(nonce),
+            gas_price: U256::from(1_000_000_000u64),
+            gas_limit: U256::from(21000),
+            to: Some(H160::repeat_byte(3)),
+            value: U256::from(10),
+            input_data: vec![],
+            v: 37,
+            r: U256::from(1),
+            s: U256::from(2),
+        }
+    }
+
+    #[test]
+    fn test_announced_transactions_are_fetched_once() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        pool.lock().unwrap().import_transactions(vec![transaction(0)]);
+        let manager = TransactionManager::new(pool.clone());
+        let mut manager = manager.lock().unwrap();
+
+        let hashes: Vec<H256> = (0..3).map(|nonce| transaction(nonce).hash()).collect();
+        let announcement = encode_new_pooled_transaction_hashes(&hashes);
+        manager.api_new_pooled_transaction_hashes(&1, &announcement).unwrap();
+        manager.api_new_pooled_transaction_hashes(&2, &announcement).unwrap();
+
+        let request = manager.next_fetch_task(&1).unwrap();
+        let requested = decode_get_pooled_transactions(&request.data).unwrap();
+        assert_eq!(requested.len(), 2);
+        assert!(!requested.contains(&hashes[0]));
+        // everything peer 2 announced is already in flight
+        assert!(manager.next_fetch_task(&2).is_none());
+
+        let response = encode_pooled_transactions(&[transaction(1), transaction(2)]);
+        manager.process_pooled_transactions(&1, &request.task_id, &response).unwrap();
+        assert!(hashes.iter().all(|hash| pool.lock().unwrap().contains(hash)));
+    }
+
+    #[test]
+    fn test_unrequested_transaction_is_rejected() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let manager = TransactionManager::new(pool.clone());
+        let mut manager = manager.lock().unwrap();
+
+        let announcement = encode_new_pooled_transaction_hashes(&[transaction(1).hash()]);
+        manager.api_new_pooled_transaction_hashes(&1, &announcement).unwrap();
+        let request = manager.next_fetch_task(&1).unwrap();
+
+        let response = encode_pooled_transactions(&[transaction(5)]);
+        assert!(manager.process_pooled_transactions(&1, &request.task_id, &response).is_err());
+        assert!(!pool.lock().unwrap().contains(&transaction(5).hash()));
+    }
+
+    #[test]
+    fn test_failed_request_can_be_fetched_from_other_peer() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let manager = TransactionManager::new(pool);
+        let mut manager = manager.lock().unwrap();
+
+        let announcement = encode_new_pooled_transaction_hashes(&[transaction(1).hash()]);
+        manager.api_new_pooled_transaction_hashes(&1, &announcement).unwrap();
+        let request = manager.next_fetch_task(&1).unwrap();
+        manager.api_new_pooled_transaction_hashes(&2, &announcement).unwrap();
+        assert!(manager.next_fetch_task(&2).is_none());
+
+        // request can be retried only on peer that announced transaction
+        manager.peer_disconnected(&1);
+        assert!(!manager.reassign_task(&request.task_id, &3));
+        assert!(manager.reassign_task(&request.task_id, &2));
+
+        // peer 2 announced it while it was in flight, so it is fetched from it without new announcement
+        manager.task_failed(&request.task_id);
+        let request = manager.next_fetch_task(&2).unwrap();
+        assert_eq!(decode_get_pooled_transactions(&request.data).unwrap(), vec![transaction(1).hash()]);
+        assert!(manager.next_fetch_task(&2).is_none());
+    }
+
+    #[test]
+    fn test_announcements_are_capped_per_peer() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let manager = TransactionManager::new(pool);
+        let mut manager = manager.lock().unwrap();
+
+        let announcement = encode_new_pooled_transaction_hashes(&[transaction(1).hash()]);
+        for _ in 0..3 {
+            manager.api_new_pooled_transaction_hashes(&1, &announcement).unwrap();
+        }
+        assert_eq!(manager.announced[&1].order.len(), 1);
+
+        let hashes: Vec<H256> = (0..MAX_ANNOUNCED_TRANSACTIONS as u64 + 10).map(H256::from_low_u64_be).collect();
+        manager.api_new_pooled_transaction_hashes(&1, &encode_new_pooled_transaction_hashes(&hashes)).unwrap();
+        assert_eq!(manager.announced[&1].order.len(), MAX_ANNOUNCED_TRANSACTIONS);
+        assert_eq!(manager.announced[&1].hashes.len(), MAX_ANNOUNCED_TRANSACTIONS);
+    }
+
+    #[test]
+    fn test_pooled_transactions_answer_is_capped() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let transactions: Vec<_> = (0..MAX_TRANSACTIONS_PER_REQUEST as u64 + 10).map(transaction).collect();
+        pool.lock().unwrap().import_transactions(transactions.clone());
+        let manager = TransactionManager::new(pool);
+        let manager = manager.lock().unwrap();
+
+        let hashes: Vec<H256> = transactions.iter().map(BlockTransaction::hash).collect();
+        let answer = match manager.api_get_pooled_transactions(&1, &encode_get_pooled_transactions(&hashes)) {
+            Ok(Task::Responde(_, _, _, data)) => decode_pooled_transactions(&data).unwrap(),
+            _ => panic!("Expected answer"),
+        };
+        assert_eq!(answer.len(), MAX_TRANSACTIONS_PER_REQUEST);
+    }
+
+    fn sent_messages(tasks: &[Task]) -> HashMap<PeerId, EthMessageId> {
+        tasks
+            .iter()
+            .map(|task| match task {
+                Task::Responde(peer, _, MessageId::Eth(message_id), _) => (*peer, *message_id),
+                _ => panic!("Unexpected task {:?}", task),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transactions_are_propagated_to_peers_that_dont_know_them() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let manager = TransactionManager::new(pool.clone());
+        let mut manager = manager.lock().unwrap();
+
+        let data = encode_transactions(&[transaction(1)]);
+        manager.api_transactions(&1, &data).unwrap();
+        assert!(pool.lock().unwrap().contains(&transaction(1).hash()));
+
+        let peers: Vec<(PeerId, u8)> = (1..=5).map(|peer| (peer, 66)).collect();
+        let sent = sent_messages(&manager.propagate(&peers));
+        assert!(!sent.contains_key(&1));
+        assert_eq!(sent.len(), 4);
+        let full = sent.values().filter(|id| **id == EthMessageId::Transactions).count();
+        assert_eq!(full, 2); // ceil(sqrt(4)), peer 1 already has it
+        // nothing is sent twice
+        manager.submit_transactions(vec![transaction(1)]);
+        assert!(manager.propagate(&peers).is_empty());
+    }
+
+    #[test]
+    fn test_old_peers_get_full_transactions() {
+        let pool = Arc::new(Mutex::new(TransactionsInMemory::new()));
+        let manager = TransactionManager::new(pool);
+        let mut manager = manager.lock().unwrap();
+
+        manager.submit_transactions(vec![transaction(1)]);
+        let peers: Vec<(PeerId, u8)> = (1..=9).map(|peer| (peer, 64)).collect();
+        let sent = sent_messages(&manager.propagate(&peers));
+        assert_eq!(sent.len(), 9);
+        assert!(sent.values().all(|id| *id == EthMessageId::Transactions));
+    }
+}